@@ -0,0 +1,579 @@
+//! Pages of multi-page TIFFs and frames of animated GIF and WebP inputs.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use image::codecs::gif::GifDecoder;
+use image::codecs::tiff::TiffDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, Delay, DynamicImage, GenericImageView, ImageBuffer, ImageFormat};
+use tiff::decoder::DecodingResult;
+
+/// One page of a multi-page image or frame of an animation.
+///
+/// Still images consist of a single frame, page 1.
+#[derive(Debug, Clone)]
+pub(crate) struct Frame {
+    /// Page or frame number, from 1
+    pub(crate) page: u32,
+    pub(crate) image: DynamicImage,
+    /// How long an animation frame is shown; unset for pages and stills
+    pub(crate) delay: Option<Delay>,
+}
+
+impl Frame {
+    pub(crate) fn size(&self) -> PageSize {
+        let (width, height) = self.image.dimensions();
+        PageSize {
+            page: self.page,
+            width,
+            height,
+        }
+    }
+}
+
+/// The decoded pages of an image, which may be only some of its pages.
+#[derive(Debug)]
+pub(crate) struct Decoded {
+    pub(crate) frames: Vec<Frame>,
+    /// Every page, including those not decoded
+    pub(crate) pages: Vec<PageSize>,
+}
+
+impl Decoded {
+    /// All pages of an image, decoded.
+    pub(crate) fn all(frames: Vec<Frame>) -> Self {
+        Decoded {
+            pages: frames.iter().map(Frame::size).collect(),
+            frames,
+        }
+    }
+}
+
+/// Number and dimensions of a page, all that planning its captures needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PageSize {
+    pub(crate) page: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+/// A selection of pages, e.g. `1,3-5` or `2-`.
+///
+/// Pages are numbered from 1 and ranges are inclusive; a range without an
+/// end runs to the last page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Pages(Vec<(u32, Option<u32>)>);
+
+impl Pages {
+    /// Only the first page.
+    pub(crate) fn first() -> Self {
+        Pages(vec![(1, Some(1))])
+    }
+
+    /// The pages selected by any of `selections`.
+    pub(crate) fn union<'a>(selections: impl IntoIterator<Item = &'a Pages>) -> Self {
+        Pages(
+            selections
+                .into_iter()
+                .flat_map(|pages| pages.0.clone())
+                .collect(),
+        )
+    }
+
+    /// Whether `page` is selected.
+    pub(crate) fn contains(&self, page: u32) -> bool {
+        self.0
+            .iter()
+            .any(|&(first, last)| page >= first && last.is_none_or(|last| page <= last))
+    }
+}
+
+impl std::str::FromStr for Pages {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let number = |n: &str| match n.trim().parse::<u32>() {
+            Ok(0) => Err(format!(
+                "Invalid page '0' in '{s}'. Pages are numbered from 1"
+            )),
+            Ok(n) => Ok(n),
+            Err(_) => Err(format!(
+                "Invalid page '{}' in '{s}'. Expected page numbers or ranges such as 1,3-5 or 2-",
+                n.trim()
+            )),
+        };
+
+        let ranges = s
+            .split(',')
+            .map(|part| match part.split_once('-') {
+                Some((first, "")) => Ok((number(first)?, None)),
+                Some((first, last)) => {
+                    let (first, last) = (number(first)?, number(last)?);
+                    if last < first {
+                        return Err(format!("Invalid page range '{part}' in '{s}'"));
+                    }
+                    Ok((first, Some(last)))
+                }
+                None => {
+                    let page = number(part)?;
+                    Ok((page, Some(page)))
+                }
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(Pages(ranges))
+    }
+}
+
+impl std::fmt::Display for Pages {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, &(first, last)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            match last {
+                Some(last) if last == first => write!(f, "{first}")?,
+                Some(last) => write!(f, "{first}-{last}")?,
+                None => write!(f, "{first}-")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decode the frames of a GIF or animated WebP into full-canvas frames with
+/// their timing.
+///
+/// Returns `None` for other formats and still WebPs, having read no more than
+/// their headers, so that the caller decodes their only frame with [`still`].
+/// TIFFs are read by [`read_tiff`].
+pub(crate) fn read_animation(
+    path: &Path,
+    format: Option<ImageFormat>,
+) -> Result<Option<Vec<Frame>>> {
+    let open_error = || format!("Unable to read the frames of '{}'", path.display());
+    let reader = || {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(open_error)
+    };
+    match format {
+        Some(ImageFormat::Gif) => {
+            let decoder = GifDecoder::new(reader()?).with_context(open_error)?;
+            animation(decoder).map(Some).with_context(open_error)
+        }
+        Some(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(reader()?).with_context(open_error)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            animation(decoder).map(Some).with_context(open_error)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Count the frames of a GIF or WebP from its block structure, without
+/// decoding them. Other formats, and stills, have one frame.
+pub(crate) fn count_frames(path: &Path, format: ImageFormat) -> std::io::Result<u64> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let frames = match format {
+        ImageFormat::Gif => count_gif_frames(&mut reader)?,
+        ImageFormat::WebP => count_webp_frames(&mut reader)?,
+        _ => 1,
+    };
+    Ok(frames.max(1))
+}
+
+/// Count the image descriptors of a GIF, skipping over extensions and
+/// compressed image data.
+fn count_gif_frames(reader: &mut BufReader<File>) -> std::io::Result<u64> {
+    fn skip_sub_blocks(reader: &mut BufReader<File>) -> std::io::Result<()> {
+        loop {
+            let mut len = [0];
+            reader.read_exact(&mut len)?;
+            if len[0] == 0 {
+                return Ok(());
+            }
+            reader.seek_relative(i64::from(len[0]))?;
+        }
+    }
+    // A color table follows when bit 7 of the flags is set
+    let color_table = |flags: u8| {
+        if flags & 0x80 != 0 {
+            3 << ((flags & 7) + 1)
+        } else {
+            0
+        }
+    };
+
+    let mut header = [0; 13];
+    reader.read_exact(&mut header)?;
+    reader.seek_relative(color_table(header[10]))?;
+    let mut frames = 0;
+    loop {
+        let mut block = [0];
+        reader.read_exact(&mut block)?;
+        match block[0] {
+            // Extension: label, then data
+            0x21 => {
+                reader.seek_relative(1)?;
+                skip_sub_blocks(reader)?;
+            }
+            // Image: descriptor, color table, LZW code size, then data
+            0x2C => {
+                let mut descriptor = [0; 9];
+                reader.read_exact(&mut descriptor)?;
+                reader.seek_relative(color_table(descriptor[8]) + 1)?;
+                skip_sub_blocks(reader)?;
+                frames += 1;
+            }
+            // Trailer
+            _ => return Ok(frames),
+        }
+    }
+}
+
+/// Count the ANMF chunks of a WebP.
+fn count_webp_frames(reader: &mut BufReader<File>) -> std::io::Result<u64> {
+    let mut header = [0; 12];
+    reader.read_exact(&mut header)?;
+    let mut frames = 0;
+    loop {
+        let mut chunk = [0; 8];
+        match reader.read_exact(&mut chunk) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(frames),
+            result => result?,
+        }
+        if &chunk[..4] == b"ANMF" {
+            frames += 1;
+        }
+        // Chunks are padded to an even size
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        reader.seek_relative(i64::from(size) + i64::from(size & 1))?;
+    }
+}
+
+/// The only frame of a still image.
+pub(crate) fn still(image: DynamicImage) -> Frame {
+    Frame {
+        page: 1,
+        image,
+        delay: None,
+    }
+}
+
+/// Decode all frames of an animation.
+fn animation<'a>(decoder: impl AnimationDecoder<'a>) -> Result<Vec<Frame>> {
+    decoder
+        .into_frames()
+        .zip(1..)
+        .map(|(frame, page)| {
+            let frame = frame?;
+            Ok(Frame {
+                page,
+                delay: Some(frame.delay()),
+                image: DynamicImage::ImageRgba8(frame.into_buffer()),
+            })
+        })
+        .collect()
+}
+
+/// Decode the pages of a TIFF that `selected` picks, or every page, returning
+/// the size of every page and whether the decoded pages hold CMYK samples.
+///
+/// The image crate only decodes the first image of a TIFF, so each page is
+/// decoded through a reader that points the header's first-IFD offset at it.
+/// Only the headers of other pages are read. CMYK pages are kept as
+/// four-channel images rather than converted to RGB, and gray+alpha pages,
+/// which the image crate cannot read, are decoded here.
+pub(crate) fn read_tiff(path: &Path, selected: Option<&Pages>) -> Result<(Decoded, bool)> {
+    let open = || {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("Unable to read '{}'", path.display()))
+    };
+    let pages_error = || format!("Unable to read the pages of '{}'", path.display());
+
+    let mut decoder = tiff::decoder::Decoder::new(open()?).with_context(pages_error)?;
+    let pages = tiff_pages(&mut decoder).with_context(pages_error)?;
+    drop(decoder);
+
+    let mut frames = Vec::new();
+    let mut cmyk = None;
+    for tiff_page in &pages {
+        let page = tiff_page.size.page;
+        if selected.is_some_and(|pages| !pages.contains(page)) {
+            continue;
+        }
+        let page_error = || format!("Unable to read page {page} of '{}'", path.display());
+        let mut reader = tiff_page.reader(path).with_context(page_error)?;
+        let (image, page_cmyk) = read_tiff_page(&mut reader).with_context(page_error)?;
+        match cmyk {
+            Some(first_page) if first_page != page_cmyk => {
+                return Err(anyhow!(
+                    "Pages of '{}' mix CMYK and other color types; page {page} differs from \
+                     page {}",
+                    path.display(),
+                    frames.first().map_or(1, |frame: &Frame| frame.page)
+                ));
+            }
+            _ => cmyk = Some(page_cmyk),
+        }
+        frames.push(Frame {
+            page,
+            image,
+            delay: None,
+        });
+    }
+
+    let decoded = Decoded {
+        frames,
+        pages: pages.into_iter().map(|page| page.size).collect(),
+    };
+    Ok((decoded, cmyk.unwrap_or(false)))
+}
+
+/// A page of a TIFF: an IFD other than the reduced-resolution copies of an
+/// image, such as the levels of a pyramidal TIFF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TiffPage {
+    /// Offset of the IFD in the file
+    offset: Option<u64>,
+    pub(crate) size: PageSize,
+}
+
+impl TiffPage {
+    /// Open the TIFF at `path` as a file whose first image is this page, for
+    /// decoders that only read the first image.
+    pub(crate) fn reader(&self, path: &Path) -> Result<impl BufRead + Seek> {
+        let mut file = File::open(path)?;
+        let mut header = Vec::new();
+        (&mut file).take(16).read_to_end(&mut header)?;
+        if let Some(offset) = self.offset {
+            point_first_ifd(&mut header, offset)?;
+        }
+        file.rewind()?;
+        Ok(BufReader::new(Repointed {
+            inner: file,
+            header,
+            pos: 0,
+        }))
+    }
+}
+
+/// List the pages of a TIFF, reading only their IFDs. The first IFD is
+/// always a page.
+pub(crate) fn tiff_pages<R: Read + Seek>(
+    decoder: &mut tiff::decoder::Decoder<R>,
+) -> tiff::TiffResult<Vec<TiffPage>> {
+    let mut pages = Vec::new();
+    for ifd in 0.. {
+        // NewSubfileType bit 0 marks a reduced-resolution image
+        let reduced = decoder
+            .find_tag_unsigned::<u32>(tiff::tags::Tag::NewSubfileType)?
+            .is_some_and(|subfile| subfile & 1 != 0);
+        if ifd == 0 || !reduced {
+            let (width, height) = decoder.dimensions()?;
+            pages.push(TiffPage {
+                offset: decoder.ifd_pointer().map(|ifd| ifd.0),
+                size: PageSize {
+                    page: pages.len() as u32 + 1,
+                    width,
+                    height,
+                },
+            });
+        }
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+    Ok(pages)
+}
+
+/// A TIFF file as read with its header replaced by `header`, which points it
+/// at another page.
+struct Repointed<R> {
+    inner: R,
+    header: Vec<u8>,
+    /// Position of the next read, which `inner` is kept at
+    pos: u64,
+}
+
+impl<R: Read + Seek> Read for Repointed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let header = usize::try_from(self.pos)
+            .ok()
+            .and_then(|pos| self.header.get(pos..))
+            .unwrap_or_default();
+        let read = if header.is_empty() {
+            self.inner.read(buf)?
+        } else {
+            let read = header.len().min(buf.len());
+            buf[..read].copy_from_slice(&header[..read]);
+            self.inner.seek(SeekFrom::Start(self.pos + read as u64))?;
+            read
+        };
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for Repointed<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// Decode the first image of a TIFF, returning whether it holds CMYK samples.
+/// CMYK is returned as RGBA-shaped samples in C, M, Y, K order.
+fn read_tiff_page(reader: &mut (impl BufRead + Seek)) -> Result<(DynamicImage, bool)> {
+    use tiff::ColorType;
+
+    let mut decoder = tiff::decoder::Decoder::new(&mut *reader)?;
+    let (width, height) = decoder.dimensions()?;
+    let unsupported = |color| anyhow!("Unsupported TIFF sample layout {color:?}");
+    let color = decoder.colortype()?;
+    let image = match color {
+        ColorType::CMYK(8 | 16)
+        | ColorType::Multiband {
+            bit_depth: 8 | 16,
+            num_samples: 2,
+        } => match (decoder.read_image()?, color) {
+            (DecodingResult::U8(samples), ColorType::CMYK(_)) => {
+                ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba8)
+            }
+            (DecodingResult::U16(samples), ColorType::CMYK(_)) => {
+                ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba16)
+            }
+            (DecodingResult::U8(samples), _) => {
+                ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLumaA8)
+            }
+            (DecodingResult::U16(samples), _) => {
+                ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLumaA16)
+            }
+            _ => None,
+        }
+        .ok_or_else(|| unsupported(color))?,
+        _ => {
+            drop(decoder);
+            reader.seek(SeekFrom::Start(0))?;
+            DynamicImage::from_decoder(TiffDecoder::new(reader)?)?
+        }
+    };
+    Ok((image, matches!(color, ColorType::CMYK(_))))
+}
+
+/// Rewrite the first-IFD offset in a TIFF or BigTIFF header.
+fn point_first_ifd(data: &mut [u8], offset: u64) -> Result<()> {
+    let little_endian = match data.get(..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return Err(anyhow!("Not a TIFF file")),
+    };
+    let version = match data.get(2..4) {
+        Some(&[a, b]) if little_endian => u16::from_le_bytes([a, b]),
+        Some(&[a, b]) => u16::from_be_bytes([a, b]),
+        _ => return Err(anyhow!("Not a TIFF file")),
+    };
+    match version {
+        42 => {
+            let offset = u32::try_from(offset)?;
+            let bytes = if little_endian {
+                offset.to_le_bytes()
+            } else {
+                offset.to_be_bytes()
+            };
+            data[4..8].copy_from_slice(&bytes);
+        }
+        43 if data.len() >= 16 => {
+            let bytes = if little_endian {
+                offset.to_le_bytes()
+            } else {
+                offset.to_be_bytes()
+            };
+            data[8..16].copy_from_slice(&bytes);
+        }
+        _ => return Err(anyhow!("Unsupported TIFF version {version}")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::Metadata;
+    use crate::output::Encoding;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_parse_pages() {
+        let pages: Pages = "1,3-5,8-".parse().unwrap();
+        let selected: Vec<u32> = (1..=10).filter(|&p| pages.contains(p)).collect();
+        assert_eq!(selected, vec![1, 3, 4, 5, 8, 9, 10]);
+        assert_eq!(pages.to_string(), "1,3-5,8-");
+        assert_eq!(" 2 , 4 ".parse::<Pages>().unwrap().to_string(), "2,4");
+    }
+
+    #[test]
+    fn test_pages_union() {
+        let a: Pages = "1,3".parse().unwrap();
+        let b: Pages = "5-".parse().unwrap();
+        let union = Pages::union([&a, &b]);
+        let selected: Vec<u32> = (1..=6).filter(|&p| union.contains(p)).collect();
+        assert_eq!(selected, vec![1, 3, 5, 6]);
+        assert!(Pages::first().contains(1) && !Pages::first().contains(2));
+    }
+
+    #[test]
+    fn test_count_frames() {
+        let dir = TempDir::new("count");
+        let frames: Vec<Frame> = (1..=3)
+            .map(|page| Frame {
+                page,
+                image: DynamicImage::ImageRgba8(ImageBuffer::from_pixel(
+                    5,
+                    4,
+                    image::Rgba([page as u8 * 60, 0, 0, 255]),
+                )),
+                delay: Some(Delay::from_numer_denom_ms(100, 1)),
+            })
+            .collect();
+        let path = dir.join("anim.gif");
+        Encoding::default()
+            .save_frames(&frames, &path, &Metadata::default())
+            .unwrap();
+        assert_eq!(count_frames(&path, ImageFormat::Gif).unwrap(), 3);
+        let decoded = read_animation(&path, Some(ImageFormat::Gif))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[2].image.as_bytes(), frames[2].image.as_bytes());
+
+        // Stills are left to the caller to decode
+        let path = dir.join("still.webp");
+        Encoding::default()
+            .save(&frames[0].image, &path, &Metadata::default())
+            .unwrap();
+        assert_eq!(count_frames(&path, ImageFormat::WebP).unwrap(), 1);
+        assert!(read_animation(&path, Some(ImageFormat::WebP))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_pages_invalid() {
+        assert!("0"
+            .parse::<Pages>()
+            .unwrap_err()
+            .contains("numbered from 1"));
+        assert!("5-3".parse::<Pages>().unwrap_err().contains("range"));
+        assert!("".parse::<Pages>().is_err());
+        assert!("1,,2".parse::<Pages>().is_err());
+        assert!("a-b".parse::<Pages>().is_err());
+    }
+}
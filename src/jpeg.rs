@@ -0,0 +1,1302 @@
+//! Lossless cropping of baseline JPEGs by copying their DCT coefficients.
+//!
+//! Re-encoding a JPEG loses quality every time. Instead, the entropy-coded
+//! data is decoded to quantized coefficient blocks, the blocks inside the
+//! capture are copied, and they are entropy-coded again with Huffman tables
+//! optimized for the crop. Captures must start on the MCU grid (8 or 16
+//! pixels, depending on chroma subsampling); the right and bottom edges can
+//! be anywhere.
+//!
+//! The same cropping decodes just the parts of a huge JPEG that captures
+//! cover, see [`decode_regions`].
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageFormat};
+use rayon::prelude::*;
+
+use crate::Rect;
+
+/// How captures of JPEG inputs written as JPEG are cropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum JpegCrop {
+    /// Decode, crop and encode again
+    #[default]
+    Reencode,
+    /// Copy DCT coefficients; captures off the MCU grid are an error
+    Lossless,
+    /// Copy DCT coefficients, growing captures up and left to the MCU grid
+    Snap,
+}
+
+impl std::str::FromStr for JpegCrop {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reencode" | "re-encode" => Ok(JpegCrop::Reencode),
+            "lossless" => Ok(JpegCrop::Lossless),
+            "snap" => Ok(JpegCrop::Snap),
+            _ => Err(format!(
+                "Invalid JPEG crop mode '{s}'. Supported values: reencode, lossless, snap"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for JpegCrop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JpegCrop::Reencode => write!(f, "reencode"),
+            JpegCrop::Lossless => write!(f, "lossless"),
+            JpegCrop::Snap => write!(f, "snap"),
+        }
+    }
+}
+
+const SOI: u8 = 0xD8;
+const EOI: u8 = 0xD9;
+const SOF0: u8 = 0xC0;
+const SOF1: u8 = 0xC1;
+const DHT: u8 = 0xC4;
+const SOS: u8 = 0xDA;
+const DQT: u8 = 0xDB;
+const DRI: u8 = 0xDD;
+const APP1: u8 = 0xE1;
+const COM: u8 = 0xFE;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// One color component of a JPEG and its decoded coefficients.
+#[derive(Debug)]
+struct Component {
+    id: u8,
+    /// Horizontal and vertical sampling factors
+    h: usize,
+    v: usize,
+    /// Quantization table
+    tq: u8,
+    /// Width of the block grid, padded to whole MCUs
+    blocks_w: usize,
+    /// Quantized coefficients of each block in zigzag order, row by row,
+    /// for the MCU rows that are kept
+    blocks: Vec<[i16; 64]>,
+}
+
+/// A decoded Huffman table.
+#[derive(Debug, Clone)]
+struct HuffmanTable {
+    /// Largest code of each length, or -1
+    max_code: [i32; 17],
+    /// Smallest code of each length
+    min_code: [i32; 17],
+    /// Index into `values` of the first code of each length
+    offset: [usize; 17],
+    values: Vec<u8>,
+}
+
+impl HuffmanTable {
+    /// Build a table from the code counts per length and the symbols.
+    fn new(counts: &[u8; 16], values: Vec<u8>) -> Self {
+        let mut table = HuffmanTable {
+            max_code: [-1; 17],
+            min_code: [0; 17],
+            offset: [0; 17],
+            values,
+        };
+        let mut code = 0;
+        let mut k = 0;
+        for len in 1..=16 {
+            let count = usize::from(counts[len - 1]);
+            if count > 0 {
+                table.offset[len] = k;
+                table.min_code[len] = code;
+                code += count as i32;
+                k += count;
+                table.max_code[len] = code - 1;
+            }
+            code <<= 1;
+        }
+        table
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8> {
+        let mut code = reader.bit() as i32;
+        for len in 1..=16 {
+            if code <= self.max_code[len] {
+                let index = self.offset[len] + (code - self.min_code[len]) as usize;
+                return self
+                    .values
+                    .get(index)
+                    .copied()
+                    .ok_or_else(|| anyhow!("Corrupt JPEG data: invalid Huffman code"));
+            }
+            code = (code << 1) | reader.bit() as i32;
+        }
+        Err(anyhow!("Corrupt JPEG data: invalid Huffman code"))
+    }
+}
+
+/// Reads the entropy-coded data of a scan bit by bit.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    byte: u8,
+    remaining: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        BitReader {
+            data,
+            pos,
+            byte: 0,
+            remaining: 0,
+        }
+    }
+
+    fn bit(&mut self) -> u16 {
+        if self.remaining == 0 {
+            self.byte = match self.data.get(self.pos..self.pos + 2) {
+                Some([0xFF, 0x00]) => {
+                    self.pos += 2;
+                    0xFF
+                }
+                // A marker ends the data; like libjpeg, pad with zeros
+                Some([0xFF, _]) => 0,
+                _ => match self.data.get(self.pos) {
+                    Some(&byte) => {
+                        self.pos += 1;
+                        byte
+                    }
+                    None => 0,
+                },
+            };
+            self.remaining = 8;
+        }
+        self.remaining -= 1;
+        u16::from((self.byte >> self.remaining) & 1)
+    }
+
+    /// Read an `n`-bit magnitude and extend its sign.
+    fn value(&mut self, n: u8) -> i32 {
+        if n == 0 {
+            return 0;
+        }
+        let mut v = 0i32;
+        for _ in 0..n {
+            v = (v << 1) | i32::from(self.bit());
+        }
+        if v < 1 << (n - 1) {
+            v - (1 << n) + 1
+        } else {
+            v
+        }
+    }
+
+    /// Skip the RSTn marker that ends a restart interval.
+    fn restart(&mut self) -> Result<()> {
+        self.remaining = 0;
+        while self.data.get(self.pos..self.pos + 2) == Some(&[0xFF, 0xFF]) {
+            self.pos += 1;
+        }
+        match self.data.get(self.pos..self.pos + 2) {
+            Some(&[0xFF, 0xD0..=0xD7]) => {
+                self.pos += 2;
+                Ok(())
+            }
+            _ => Err(anyhow!("Corrupt JPEG data: missing restart marker")),
+        }
+    }
+
+    /// Position of the marker following the entropy-coded data.
+    fn end(&self) -> usize {
+        let mut pos = self.pos;
+        while pos + 1 < self.data.len() {
+            if self.data[pos] == 0xFF && !matches!(self.data[pos + 1], 0x00 | 0xD0..=0xD7) {
+                return pos;
+            }
+            pos += 1;
+        }
+        self.data.len()
+    }
+}
+
+/// The headers of a baseline JPEG: what planning lossless crops needs, read
+/// without its entropy-coded data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    width: u32,
+    height: u32,
+    /// Size of an MCU in pixels, the grid lossless crops start on
+    mcu_size: (u32, u32),
+    orientation: Orientation,
+}
+
+impl Header {
+    /// Read the headers of a JPEG for lossless cropping, stopping at its
+    /// first scan. Returns `None` for other formats.
+    ///
+    /// Progressive, arithmetic-coded, lossless and 12-bit JPEGs cannot be
+    /// cropped losslessly and are an error.
+    pub(crate) fn read(path: &Path) -> Result<Option<Self>> {
+        let file =
+            File::open(path).with_context(|| format!("Unable to read '{}'", path.display()))?;
+        Header::parse(&mut BufReader::new(file))
+            .with_context(|| format!("Unable to crop '{}' losslessly", path.display()))
+    }
+
+    fn parse(reader: &mut impl BufRead) -> Result<Option<Self>> {
+        let truncated = |_| anyhow!("Corrupt JPEG data: truncated segment");
+        let mut marker = [0u8; 2];
+        if reader.read_exact(&mut marker).is_err() || marker != [0xFF, SOI] {
+            return Ok(None);
+        }
+        let mut header = None;
+        let mut orientation = Orientation::NoTransforms;
+        loop {
+            reader.read_exact(&mut marker).map_err(truncated)?;
+            // Markers may be preceded by any number of 0xFF fill bytes
+            while marker == [0xFF, 0xFF] {
+                reader.read_exact(&mut marker[1..]).map_err(truncated)?;
+            }
+            let marker = match marker {
+                [0xFF, marker] => marker,
+                _ => return Err(anyhow!("Corrupt JPEG data: expected a marker")),
+            };
+            if marker == EOI || marker == SOS {
+                break;
+            }
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len).map_err(truncated)?;
+            let len = usize::from(u16::from_be_bytes(len))
+                .checked_sub(2)
+                .ok_or_else(|| anyhow!("Corrupt JPEG data: truncated segment"))?;
+            if !matches!(marker, SOF0 | SOF1 | APP1) {
+                check_frame_type(marker)?;
+                std::io::copy(&mut reader.take(len as u64), &mut std::io::sink())?;
+                continue;
+            }
+            let mut payload = vec![0; len];
+            reader.read_exact(&mut payload).map_err(truncated)?;
+            if marker == APP1 {
+                if let Some(exif) = payload.strip_prefix(EXIF_HEADER) {
+                    orientation =
+                        Orientation::from_exif_chunk(exif).unwrap_or(Orientation::NoTransforms);
+                }
+                continue;
+            }
+            let mut source = Source::empty();
+            source.parse_frame(&payload, Some(&[]))?;
+            header = Some((source.width, source.height, source.mcu_size()));
+        }
+        let (width, height, mcu_size) =
+            header.ok_or_else(|| anyhow!("Corrupt JPEG data: no image"))?;
+        Ok(Some(Header {
+            width,
+            height,
+            mcu_size,
+            orientation,
+        }))
+    }
+
+    /// The orientation recorded in the JPEG's EXIF.
+    pub(crate) fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+
+    /// Align a capture to the MCU grid as `mode` allows.
+    ///
+    /// With `JpegCrop::Snap`, the left and top edges move out to the grid
+    /// and the size grows so that the original rectangle is still covered.
+    pub(crate) fn align(&self, rect: Rect, mode: JpegCrop) -> Result<Rect> {
+        let (mcu_w, mcu_h) = self.mcu_size;
+        if rect.x < 0
+            || rect.y < 0
+            || rect.x + i64::from(rect.width) > i64::from(self.width)
+            || rect.y + i64::from(rect.height) > i64::from(self.height)
+        {
+            return Err(anyhow!(
+                "Capture {}x{} at ({}, {}) extends past the {}x{} image, which lossless JPEG cropping cannot pad",
+                rect.width,
+                rect.height,
+                rect.x,
+                rect.y,
+                self.width,
+                self.height
+            ));
+        }
+
+        let x = rect.x - rect.x % i64::from(mcu_w);
+        let y = rect.y - rect.y % i64::from(mcu_h);
+        if (x, y) != (rect.x, rect.y) && mode != JpegCrop::Snap {
+            return Err(anyhow!(
+                "Capture at ({}, {}) is not aligned to the {mcu_w}x{mcu_h} JPEG block grid. \
+                 Move it to a multiple of {mcu_w}x{mcu_h} or use --jpeg-crop snap",
+                rect.x,
+                rect.y
+            ));
+        }
+        Ok(Rect {
+            x,
+            y,
+            width: rect.width + (rect.x - x) as u32,
+            height: rect.height + (rect.y - y) as u32,
+        })
+    }
+
+    /// Read the coefficients of the JPEG at `path` that crops of the aligned
+    /// `rects` need: those of the MCU rows the rectangles cover.
+    pub(crate) fn source(&self, path: &Path, rects: &[Rect]) -> Result<Source> {
+        let data =
+            std::fs::read(path).with_context(|| format!("Unable to read '{}'", path.display()))?;
+        let spans: Vec<(u32, u32)> = rects
+            .iter()
+            .map(|rect| (rect.y as u32, rect.y as u32 + rect.height))
+            .collect();
+        Source::parse(&data, Some(&spans))
+            .with_context(|| format!("Unable to crop '{}' losslessly", path.display()))
+    }
+}
+
+/// Fail for the frame types that cannot be cropped losslessly.
+fn check_frame_type(marker: u8) -> Result<()> {
+    match marker {
+        0xC2 | 0xC6 | 0xCA | 0xCE => Err(anyhow!("Progressive JPEGs cannot be cropped losslessly")),
+        0xC3 | 0xC5 | 0xC7 | 0xC9 | 0xCB | 0xCD | 0xCF => Err(anyhow!(
+            "Lossless, hierarchical and arithmetic-coded JPEGs cannot be cropped losslessly"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// A baseline JPEG decoded to DCT coefficients, ready to be cropped.
+#[derive(Debug)]
+pub(crate) struct Source {
+    width: u32,
+    height: u32,
+    /// APPn, COM and DQT segments, copied into every crop
+    segments: Vec<(u8, Vec<u8>)>,
+    components: Vec<Component>,
+    /// Position of each MCU row's blocks among the kept rows, if kept
+    rows: Vec<Option<usize>>,
+}
+
+/// Components of a scan with their DC and AC tables.
+struct Scan {
+    components: Vec<(usize, usize, usize)>,
+}
+
+impl Source {
+    fn empty() -> Self {
+        Source {
+            width: 0,
+            height: 0,
+            segments: Vec::new(),
+            components: Vec::new(),
+            rows: Vec::new(),
+        }
+    }
+
+    /// Parse a JPEG, keeping the coefficients of every MCU row, or only of
+    /// those that overlap or neighbour the pixel row ranges `spans`.
+    fn parse(data: &[u8], spans: Option<&[(u32, u32)]>) -> Result<Self> {
+        let mut source = Source::empty();
+        let mut dc_tables: [Option<HuffmanTable>; 4] = Default::default();
+        let mut ac_tables: [Option<HuffmanTable>; 4] = Default::default();
+        let mut restart_interval = 0;
+
+        let mut pos = 2;
+        loop {
+            // Markers may be preceded by any number of 0xFF fill bytes
+            while data.get(pos..pos + 2).is_some_and(|m| m == [0xFF, 0xFF]) {
+                pos += 1;
+            }
+            let marker = match data.get(pos..pos + 2) {
+                Some(&[0xFF, marker]) => marker,
+                _ => return Err(anyhow!("Corrupt JPEG data: expected a marker")),
+            };
+            pos += 2;
+            if marker == EOI {
+                break;
+            }
+            let len = match data.get(pos..pos + 2) {
+                Some(&[a, b]) => usize::from(u16::from_be_bytes([a, b])),
+                _ => return Err(anyhow!("Corrupt JPEG data: truncated segment")),
+            };
+            let payload = data
+                .get(pos + 2..pos + len)
+                .filter(|_| len >= 2)
+                .ok_or_else(|| anyhow!("Corrupt JPEG data: truncated segment"))?;
+            pos += len;
+
+            match marker {
+                SOF0 | SOF1 => source.parse_frame(payload, spans)?,
+                DHT => parse_huffman_tables(payload, &mut dc_tables, &mut ac_tables)?,
+                DRI => {
+                    restart_interval = match payload {
+                        &[a, b] => usize::from(u16::from_be_bytes([a, b])),
+                        _ => return Err(anyhow!("Corrupt JPEG data: invalid DRI segment")),
+                    }
+                }
+                SOS => {
+                    let scan = source.parse_scan(payload)?;
+                    pos = source.decode_scan(
+                        data,
+                        pos,
+                        &scan,
+                        &dc_tables,
+                        &ac_tables,
+                        restart_interval,
+                    )?;
+                }
+                DQT | 0xE0..=0xEF | COM => source.segments.push((marker, payload.to_vec())),
+                0xDC => return Err(anyhow!("JPEGs with a DNL marker are not supported")),
+                _ => check_frame_type(marker)?,
+            }
+        }
+
+        if source.components.is_empty() {
+            return Err(anyhow!("Corrupt JPEG data: no image"));
+        }
+        Ok(source)
+    }
+
+    /// Parse a SOF0 or SOF1 frame header and allocate the coefficient blocks
+    /// of the MCU rows to keep.
+    fn parse_frame(&mut self, payload: &[u8], spans: Option<&[(u32, u32)]>) -> Result<()> {
+        let invalid = || anyhow!("Corrupt JPEG data: invalid frame header");
+        let header = payload.get(..6).ok_or_else(invalid)?;
+        if header[0] != 8 {
+            return Err(anyhow!(
+                "{}-bit JPEGs cannot be cropped losslessly",
+                header[0]
+            ));
+        }
+        self.height = u32::from(u16::from_be_bytes([header[1], header[2]]));
+        self.width = u32::from(u16::from_be_bytes([header[3], header[4]]));
+        if self.width == 0 || self.height == 0 {
+            return Err(invalid());
+        }
+
+        let count = usize::from(header[5]);
+        let specs = payload.get(6..6 + 3 * count).ok_or_else(invalid)?;
+        for spec in specs.chunks_exact(3) {
+            let (h, v) = (usize::from(spec[1] >> 4), usize::from(spec[1] & 15));
+            if !(1..=4).contains(&h) || !(1..=4).contains(&v) {
+                return Err(invalid());
+            }
+            self.components.push(Component {
+                id: spec[0],
+                h,
+                v,
+                tq: spec[2],
+                blocks_w: 0,
+                blocks: Vec::new(),
+            });
+        }
+        // A single component is never interleaved, so it has one block per MCU
+        if let [component] = self.components.as_mut_slice() {
+            component.h = 1;
+            component.v = 1;
+        }
+
+        let (mcus_w, mcus_h) = self.mcu_grid(self.width, self.height);
+        let (_, mcu_h) = self.mcu_size();
+        let mut kept = 0;
+        self.rows = (0..mcus_h as u32)
+            .map(|row| {
+                let (top, bottom) = (row.saturating_sub(1) * mcu_h, (row + 2) * mcu_h);
+                let keep = spans
+                    .is_none_or(|spans| spans.iter().any(|&(from, to)| from < bottom && to > top));
+                keep.then(|| {
+                    kept += 1;
+                    kept - 1
+                })
+            })
+            .collect();
+        for component in &mut self.components {
+            component.blocks_w = mcus_w * component.h;
+            component.blocks = vec![[0; 64]; component.blocks_w * kept * component.v];
+        }
+        Ok(())
+    }
+
+    /// Parse a scan header into component indices and table selectors.
+    fn parse_scan(&self, payload: &[u8]) -> Result<Scan> {
+        let invalid = || anyhow!("Corrupt JPEG data: invalid scan header");
+        if self.components.is_empty() {
+            return Err(invalid());
+        }
+        let count = usize::from(*payload.first().ok_or_else(invalid)?);
+        let specs = payload.get(1..1 + 2 * count).ok_or_else(invalid)?;
+        let components = specs
+            .chunks_exact(2)
+            .map(|spec| {
+                let index = self
+                    .components
+                    .iter()
+                    .position(|c| c.id == spec[0])
+                    .ok_or_else(invalid)?;
+                Ok((index, usize::from(spec[1] >> 4), usize::from(spec[1] & 15)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if components.is_empty() || components.iter().any(|&(_, dc, ac)| dc > 3 || ac > 3) {
+            return Err(invalid());
+        }
+        Ok(Scan { components })
+    }
+
+    /// Decode the entropy-coded data of a scan starting at `pos` into the
+    /// coefficient blocks. Returns the position of the following marker.
+    fn decode_scan(
+        &mut self,
+        data: &[u8],
+        pos: usize,
+        scan: &Scan,
+        dc_tables: &[Option<HuffmanTable>; 4],
+        ac_tables: &[Option<HuffmanTable>; 4],
+        restart_interval: usize,
+    ) -> Result<usize> {
+        let table = |tables: &[Option<HuffmanTable>; 4], id: usize| {
+            tables[id]
+                .clone()
+                .ok_or_else(|| anyhow!("Corrupt JPEG data: missing Huffman table"))
+        };
+        let tables = scan
+            .components
+            .iter()
+            .map(|&(_, dc, ac)| Ok((table(dc_tables, dc)?, table(ac_tables, ac)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        // A scan of one component covers just its blocks, one per MCU
+        let (mcus_w, mcus_h) = match scan.components.as_slice() {
+            &[(index, _, _)] => self.component_blocks(index),
+            _ => self.mcu_grid(self.width, self.height),
+        };
+        let single = scan.components.len() == 1;
+
+        let mut reader = BitReader::new(data, pos);
+        let mut predictions = vec![0i32; scan.components.len()];
+        // Blocks of MCU rows that are not kept are decoded and dropped
+        let mut skipped = [0; 64];
+        for mcu in 0..mcus_w * mcus_h {
+            if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+                reader.restart()?;
+                predictions.fill(0);
+            }
+            let (mcu_x, mcu_y) = (mcu % mcus_w, mcu / mcus_w);
+            for (((index, _, _), (dc, ac)), prediction) in
+                scan.components.iter().zip(&tables).zip(&mut predictions)
+            {
+                let component = &mut self.components[*index];
+                let (h, v) = if single {
+                    (1, 1)
+                } else {
+                    (component.h, component.v)
+                };
+                for by in 0..v {
+                    for bx in 0..h {
+                        let x = mcu_x * h + bx;
+                        let y = mcu_y * v + by;
+                        let block = match block_index(&self.rows, component, x, y) {
+                            Some(index) => &mut component.blocks[index],
+                            None => &mut skipped,
+                        };
+                        decode_block(&mut reader, dc, ac, prediction, block)?;
+                    }
+                }
+            }
+        }
+        Ok(reader.end())
+    }
+
+    /// Number of MCUs across and down an image of the given size.
+    fn mcu_grid(&self, width: u32, height: u32) -> (usize, usize) {
+        let (mcu_w, mcu_h) = self.mcu_size();
+        (
+            width.div_ceil(mcu_w) as usize,
+            height.div_ceil(mcu_h) as usize,
+        )
+    }
+
+    /// Blocks across and down that hold image data of a component.
+    fn component_blocks(&self, index: usize) -> (usize, usize) {
+        let (max_h, max_v) = self.max_sampling();
+        let component = &self.components[index];
+        let width = (self.width as usize * component.h).div_ceil(max_h);
+        let height = (self.height as usize * component.v).div_ceil(max_v);
+        (width.div_ceil(8), height.div_ceil(8))
+    }
+
+    fn max_sampling(&self) -> (usize, usize) {
+        let max_h = self.components.iter().map(|c| c.h).max().unwrap_or(1);
+        let max_v = self.components.iter().map(|c| c.v).max().unwrap_or(1);
+        (max_h, max_v)
+    }
+
+    /// Size of an MCU in pixels, the grid lossless crops start on.
+    pub(crate) fn mcu_size(&self) -> (u32, u32) {
+        let (max_h, max_v) = self.max_sampling();
+        (8 * max_h as u32, 8 * max_v as u32)
+    }
+
+    /// Encode the blocks inside an aligned rectangle as a new JPEG.
+    ///
+    /// The metadata segments are copied, with the EXIF pixel dimensions
+    /// updated to the size of the crop.
+    pub(crate) fn crop(&self, rect: Rect) -> Vec<u8> {
+        let (mcu_w, mcu_h) = self.mcu_size();
+        let (mcu_x0, mcu_y0) = (
+            rect.x as usize / mcu_w as usize,
+            rect.y as usize / mcu_h as usize,
+        );
+
+        // Blocks of the crop in coding order, per component. A single
+        // component is coded block by block; several are interleaved by MCU.
+        let mut coded: Vec<(usize, &[i16; 64])> = Vec::new();
+        if let [component] = self.components.as_slice() {
+            let (blocks_w, blocks_h) = (
+                rect.width.div_ceil(8) as usize,
+                rect.height.div_ceil(8) as usize,
+            );
+            for y in 0..blocks_h {
+                for x in 0..blocks_w {
+                    let index = block_index(&self.rows, component, mcu_x0 + x, mcu_y0 + y)
+                        .expect("crop within the kept MCU rows");
+                    coded.push((0, &component.blocks[index]));
+                }
+            }
+        } else {
+            let (mcus_w, mcus_h) = self.mcu_grid(rect.width, rect.height);
+            for mcu_y in 0..mcus_h {
+                for mcu_x in 0..mcus_w {
+                    for (c, component) in self.components.iter().enumerate() {
+                        for by in 0..component.v {
+                            for bx in 0..component.h {
+                                let x = (mcu_x0 + mcu_x) * component.h + bx;
+                                let y = (mcu_y0 + mcu_y) * component.v + by;
+                                let index = block_index(&self.rows, component, x, y)
+                                    .expect("crop within the kept MCU rows");
+                                coded.push((c, &component.blocks[index]));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // The first component uses table 0 and the others share table 1
+        let table_of = |c: usize| usize::from(c > 0);
+        let table_count = if self.components.len() > 1 { 2 } else { 1 };
+
+        let mut dc_counts = vec![[0u32; 257]; table_count];
+        let mut ac_counts = vec![[0u32; 257]; table_count];
+        let mut predictions = vec![0i32; self.components.len()];
+        for &(c, block) in &coded {
+            let table = table_of(c);
+            encode_block(block, &mut predictions[c], |symbol, _, _, dc| {
+                let counts = if dc { &mut dc_counts } else { &mut ac_counts };
+                counts[table][usize::from(symbol)] += 1;
+            });
+        }
+        let dc_codes: Vec<HuffmanCode> = dc_counts.iter().map(HuffmanCode::optimal).collect();
+        let ac_codes: Vec<HuffmanCode> = ac_counts.iter().map(HuffmanCode::optimal).collect();
+
+        let mut out = vec![0xFF, SOI];
+        for (marker, payload) in &self.segments {
+            match payload.strip_prefix(EXIF_HEADER) {
+                Some(exif) if *marker == APP1 => {
+                    let mut exif = exif.to_vec();
+                    crate::metadata::set_exif_dimensions(&mut exif, rect.width, rect.height);
+                    write_segment(&mut out, *marker, &[EXIF_HEADER, &exif].concat());
+                }
+                _ => write_segment(&mut out, *marker, payload),
+            }
+        }
+
+        let mut frame = vec![8];
+        frame.extend((rect.height as u16).to_be_bytes());
+        frame.extend((rect.width as u16).to_be_bytes());
+        frame.push(self.components.len() as u8);
+        for component in &self.components {
+            frame.extend([
+                component.id,
+                ((component.h as u8) << 4) | component.v as u8,
+                component.tq,
+            ]);
+        }
+        write_segment(&mut out, SOF0, &frame);
+
+        let mut tables = Vec::new();
+        for (class, codes) in [(0u8, &dc_codes), (1, &ac_codes)] {
+            for (id, code) in codes.iter().enumerate() {
+                tables.push((class << 4) | id as u8);
+                tables.extend(code.counts);
+                tables.extend(&code.values);
+            }
+        }
+        write_segment(&mut out, DHT, &tables);
+
+        let mut scan = vec![self.components.len() as u8];
+        for (c, component) in self.components.iter().enumerate() {
+            let table = table_of(c) as u8;
+            scan.extend([component.id, (table << 4) | table]);
+        }
+        scan.extend([0, 63, 0]);
+        write_segment(&mut out, SOS, &scan);
+
+        let mut writer = BitWriter {
+            out: &mut out,
+            byte: 0,
+            used: 0,
+        };
+        predictions.fill(0);
+        for &(c, block) in &coded {
+            let table = table_of(c);
+            encode_block(block, &mut predictions[c], |symbol, bits, len, dc| {
+                let code = if dc {
+                    &dc_codes[table]
+                } else {
+                    &ac_codes[table]
+                };
+                let (symbol_code, symbol_len) = code.codes[usize::from(symbol)];
+                writer.write(symbol_code, symbol_len);
+                writer.write(bits, len);
+            });
+        }
+        writer.flush();
+
+        out.extend([0xFF, EOI]);
+        out
+    }
+}
+
+/// Index into a component's blocks of the block at column `x` and row `y`
+/// of its grid, if its MCU row is kept.
+fn block_index(rows: &[Option<usize>], component: &Component, x: usize, y: usize) -> Option<usize> {
+    let slot = (*rows.get(y / component.v)?)?;
+    Some((slot * component.v + y % component.v) * component.blocks_w + x)
+}
+
+/// Whether a file is a JPEG that can be cropped losslessly, and so decoded
+/// in parts, judged from its headers without reading the rest of the file.
+pub(crate) fn is_baseline(path: &Path) -> bool {
+    matches!(Header::read(path), Ok(Some(_)))
+}
+
+/// Decode the parts of a baseline JPEG inside `rects`, which lie within the
+/// image, without decoding the rest of it.
+///
+/// Only the coefficients of the MCU rows the rectangles cover are kept. Each
+/// rectangle is grown by an MCU on every side, cropped losslessly and decoded
+/// on its own, so that chroma upsampling sees the same neighbours as when the
+/// whole image is decoded and the pixels match.
+pub(crate) fn decode_regions(path: &Path, rects: &[Rect]) -> Result<Vec<DynamicImage>> {
+    let data =
+        std::fs::read(path).with_context(|| format!("Unable to read '{}'", path.display()))?;
+    let spans: Vec<(u32, u32)> = rects
+        .iter()
+        .map(|rect| (rect.y as u32, rect.y as u32 + rect.height))
+        .collect();
+    let source = Source::parse(&data, Some(&spans))
+        .with_context(|| format!("Unable to decode '{}'", path.display()))?;
+
+    let (mcu_w, mcu_h) = source.mcu_size();
+    let grow = |start: i64, size: u32, mcu: u32, limit: u32| {
+        let first = (start as u32 / mcu).saturating_sub(1) * mcu;
+        let end = ((start as u32 + size).div_ceil(mcu) + 1) * mcu;
+        (first, end.min(limit) - first)
+    };
+    rects
+        .par_iter()
+        .map(|rect| {
+            let (x, width) = grow(rect.x, rect.width, mcu_w, source.width);
+            let (y, height) = grow(rect.y, rect.height, mcu_h, source.height);
+            let region = source.crop(Rect {
+                x: i64::from(x),
+                y: i64::from(y),
+                width,
+                height,
+            });
+            let img = image::load_from_memory_with_format(&region, ImageFormat::Jpeg)
+                .with_context(|| format!("Unable to decode '{}'", path.display()))?;
+            Ok(img.crop_imm(
+                rect.x as u32 - x,
+                rect.y as u32 - y,
+                rect.width,
+                rect.height,
+            ))
+        })
+        .collect()
+}
+
+/// Parse the tables of a DHT segment.
+fn parse_huffman_tables(
+    mut payload: &[u8],
+    dc_tables: &mut [Option<HuffmanTable>; 4],
+    ac_tables: &mut [Option<HuffmanTable>; 4],
+) -> Result<()> {
+    let invalid = || anyhow!("Corrupt JPEG data: invalid Huffman table");
+    while let Some((&spec, rest)) = payload.split_first() {
+        let counts: [u8; 16] = rest
+            .get(..16)
+            .and_then(|c| c.try_into().ok())
+            .ok_or_else(invalid)?;
+        let total = counts.iter().map(|&c| usize::from(c)).sum::<usize>();
+        let values = rest.get(16..16 + total).ok_or_else(invalid)?.to_vec();
+        let table = HuffmanTable::new(&counts, values);
+        match (spec >> 4, usize::from(spec & 15)) {
+            (0, id @ 0..=3) => dc_tables[id] = Some(table),
+            (1, id @ 0..=3) => ac_tables[id] = Some(table),
+            _ => return Err(invalid()),
+        }
+        payload = &rest[16 + total..];
+    }
+    Ok(())
+}
+
+/// Decode one block's DC difference and AC coefficients.
+fn decode_block(
+    reader: &mut BitReader,
+    dc: &HuffmanTable,
+    ac: &HuffmanTable,
+    prediction: &mut i32,
+    block: &mut [i16; 64],
+) -> Result<()> {
+    let size = dc.decode(reader)?;
+    if size > 15 {
+        return Err(anyhow!("Corrupt JPEG data: invalid DC coefficient"));
+    }
+    *prediction += reader.value(size);
+    block[0] = *prediction as i16;
+
+    let mut k = 1;
+    while k < 64 {
+        let symbol = ac.decode(reader)?;
+        let (run, size) = (usize::from(symbol >> 4), symbol & 15);
+        if size == 0 {
+            if run == 15 {
+                k += 16;
+                continue;
+            }
+            break;
+        }
+        k += run;
+        if k > 63 {
+            return Err(anyhow!("Corrupt JPEG data: invalid AC coefficient"));
+        }
+        block[k] = reader.value(size) as i16;
+        k += 1;
+    }
+    Ok(())
+}
+
+/// Produce the Huffman symbols of a block, each with the extra bits that
+/// follow it and whether it is a DC symbol.
+fn encode_block(block: &[i16; 64], prediction: &mut i32, mut emit: impl FnMut(u8, u16, u8, bool)) {
+    let diff = i32::from(block[0]) - *prediction;
+    *prediction = i32::from(block[0]);
+    let (size, bits) = magnitude(diff);
+    emit(size, bits, size, true);
+
+    let mut run = 0;
+    for &coefficient in &block[1..] {
+        if coefficient == 0 {
+            run += 1;
+            continue;
+        }
+        while run > 15 {
+            emit(0xF0, 0, 0, false);
+            run -= 16;
+        }
+        let (size, bits) = magnitude(i32::from(coefficient));
+        emit((run << 4) | size, bits, size, false);
+        run = 0;
+    }
+    if run > 0 {
+        emit(0x00, 0, 0, false);
+    }
+}
+
+/// Size category and extra bits of a coefficient value.
+fn magnitude(value: i32) -> (u8, u16) {
+    let size = (32 - value.unsigned_abs().leading_zeros()) as u8;
+    let bits = if value < 0 { value - 1 } else { value };
+    (size, (bits as u16) & ((1u32 << size) - 1) as u16)
+}
+
+/// A Huffman code built for the symbols actually used.
+struct HuffmanCode {
+    /// Number of codes of each length from 1 to 16
+    counts: [u8; 16],
+    /// Symbols ordered by code length
+    values: Vec<u8>,
+    /// Code and length of each symbol
+    codes: [(u16, u8); 256],
+}
+
+impl HuffmanCode {
+    /// Build an optimal code limited to 16 bits, following JPEG Annex K.2.
+    fn optimal(frequencies: &[u32; 257]) -> Self {
+        let mut freq = *frequencies;
+        // A reserved symbol keeps any real code from being all ones
+        freq[256] = 1;
+        let mut code_size = [0usize; 257];
+        let mut others = [None::<usize>; 257];
+
+        loop {
+            let least = |exclude: Option<usize>| {
+                (0..257)
+                    .filter(|&i| freq[i] > 0 && Some(i) != exclude)
+                    .min_by_key(|&i| (freq[i], std::cmp::Reverse(i)))
+            };
+            let Some(v1) = least(None) else { break };
+            let Some(v2) = least(Some(v1)) else { break };
+
+            freq[v1] += freq[v2];
+            freq[v2] = 0;
+            let mut v = v1;
+            code_size[v] += 1;
+            while let Some(next) = others[v] {
+                v = next;
+                code_size[v] += 1;
+            }
+            others[v] = Some(v2);
+            let mut v = v2;
+            code_size[v] += 1;
+            while let Some(next) = others[v] {
+                v = next;
+                code_size[v] += 1;
+            }
+        }
+
+        let mut bits = [0u32; 33];
+        for &size in code_size.iter().filter(|&&size| size > 0) {
+            bits[size] += 1;
+        }
+        // Limit code lengths to 16 bits
+        for i in (17..=32).rev() {
+            while bits[i] > 0 {
+                let mut j = i - 2;
+                while bits[j] == 0 {
+                    j -= 1;
+                }
+                bits[i] -= 2;
+                bits[i - 1] += 1;
+                bits[j + 1] += 2;
+                bits[j] -= 1;
+            }
+        }
+        // Drop the reserved symbol, which has the longest code
+        if let Some(longest) = (1..=16).rev().find(|&i| bits[i] > 0) {
+            bits[longest] -= 1;
+        }
+
+        let mut values: Vec<u8> = Vec::new();
+        for size in 1..=32 {
+            values.extend((0..256).filter(|&i| code_size[i] == size).map(|i| i as u8));
+        }
+        let mut counts = [0u8; 16];
+        for (count, &bits) in counts.iter_mut().zip(&bits[1..=16]) {
+            *count = bits as u8;
+        }
+        values.truncate(counts.iter().map(|&c| usize::from(c)).sum());
+
+        let mut codes = [(0u16, 0u8); 256];
+        let mut code = 0u16;
+        let mut k = 0;
+        for (len, &count) in (1..=16u8).zip(&counts) {
+            for _ in 0..count {
+                codes[usize::from(values[k])] = (code, len);
+                code = code.wrapping_add(1);
+                k += 1;
+            }
+            code = code.wrapping_shl(1);
+        }
+
+        HuffmanCode {
+            counts,
+            values,
+            codes,
+        }
+    }
+}
+
+/// Writes entropy-coded data, stuffing a zero after every 0xFF byte.
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    byte: u8,
+    used: u8,
+}
+
+impl BitWriter<'_> {
+    fn write(&mut self, bits: u16, len: u8) {
+        for i in (0..len).rev() {
+            self.byte = (self.byte << 1) | ((bits >> i) & 1) as u8;
+            self.used += 1;
+            if self.used == 8 {
+                self.out.push(self.byte);
+                if self.byte == 0xFF {
+                    self.out.push(0);
+                }
+                self.byte = 0;
+                self.used = 0;
+            }
+        }
+    }
+
+    /// Pad the last byte with ones.
+    fn flush(&mut self) {
+        if self.used > 0 {
+            let len = 8 - self.used;
+            self.write((1 << len) - 1, len);
+        }
+    }
+}
+
+/// Append a marker segment.
+fn write_segment(out: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+    out.extend([0xFF, marker]);
+    out.extend(((payload.len() + 2) as u16).to_be_bytes());
+    out.extend(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{DynamicImage, GenericImageView};
+
+    fn encode(img: &DynamicImage) -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        img.write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, 80))
+            .unwrap();
+        jpeg
+    }
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 7) as u8, (y * 5) as u8, ((x + y) * 3) as u8])
+        }))
+    }
+
+    #[test]
+    fn test_lossless_crop_matches_decoded_pixels() {
+        for img in [
+            gradient(100, 70),
+            DynamicImage::ImageLuma8(gradient(45, 37).to_luma8()),
+        ] {
+            let jpeg = encode(&img);
+            let source = Source::parse(&jpeg, None).unwrap();
+            let decoded = image::load_from_memory(&jpeg).unwrap();
+            let (width, height) = decoded.dimensions();
+
+            let (mcu_w, mcu_h) = source.mcu_size();
+            for (x, y, w, h) in [
+                (0, 0, width, height),
+                (mcu_w, mcu_h, 21, 13),
+                (16, 8, width - 16, height - 8),
+            ] {
+                let rect = Rect {
+                    x: i64::from(x),
+                    y: i64::from(y),
+                    width: w,
+                    height: h,
+                };
+                let cropped = image::load_from_memory(&source.crop(rect)).unwrap();
+                assert_eq!(cropped.dimensions(), (w, h));
+                assert_eq!(
+                    cropped.as_bytes(),
+                    decoded.crop_imm(x, y, w, h).as_bytes(),
+                    "crop {w}x{h} at ({x}, {y})"
+                );
+            }
+        }
+    }
+
+    /// A 4:2:0 JPEG built from pseudo-random coefficients.
+    fn subsampled(width: u32, height: u32) -> Source {
+        let mut seed = 1u32;
+        let mut random = |range: i16| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            ((seed >> 16) % (2 * range as u32 + 1)) as i16 - range
+        };
+        let mut components = Vec::new();
+        for (id, h, v) in [(1, 2, 2), (2, 1, 1), (3, 1, 1)] {
+            let (blocks_w, blocks_h) = (
+                width.div_ceil(16) as usize * h,
+                height.div_ceil(16) as usize * v,
+            );
+            let blocks = (0..blocks_w * blocks_h)
+                .map(|_| {
+                    let mut block = [0i16; 64];
+                    block[0] = random(60);
+                    for k in [1, 2, 5, 9, 20, 63] {
+                        block[k] = random(8);
+                    }
+                    block
+                })
+                .collect();
+            components.push(Component {
+                id,
+                h,
+                v,
+                tq: 0,
+                blocks_w,
+                blocks,
+            });
+        }
+        Source {
+            width,
+            height,
+            segments: vec![(DQT, [&[0u8][..], &[4; 64]].concat())],
+            components,
+            rows: (0..height.div_ceil(16) as usize).map(Some).collect(),
+        }
+    }
+
+    #[test]
+    fn test_subsampled_round_trip() {
+        let (width, height) = (100u32, 70u32);
+        let source = subsampled(width, height);
+        assert_eq!(source.mcu_size(), (16, 16));
+
+        let full = source.crop(Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        });
+        let parsed = Source::parse(&full, None).unwrap();
+        for (a, b) in parsed.components.iter().zip(&source.components) {
+            assert_eq!((a.h, a.v, a.blocks_w), (b.h, b.v, b.blocks_w));
+            assert_eq!(a.blocks, b.blocks);
+        }
+
+        let rect = Rect {
+            x: 32,
+            y: 16,
+            width: 50,
+            height: 41,
+        };
+        let cropped = image::load_from_memory(&parsed.crop(rect))
+            .unwrap()
+            .to_rgb8();
+        let decoded = image::load_from_memory(&full)
+            .unwrap()
+            .crop_imm(32, 16, 50, 41)
+            .to_rgb8();
+        assert_eq!(cropped.dimensions(), (50, 41));
+        // Chroma upsampling may blend across the crop edge, so only the
+        // inside is compared
+        for (x, y, pixel) in cropped.enumerate_pixels() {
+            if (2..48).contains(&x) && (2..39).contains(&y) {
+                assert_eq!(pixel, decoded.get_pixel(x, y), "pixel ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_regions_matches_full_decode() {
+        let dir = TempDir::new("regions");
+        let path = dir.join("in.jpg");
+        let (width, height) = (150u32, 130u32);
+        let jpeg = subsampled(width, height).crop(Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        });
+        std::fs::write(&path, &jpeg).unwrap();
+        assert!(is_baseline(&path));
+
+        let full = image::load_from_memory(&jpeg).unwrap();
+        let rects: Vec<Rect> = [
+            (0, 0, width, height),
+            (37, 45, 20, 11),
+            (140, 3, 10, 60),
+            (0, 120, 150, 10),
+        ]
+        .iter()
+        .map(|&(x, y, width, height)| Rect {
+            x: i64::from(x),
+            y: i64::from(y),
+            width,
+            height,
+        })
+        .collect();
+        let regions = decode_regions(&path, &rects).unwrap();
+        for (rect, region) in rects.iter().zip(regions) {
+            let (x, y) = (rect.x as u32, rect.y as u32);
+            assert_eq!(
+                region.as_bytes(),
+                full.crop_imm(x, y, rect.width, rect.height).as_bytes(),
+                "{rect:?}"
+            );
+        }
+
+        // Only the MCU rows around the rectangles are kept
+        let source = Source::parse(&jpeg, Some(&[(37, 57)])).unwrap();
+        assert_eq!(source.rows.iter().flatten().count(), 4);
+
+        // Lossless crops read the headers first, then only the rows they need
+        let header = Header::read(&path).unwrap().unwrap();
+        assert_eq!((header.width, header.height), (width, height));
+        assert_eq!(header.mcu_size, (16, 16));
+        let rect = Rect {
+            x: 32,
+            y: 48,
+            width: 50,
+            height: 20,
+        };
+        let source = header.source(&path, &[rect]).unwrap();
+        assert_eq!(source.rows.iter().flatten().count(), 4);
+        assert_eq!(source.crop(rect), subsampled(width, height).crop(rect));
+
+        let png = dir.join("in.png");
+        full.save(&png).unwrap();
+        assert_eq!(Header::read(&png).unwrap(), None);
+        assert!(!is_baseline(&png));
+    }
+
+    #[test]
+    fn test_align() {
+        let source = Header::parse(&mut encode(&gradient(100, 70)).as_slice())
+            .unwrap()
+            .unwrap();
+        assert_eq!(source.mcu_size, (8, 8));
+        let rect = Rect {
+            x: 10,
+            y: 19,
+            width: 20,
+            height: 30,
+        };
+        let result = source.align(rect, JpegCrop::Lossless);
+        assert!(result.unwrap_err().to_string().contains("not aligned"));
+        assert_eq!(
+            source.align(rect, JpegCrop::Snap).unwrap(),
+            Rect {
+                x: 8,
+                y: 16,
+                width: 22,
+                height: 33
+            }
+        );
+        let outside = Rect { x: 96, ..rect };
+        assert!(source.align(outside, JpegCrop::Snap).is_err());
+    }
+
+    #[test]
+    fn test_parse_jpeg_crop() {
+        assert_eq!("lossless".parse::<JpegCrop>(), Ok(JpegCrop::Lossless));
+        assert_eq!("Snap".parse::<JpegCrop>(), Ok(JpegCrop::Snap));
+        assert_eq!("re-encode".parse::<JpegCrop>(), Ok(JpegCrop::Reencode));
+        assert!("fast".parse::<JpegCrop>().is_err());
+    }
+}
@@ -1,40 +1,707 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::io::Write;
+use std::ops::Add;
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 use std::time::Instant;
 
 use anyhow::{anyhow, Context, Result};
 use clap::{ArgAction, Parser};
-use image::{DynamicImage, GenericImageView};
+use image::{DynamicImage, GenericImageView, Rgba};
 use rayon::prelude::*;
 
+mod frames;
+mod jpeg;
+mod memory;
+mod metadata;
+mod output;
+mod pyramid;
+mod region;
+mod report;
+mod spec_file;
+#[cfg(test)]
+mod test_util;
+
 /// A rectangular capture region specification.
 ///
 /// Defines a named rectangular area within an image to be extracted.
-/// Coordinates are specified relative to the chosen origin (top-left or bottom-left).
+/// Coordinates are specified relative to the chosen origin (see `Origin`),
+/// or relative to the spec's own anchor point when one is given.
 #[derive(Debug, Clone)]
 struct CaptureSpec {
     /// Name of the capture region, used in output filename
     name: String,
-    /// X coordinate (left edge) in pixels
-    x: u32,
-    /// Y coordinate in pixels (interpretation depends on origin)
-    y: u32,
-    /// Width of the region in pixels
+    /// Anchor point the rectangle is positioned against; overrides the origin
+    anchor: Option<Anchor>,
+    /// X coordinate (left edge); negative values are measured from the far edge
+    x: Length,
+    /// Y coordinate (interpretation depends on origin); negative values are
+    /// measured from the far edge
+    y: Length,
+    /// Width of the region
+    width: Length,
+    /// Height of the region
+    height: Length,
+    /// How to handle the region extending past the image edge;
+    /// --out-of-bounds if unset
+    out_of_bounds: Option<OutOfBounds>,
+    /// Pages or frames to capture from; --pages, or every page, if unset
+    pages: Option<frames::Pages>,
+}
+
+/// How a capture extending past the image edge is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutOfBounds {
+    /// Fail the capture
+    Error,
+    /// Shrink the rectangle to the part inside the image
+    Clamp,
+    /// Keep the requested size, filling the area outside the image with the
+    /// given color, or with zeroed (black or transparent) pixels if unset
+    Pad(Option<Rgba<u8>>),
+    /// Leave the capture out for this image
+    Skip,
+}
+
+impl std::str::FromStr for OutOfBounds {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
+        match lower.split_once(':') {
+            Some(("pad", color)) => parse_color(color).map(|c| OutOfBounds::Pad(Some(c))),
+            _ => match lower.as_str() {
+                "error" | "strict" => Ok(OutOfBounds::Error),
+                "clamp" | "clip" => Ok(OutOfBounds::Clamp),
+                "pad" => Ok(OutOfBounds::Pad(None)),
+                "skip" => Ok(OutOfBounds::Skip),
+                _ => Err(format!(
+                    "Invalid out-of-bounds policy '{s}'. Supported values: error, clamp, pad, pad:<color>, skip"
+                )),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for OutOfBounds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutOfBounds::Error => f.write_str("error"),
+            OutOfBounds::Clamp => f.write_str("clamp"),
+            OutOfBounds::Pad(None) => f.write_str("pad"),
+            OutOfBounds::Pad(Some(Rgba([r, g, b, 255]))) => write!(f, "pad:#{r:02x}{g:02x}{b:02x}"),
+            OutOfBounds::Pad(Some(Rgba([r, g, b, a]))) => {
+                write!(f, "pad:#{r:02x}{g:02x}{b:02x}{a:02x}")
+            }
+            OutOfBounds::Skip => f.write_str("skip"),
+        }
+    }
+}
+
+/// Parse a padding color: `black`, `white`, `transparent`, or hex
+/// `#rrggbb` / `#rrggbbaa` (the `#` is optional).
+fn parse_color(s: &str) -> std::result::Result<Rgba<u8>, String> {
+    match s {
+        "black" => return Ok(Rgba([0, 0, 0, 255])),
+        "white" => return Ok(Rgba([255, 255, 255, 255])),
+        "transparent" => return Ok(Rgba([0, 0, 0, 0])),
+        _ => {}
+    }
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    let channel = |i: usize| {
+        hex.get(i..i + 2)
+            .and_then(|c| u8::from_str_radix(c, 16).ok())
+    };
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok(Rgba([r, g, b, 255])),
+        (8, Some(r), Some(g), Some(b)) => channel(6)
+            .map(|a| Rgba([r, g, b, a]))
+            .ok_or_else(|| format!("Invalid color '{s}'")),
+        _ => Err(format!(
+            "Invalid color '{s}'. Expected black, white, transparent, #rrggbb or #rrggbbaa"
+        )),
+    }
+}
+
+/// A distance along one image axis.
+///
+/// Lengths are either absolute pixel counts or fractions of the image
+/// dimension along that axis. Fractions are resolved per image in
+/// `convert_coordinates`, so one spec can cover images of varying size.
+///
+/// Lengths are signed so that offsets can count back from the far edge of
+/// the image, e.g. `-200` or `-10%`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Length {
+    /// An absolute number of pixels, e.g. `200`
+    Pixels(i64),
+    /// A fraction of the image dimension, e.g. `50%` or `0.5`
+    Fraction(f64),
+}
+
+/// Tolerance used when rounding resolved fractions, so that values such as
+/// `0.1 * 1000` land on the intended pixel despite floating point error.
+const FRACTION_EPSILON: f64 = 1e-6;
+
+impl Length {
+    /// Returns true if this length is greater than zero regardless of image size.
+    fn is_positive(self) -> bool {
+        match self {
+            Length::Pixels(v) => v > 0,
+            Length::Fraction(f) => f > 0.0,
+        }
+    }
+
+    /// Resolve an offset against an image dimension, rounding down.
+    ///
+    /// Negative offsets are measured back from the far edge, so `-200`
+    /// resolves to `total - 200`. Returns `None` if the result falls before
+    /// the start of the axis or does not fit in a pixel coordinate.
+    fn resolve_offset(self, total: u32) -> Option<u32> {
+        let v = self.resolve_signed(total);
+        if v < 0 {
+            u32::try_from(i64::from(total) + v).ok()
+        } else {
+            u32::try_from(v).ok()
+        }
+    }
+
+    /// Resolve a signed offset against an image dimension, rounding down.
+    fn resolve_signed(self, total: u32) -> i64 {
+        match self {
+            Length::Pixels(v) => v,
+            Length::Fraction(f) => (f * f64::from(total) + FRACTION_EPSILON).floor() as i64,
+        }
+    }
+
+    /// Resolve an extent against an image dimension, rounding up.
+    ///
+    /// Offsets round down and extents round up, so complementary fractions
+    /// such as `0%` + `50%` and `50%` + `50%` always tile the full image.
+    fn resolve_extent(self, total: u32) -> u32 {
+        match self {
+            Length::Pixels(v) => u32::try_from(v).unwrap_or(u32::MAX),
+            Length::Fraction(f) => (f * f64::from(total) - FRACTION_EPSILON).ceil() as u32,
+        }
+    }
+}
+
+impl std::str::FromStr for Length {
+    type Err = String;
+
+    /// Parse a length: `200` (pixels), `50%` (percent) or `0.5` (fraction),
+    /// optionally preceded by a minus sign.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let fraction = if let Some(percent) = s.strip_suffix('%') {
+            percent
+                .parse::<f64>()
+                .map(|p| p / 100.0)
+                .map_err(|e| format!("Invalid percentage '{s}': {e}"))?
+        } else if s.contains('.') {
+            s.parse::<f64>()
+                .map_err(|e| format!("Invalid fraction '{s}': {e}"))?
+        } else {
+            return s
+                .parse::<i64>()
+                .map(Length::Pixels)
+                .map_err(|e| format!("Invalid pixel value '{s}': {e}"));
+        };
+
+        if !fraction.is_finite() {
+            return Err(format!("Invalid length '{s}': must be a finite number"));
+        }
+        Ok(Length::Fraction(fraction))
+    }
+}
+
+impl std::fmt::Display for Length {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Length::Pixels(v) => write!(f, "{v}"),
+            Length::Fraction(frac) => write!(f, "{}%", frac * 100.0),
+        }
+    }
+}
+
+/// A capture rectangle resolved to absolute pixel coordinates.
+///
+/// Always expressed in the top-left coordinate system of the image. The
+/// position is signed because padded captures may extend past the image edges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rect {
+    x: i64,
+    y: i64,
     width: u32,
-    /// Height of the region in pixels
     height: u32,
 }
 
+/// Alignment of a capture rectangle along one image axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Align {
+    /// Offsets are measured from the left or top edge
+    Start,
+    /// Rectangle is centred on the axis; offsets shift it right or down
+    Middle,
+    /// Offsets are measured inward from the right or bottom edge
+    End,
+}
+
+/// One of the nine reference points a capture can be positioned against.
+///
+/// For edge and corner anchors the x/y offsets are distances inward from the
+/// anchor to the matching edge of the rectangle, so `se` with `20x20` places
+/// the rectangle's bottom-right corner 20px in from the image's bottom-right
+/// corner. On a centred axis the rectangle is centred and the offset shifts it
+/// right or down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Anchor {
+    NorthWest,
+    North,
+    NorthEast,
+    West,
+    Center,
+    East,
+    SouthWest,
+    South,
+    SouthEast,
+}
+
+impl Anchor {
+    /// Returns the (horizontal, vertical) alignment for this anchor.
+    fn alignment(self) -> (Align, Align) {
+        match self {
+            Anchor::NorthWest => (Align::Start, Align::Start),
+            Anchor::North => (Align::Middle, Align::Start),
+            Anchor::NorthEast => (Align::End, Align::Start),
+            Anchor::West => (Align::Start, Align::Middle),
+            Anchor::Center => (Align::Middle, Align::Middle),
+            Anchor::East => (Align::End, Align::Middle),
+            Anchor::SouthWest => (Align::Start, Align::End),
+            Anchor::South => (Align::Middle, Align::End),
+            Anchor::SouthEast => (Align::End, Align::End),
+        }
+    }
+}
+
+impl std::str::FromStr for Anchor {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nw" | "tl" | "top-left" | "top_left" => Ok(Anchor::NorthWest),
+            "n" | "top" => Ok(Anchor::North),
+            "ne" | "tr" | "top-right" | "top_right" => Ok(Anchor::NorthEast),
+            "w" | "left" => Ok(Anchor::West),
+            "c" | "center" | "centre" => Ok(Anchor::Center),
+            "e" | "right" => Ok(Anchor::East),
+            "sw" | "bl" | "bottom-left" | "bottom_left" => Ok(Anchor::SouthWest),
+            "s" | "bottom" => Ok(Anchor::South),
+            "se" | "br" | "bottom-right" | "bottom_right" => Ok(Anchor::SouthEast),
+            other => Err(format!(
+                "Invalid anchor '{other}'. Supported values: nw, n, ne, w, center, e, sw, s, se"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Anchor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Anchor::NorthWest => "nw",
+            Anchor::North => "n",
+            Anchor::NorthEast => "ne",
+            Anchor::West => "w",
+            Anchor::Center => "center",
+            Anchor::East => "e",
+            Anchor::SouthWest => "sw",
+            Anchor::South => "s",
+            Anchor::SouthEast => "se",
+        };
+        f.write_str(name)
+    }
+}
+
+/// An evenly divided grid of capture cells, generated per image.
+///
+/// Cells are named `r<row>c<col>`, counting from zero at the top-left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Grid {
+    /// A fixed number of rows and columns; cell edges are rounded down so
+    /// that cells tile the image exactly
+    Count { rows: u32, cols: u32 },
+    /// A fixed cell size in pixels; partial cells at the right and bottom
+    /// edges are dropped
+    CellSize { width: u32, height: u32 },
+    /// Fixed-size tiles placed every `stride` pixels, overlapping when the
+    /// stride is smaller than the tile
+    Tiles {
+        width: u32,
+        height: u32,
+        stride_x: u32,
+        stride_y: u32,
+        edge: TileEdge,
+    },
+}
+
+/// How tiles that would extend past the right or bottom edge are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TileEdge {
+    /// Drop partial tiles, leaving the remainder of the image uncaptured
+    Drop,
+    /// Keep partial tiles at full size, padding the area outside the image
+    Pad,
+    /// Shift the last tile back so that it ends at the image edge
+    Shift,
+}
+
+impl std::str::FromStr for TileEdge {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "drop" => Ok(TileEdge::Drop),
+            "pad" => Ok(TileEdge::Pad),
+            "shift" | "shift-in" | "shift_in" => Ok(TileEdge::Shift),
+            other => Err(format!(
+                "Invalid tile edge '{other}'. Supported values: drop, pad, shift"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for TileEdge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            TileEdge::Drop => "drop",
+            TileEdge::Pad => "pad",
+            TileEdge::Shift => "shift",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Grid {
+    /// Generate the capture specs for every cell of this grid in an image.
+    ///
+    /// Cells extending past the image edge are marked to be padded.
+    fn specs(self, img_width: u32, img_height: u32) -> Result<Vec<CaptureSpec>> {
+        let (cols, rows) = match self {
+            Grid::Count { rows, cols } => {
+                if cols > img_width || rows > img_height {
+                    return Err(anyhow!(
+                        "Grid {rows}x{cols} has more cells than pixels in image {img_width}x{img_height}"
+                    ));
+                }
+                (
+                    spans_from_edges(&even_edges(cols, img_width)),
+                    spans_from_edges(&even_edges(rows, img_height)),
+                )
+            }
+            Grid::CellSize { width, height } => {
+                if width > img_width || height > img_height {
+                    return Err(anyhow!(
+                        "Grid cell size {width}x{height} is larger than image {img_width}x{img_height}"
+                    ));
+                }
+                (
+                    (0..img_width / width).map(|i| (i * width, width)).collect(),
+                    (0..img_height / height)
+                        .map(|i| (i * height, height))
+                        .collect(),
+                )
+            }
+            Grid::Tiles {
+                width,
+                height,
+                stride_x,
+                stride_y,
+                edge,
+            } => (
+                tile_spans(width, stride_x, img_width, edge),
+                tile_spans(height, stride_y, img_height, edge),
+            ),
+        };
+
+        let mut specs = Vec::with_capacity(rows.len() * cols.len());
+        for (row, &(y, h)) in rows.iter().enumerate() {
+            for (col, &(x, w)) in cols.iter().enumerate() {
+                let overflows = u64::from(x) + u64::from(w) > u64::from(img_width)
+                    || u64::from(y) + u64::from(h) > u64::from(img_height);
+                specs.push(CaptureSpec {
+                    name: format!("r{row}c{col}"),
+                    anchor: Some(Anchor::NorthWest),
+                    x: Length::Pixels(i64::from(x)),
+                    y: Length::Pixels(i64::from(y)),
+                    width: Length::Pixels(i64::from(w)),
+                    height: Length::Pixels(i64::from(h)),
+                    out_of_bounds: overflows.then_some(OutOfBounds::Pad(None)),
+                    pages: None,
+                });
+            }
+        }
+        Ok(specs)
+    }
+}
+
+/// Convert a list of edges into `(start, length)` spans between them.
+fn spans_from_edges(edges: &[u32]) -> Vec<(u32, u32)> {
+    edges.windows(2).map(|e| (e[0], e[1] - e[0])).collect()
+}
+
+/// Place tiles of `size` pixels every `stride` pixels along an axis of
+/// `total` pixels, returning their `(start, length)` spans.
+///
+/// The last tile may extend past the edge when padding, or when shifting an
+/// axis shorter than a single tile.
+fn tile_spans(size: u32, stride: u32, total: u32, edge: TileEdge) -> Vec<(u32, u32)> {
+    let mut spans: Vec<(u32, u32)> = Vec::new();
+    let mut start: u64 = 0;
+    loop {
+        let end = start + u64::from(size);
+        if end <= u64::from(total) {
+            spans.push((start as u32, size));
+            if end == u64::from(total) {
+                break;
+            }
+        } else {
+            match edge {
+                TileEdge::Drop => {}
+                TileEdge::Pad => spans.push((start as u32, size)),
+                TileEdge::Shift => {
+                    let shifted = total.saturating_sub(size);
+                    if spans.last().map(|&(s, _)| s) != Some(shifted) {
+                        spans.push((shifted, size));
+                    }
+                }
+            }
+            break;
+        }
+        start += u64::from(stride);
+    }
+    spans
+}
+
+/// Split `total` pixels into `count` near-equal spans, returning the
+/// `count + 1` edges. Edges are rounded down so the spans tile exactly.
+fn even_edges(count: u32, total: u32) -> Vec<u32> {
+    (0..=count)
+        .map(|i| (u64::from(i) * u64::from(total) / u64::from(count)) as u32)
+        .collect()
+}
+
+impl std::fmt::Display for Grid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Grid::Count { rows, cols } => write!(f, "{rows} rows x {cols} columns"),
+            Grid::CellSize { width, height } => write!(f, "{width}x{height} cells"),
+            Grid::Tiles {
+                width,
+                height,
+                stride_x,
+                stride_y,
+                edge,
+            } => write!(
+                f,
+                "{width}x{height} tiles every {stride_x}x{stride_y} pixels ({edge} edges)"
+            ),
+        }
+    }
+}
+
+/// Parse a `<a>x<b>` pair of positive integers for grid arguments.
+fn parse_grid_arg(s: &str) -> std::result::Result<(u32, u32), String> {
+    let (a, b) = s
+        .split_once('x')
+        .ok_or_else(|| format!("Invalid grid '{s}'. Expected format: <a>x<b>"))?;
+    let a: u32 = a.parse().map_err(|e| format!("Invalid grid '{s}': {e}"))?;
+    let b: u32 = b.parse().map_err(|e| format!("Invalid grid '{s}': {e}"))?;
+    if a == 0 || b == 0 {
+        return Err(format!("Invalid grid '{s}': values must be positive"));
+    }
+    Ok((a, b))
+}
+
+/// Parse a tile stride or overlap: `<n>` for both axes or `<x>x<y>`.
+fn parse_tile_step(s: &str) -> std::result::Result<(u32, u32), String> {
+    let parse = |v: &str| {
+        v.parse::<u32>()
+            .map_err(|e| format!("Invalid tile step '{s}': {e}"))
+    };
+    match s.split_once('x') {
+        Some((x, y)) => Ok((parse(x)?, parse(y)?)),
+        None => parse(s).map(|v| (v, v)),
+    }
+}
+
+/// Settings shared by every input image in a run.
+#[derive(Debug)]
+struct Options {
+    /// Coordinate origin for specs without their own anchor
+    origin: Origin,
+    /// Apply per-image sidecar files
+    sidecars: bool,
+    /// Rotate and flip images as their orientation metadata says before capturing
+    auto_orient: bool,
+    /// Grid of cells to capture in addition to the capture specs
+    grid: Option<Grid>,
+    /// How captures without their own policy treat the image edge
+    out_of_bounds: OutOfBounds,
+    /// Build a tile pyramid from each input instead of capturing regions
+    pyramid: Option<pyramid::Pyramid>,
+    /// Pages or frames captured by specs without their own selection
+    pages: Option<frames::Pages>,
+    /// Where each capture is written
+    output: output::OutputTemplate,
+    /// How each capture is encoded
+    encoding: output::Encoding,
+    /// How captures of JPEG inputs written as JPEG are cropped
+    jpeg_crop: jpeg::JpegCrop,
+    /// Output paths claimed so far, and the policy for existing files
+    outputs: output::OutputRegistry,
+    /// Report failed inputs and captures and carry on with the rest
+    keep_going: bool,
+    /// Print a JSON record per input to stdout instead of progress text
+    json: bool,
+    /// Print timing information
+    verbose: bool,
+}
+
+/// Exit code when --keep-going finished with some, but not all, inputs failed.
+const EXIT_PARTIAL_FAILURE: u8 = 3;
+
+/// What became of the inputs and outputs of a run, or of a single input.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "type", rename = "summary")]
+struct Summary {
+    /// Inputs processed, whether or not they succeeded
+    images: usize,
+    /// Inputs that failed, or had any output fail
+    failed_images: usize,
+    /// Output files written, counting pyramid tiles
+    written: usize,
+    /// Outputs skipped because their file already existed
+    skipped: usize,
+    /// Captures left out because they do not fit the image
+    outside: usize,
+    /// Captures and outputs that failed
+    failed: usize,
+}
+
+impl Summary {
+    /// Count a failure, or return it if the run stops at the first one.
+    ///
+    /// With --keep-going the failure is reported straight away, so that it
+    /// is not lost among the rest of a long batch.
+    fn fail(&mut self, error: anyhow::Error, keep_going: bool) -> Result<()> {
+        if !keep_going {
+            return Err(error);
+        }
+        eprintln!("Error: {error:#}");
+        self.failed += 1;
+        Ok(())
+    }
+
+    /// What a dry run found for one image, counted from its record. Nothing
+    /// is written, so outputs are only skipped or failed.
+    fn validated(record: &report::ImageRecord) -> Summary {
+        let count = |status| {
+            record
+                .captures
+                .iter()
+                .filter(|capture| capture.status == status)
+                .count()
+        };
+        Summary {
+            images: 1,
+            failed_images: usize::from(record.status == report::Status::Failed),
+            written: 0,
+            skipped: count(report::Status::Exists),
+            outside: count(report::Status::Outside),
+            failed: count(report::Status::Failed),
+        }
+    }
+
+    /// Success if nothing failed, partial failure if some inputs succeeded.
+    fn exit_code(&self) -> ExitCode {
+        if self.failed_images == 0 {
+            ExitCode::SUCCESS
+        } else if self.failed_images < self.images {
+            ExitCode::from(EXIT_PARTIAL_FAILURE)
+        } else {
+            ExitCode::FAILURE
+        }
+    }
+}
+
+impl Add for Summary {
+    type Output = Summary;
+
+    fn add(self, other: Summary) -> Summary {
+        Summary {
+            images: self.images + other.images,
+            failed_images: self.failed_images + other.failed_images,
+            written: self.written + other.written,
+            skipped: self.skipped + other.skipped,
+            outside: self.outside + other.outside,
+            failed: self.failed + other.failed,
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Processed {} images: {} succeeded, {} failed; {} outputs written, {} skipped, {} failed",
+            self.images,
+            self.images - self.failed_images,
+            self.failed_images,
+            self.written,
+            self.skipped,
+            self.failed
+        )?;
+        if self.outside > 0 {
+            write!(f, "; {} captures outside the image", self.outside)?;
+        }
+        Ok(())
+    }
+}
+
 /// Coordinate system origin for image coordinates.
 ///
-/// Determines how Y coordinates are interpreted:
+/// Determines how X and Y coordinates are interpreted:
 /// - `TopLeft`: Standard image coordinates where Y increases downward
 /// - `BottomLeft`: Mathematical coordinates where Y increases upward
+/// - `TopRight`/`BottomRight`: As above, with X increasing leftward
+/// - `Center`: Rectangles are centred on the image and offsets shift them
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Origin {
     /// Y=0 is at the top of the image, Y increases downward
     TopLeft,
     /// Y=0 is at the bottom of the image, Y increases upward
     BottomLeft,
+    /// X=0 is at the right of the image, X increases leftward
+    TopRight,
+    /// X=0 and Y=0 are at the bottom-right, X increases leftward and Y upward
+    BottomRight,
+    /// (0, 0) centres the rectangle on the image, X increases right and Y down
+    Center,
+}
+
+impl Origin {
+    /// Returns the anchor equivalent to measuring from this origin.
+    fn anchor(self) -> Anchor {
+        match self {
+            Origin::TopLeft => Anchor::NorthWest,
+            Origin::BottomLeft => Anchor::SouthWest,
+            Origin::TopRight => Anchor::NorthEast,
+            Origin::BottomRight => Anchor::SouthEast,
+            Origin::Center => Anchor::Center,
+        }
+    }
 }
 
 impl std::str::FromStr for Origin {
@@ -44,8 +711,11 @@ impl std::str::FromStr for Origin {
         match s.to_lowercase().as_str() {
             "tl" | "top-left" | "top_left" => Ok(Origin::TopLeft),
             "bl" | "bottom-left" | "bottom_left" => Ok(Origin::BottomLeft),
+            "tr" | "top-right" | "top_right" => Ok(Origin::TopRight),
+            "br" | "bottom-right" | "bottom_right" => Ok(Origin::BottomRight),
+            "c" | "center" | "centre" => Ok(Origin::Center),
             other => Err(format!(
-                "Invalid origin '{other}'. Supported values: tl, bl"
+                "Invalid origin '{other}'. Supported values: tl, bl, tr, br, center"
             )),
         }
     }
@@ -63,128 +733,705 @@ impl std::str::FromStr for Origin {
     long_about = None
 )]
 struct Cli {
-    /// Coordinate origin: 'tl' (top-left) or 'bl' (bottom-left)
+    /// Coordinate origin: 'tl' (top-left), 'bl' (bottom-left), 'tr' (top-right),
+    /// 'br' (bottom-right) or 'center'
     #[arg(
         long,
         value_parser,
         default_value = "tl",
-        help = "Coordinate origin: tl (top-left) or bl (bottom-left)"
+        help = "Coordinate origin: tl (top-left), bl (bottom-left), tr (top-right), br (bottom-right) or center"
     )]
     origin: Origin,
 
+    /// Load capture specs from a TOML, JSON or YAML file
+    ///
+    /// The format is chosen by the file extension. Specs from the file are
+    /// processed before any given with --capture.
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Load capture specs from a TOML, JSON or YAML file"
+    )]
+    spec_file: Option<PathBuf>,
+
+    /// Select a named layout from the spec file
+    #[arg(
+        long,
+        value_name = "NAME",
+        requires = "spec_file",
+        help = "Select a named layout from the spec file"
+    )]
+    layout: Option<String>,
+
     /// A rectangular area to capture. Can be repeated.
     ///
     /// Format: <name>:<x>x<y>:<width>x<height>
     ///
+    /// Each value is in pixels (200), a percentage (50%) or a fraction (0.5)
+    /// of the image dimension. Negative x/y values (-200) are measured from
+    /// the far edge.
+    ///
     /// Example: left:200x300:1200x1850
     #[arg(
         long,
         short = 'c',
         value_name = "SPEC",
         action = ArgAction::Append,
-        required = true,
         help = "Capture spec: <name>:<x>x<y>:<width>x<height>. Can be repeated."
     )]
     capture: Vec<String>,
 
-    /// Input image files (e.g. *.jpg, *.png, *.tif, *.webp, *.gif, *.bmp)
-    #[arg(required = true)]
-    inputs: Vec<PathBuf>,
-
-    /// Enable verbose output with timing information
+    /// Split each image into an evenly divided grid of rows x columns
+    ///
+    /// Cells are named r<row>c<col> and can be combined with --capture.
     #[arg(
         long,
-        short = 'v',
-        help = "Enable verbose output with timing information"
+        value_name = "ROWSxCOLS",
+        value_parser = parse_grid_arg,
+        conflicts_with_all = ["grid_size", "tile"],
+        help = "Split each image into a grid of <rows>x<cols> cells named r<row>c<col>"
     )]
-    verbose: bool,
+    grid: Option<(u32, u32)>,
 
-    /// Validate capture specifications without processing images
+    /// Split each image into a grid of fixed-size cells
+    ///
+    /// Partial cells at the right and bottom edges are dropped.
     #[arg(
         long,
-        help = "Validate capture specifications without processing images"
+        value_name = "WxH",
+        value_parser = parse_grid_arg,
+        help = "Split each image into a grid of <width>x<height> pixel cells named r<row>c<col>"
     )]
-    dry_run: bool,
-}
+    grid_size: Option<(u32, u32)>,
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-
-    // Parse capture specs
-    let specs: Vec<CaptureSpec> = cli
-        .capture
-        .iter()
-        .map(|s| parse_capture_spec(s))
-        .collect::<Result<_>>()?;
+    /// Split each image into fixed-size, optionally overlapping tiles
+    ///
+    /// Tiles are named r<row>c<col>. Use --tile-stride or --tile-overlap to
+    /// control spacing and --tile-edge for tiles at the right and bottom edges.
+    #[arg(
+        long,
+        value_name = "WxH",
+        value_parser = parse_grid_arg,
+        conflicts_with = "grid_size",
+        help = "Split each image into <width>x<height> tiles named r<row>c<col>"
+    )]
+    tile: Option<(u32, u32)>,
 
-    if cli.dry_run {
-        // Validate mode: check specs against image dimensions without processing
-        eprintln!(
-            "Dry run mode: validating {} capture specs against {} images",
-            specs.len(),
-            cli.inputs.len()
-        );
-        for spec in &specs {
-            eprintln!(
-                "  Capture '{}': {}x{} at ({}, {})",
-                spec.name, spec.width, spec.height, spec.x, spec.y
-            );
-        }
-        eprintln!();
+    /// Distance between the starts of neighbouring tiles (default: tile size)
+    #[arg(
+        long,
+        value_name = "N|XxY",
+        value_parser = parse_tile_step,
+        requires = "tile",
+        conflicts_with = "tile_overlap",
+        help = "Distance between neighbouring tiles: <n> or <x>x<y> (default: tile size)"
+    )]
+    tile_stride: Option<(u32, u32)>,
 
-        for input in &cli.inputs {
-            validate_image(input, cli.origin, &specs)?;
-        }
+    /// Overlap between neighbouring tiles (default: 0)
+    #[arg(
+        long,
+        value_name = "N|XxY",
+        value_parser = parse_tile_step,
+        requires = "tile",
+        help = "Overlap between neighbouring tiles: <n> or <x>x<y> (default: 0)"
+    )]
+    tile_overlap: Option<(u32, u32)>,
 
-        eprintln!("Validation successful. All capture specifications are valid.");
-        return Ok(());
-    }
+    /// How to handle tiles at the right and bottom edges: drop, pad or shift
+    #[arg(
+        long,
+        value_parser,
+        default_value = "shift",
+        requires = "tile",
+        help = "Edge tiles: drop (skip partial tiles), pad (fill past the edge) or shift (move back inside)"
+    )]
+    tile_edge: TileEdge,
 
-    // Process files in parallel
-    cli.inputs
-        .par_iter()
-        .map(|input| {
-            process_image(input, cli.origin, &specs, cli.verbose)
-                .with_context(|| format!("Failed to process input image: {}", input.display()))
-        })
-        .collect::<Result<()>>()?;
+    /// How to handle captures extending past the image edge
+    ///
+    /// `error` fails the capture, `clamp` shrinks it to the part inside the
+    /// image, `pad` keeps its size and fills the rest with black or
+    /// transparent pixels, `pad:<color>` fills with a color such as `white`
+    /// or `#ff8800`, and `skip` leaves it out. Spec files can set a policy
+    /// per capture with an `out_of_bounds` field.
+    #[arg(
+        long,
+        value_name = "POLICY",
+        value_parser,
+        default_value = "error",
+        help = "Captures past the image edge: error, clamp, pad, pad:<color> or skip"
+    )]
+    out_of_bounds: OutOfBounds,
 
-    Ok(())
-}
+    /// Build a multi-resolution tile pyramid from each input: dzi or xyz
+    ///
+    /// Replaces capturing regions. dzi writes <stem>.dzi and <stem>_files/,
+    /// xyz writes <stem>_tiles/<z>/<x>/<y>.<ext>.
+    #[arg(
+        long,
+        value_name = "LAYOUT",
+        value_parser,
+        conflicts_with_all = ["capture", "spec_file", "grid", "grid_size", "tile"],
+        help = "Build a tile pyramid from each input instead of capturing regions: dzi or xyz"
+    )]
+    pyramid: Option<pyramid::Layout>,
 
-/// Parse a single capture specification string.
-///
-/// Format: <name>:<x>x<y>:<width>x<height>
-/// Example: left:200x300:1200x1850
-fn parse_capture_spec(s: &str) -> Result<CaptureSpec> {
-    let parts: Vec<&str> = s.split(':').collect();
-    if parts.len() != 3 {
-        return Err(anyhow!(
-            "Invalid capture spec '{s}'. Expected format: <name>:<x>x<y>:<width>x<height>"
-        ));
-    }
+    /// Pyramid tile size in pixels (default: 254 for dzi, 256 for xyz)
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "pyramid",
+        help = "Pyramid tile size in pixels (default: 254 for dzi, 256 for xyz)"
+    )]
+    pyramid_tile_size: Option<u32>,
 
-    let name = parts[0].to_string();
-    let (x, y) = parse_pair(parts[1], 'x', "x", s)?;
-    let (w, h) = parse_pair(parts[2], 'x', "width x height", s)?;
+    /// Overlap between neighbouring pyramid tiles (default: 1 for dzi, 0 for xyz)
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "pyramid",
+        help = "Overlap between pyramid tiles in pixels (default: 1 for dzi; xyz does not support overlap)"
+    )]
+    pyramid_overlap: Option<u32>,
 
-    if w == 0 || h == 0 {
+    /// Pages of multi-page TIFFs or frames of animated GIF/WebP inputs to
+    /// capture, e.g. 1,3-5 (default: all)
+    ///
+    /// Spec files can select pages per capture with a `page` field.
+    #[arg(
+        long,
+        value_name = "LIST",
+        value_parser,
+        help = "Pages or animation frames to capture, e.g. 1,3-5 or 2- (default: all)"
+    )]
+    pages: Option<frames::Pages>,
+
+    /// Directory to write outputs to (default: next to each input)
+    #[arg(
+        long,
+        short = 'o',
+        value_name = "DIR",
+        help = "Directory to write outputs to, created if needed (default: next to each input)"
+    )]
+    output: Option<PathBuf>,
+
+    /// Output path template
+    ///
+    /// Placeholders: {dir} {stem} {name} {index} {page} {x} {y} {w} {h} {ext}.
+    /// Numeric placeholders take a zero-padded width, e.g. {index:03}.
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        default_value = output::DEFAULT_TEMPLATE,
+        help = "Output path template using {dir} {stem} {name} {index} {page} {x} {y} {w} {h} {ext}"
+    )]
+    out_template: String,
+
+    /// Output image format (default: from the output file extension)
+    ///
+    /// Also sets the extension substituted for {ext} in the output template.
+    #[arg(
+        long,
+        short = 'f',
+        value_name = "FORMAT",
+        value_parser,
+        help = "Output format: png, jpeg, webp, tiff, bmp or gif (default: same as the input)"
+    )]
+    format: Option<output::OutputFormat>,
+
+    /// JPEG quality from 1 to 100
+    #[arg(
+        long,
+        value_name = "1-100",
+        default_value_t = 90,
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        help = "JPEG quality from 1 to 100"
+    )]
+    jpeg_quality: u8,
+
+    /// PNG compression effort: none, fast, default or best
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        value_parser,
+        default_value = "fast",
+        help = "PNG compression: none, fast, default or best"
+    )]
+    png_compression: output::PngCompression,
+
+    /// TIFF compression: none, lzw, deflate or packbits
+    #[arg(
+        long,
+        value_name = "SCHEME",
+        value_parser,
+        default_value = "none",
+        help = "TIFF compression: none, lzw, deflate or packbits"
+    )]
+    tiff_compression: output::TiffCompression,
+
+    /// How JPEG inputs written as JPEG are cropped
+    ///
+    /// `reencode` decodes and encodes again. `lossless` copies the DCT
+    /// coefficients of baseline JPEGs without generation loss; captures must
+    /// start on the 8 or 16 pixel block grid. `snap` grows captures up and
+    /// left to the grid instead of rejecting them.
+    #[arg(
+        long,
+        value_name = "MODE",
+        value_parser,
+        default_value = "reencode",
+        help = "JPEG to JPEG cropping: reencode, lossless (block-aligned only) or snap (grow to blocks)"
+    )]
+    jpeg_crop: jpeg::JpegCrop,
+
+    /// Convert images the output format cannot store exactly instead of failing
+    ///
+    /// Without this, writing e.g. a 16-bit or CMYK image as JPEG, or a
+    /// truecolor image as GIF, is an error unless the conversion loses
+    /// nothing.
+    #[arg(
+        long,
+        help = "Allow lossy conversion of bit depth, alpha, colors or CMYK to what the output format stores"
+    )]
+    allow_downconvert: bool,
+
+    /// Input image files (e.g. *.jpg, *.png, *.tif, *.webp, *.gif, *.bmp)
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Replace output files that already exist
+    #[arg(
+        long,
+        conflicts_with_all = ["no_clobber", "suffix_on_conflict"],
+        help = "Replace output files that already exist"
+    )]
+    overwrite: bool,
+
+    /// Skip outputs whose file already exists
+    #[arg(
+        long,
+        conflicts_with = "suffix_on_conflict",
+        help = "Skip outputs whose file already exists"
+    )]
+    no_clobber: bool,
+
+    /// Write to <name>-<n>.<ext> when the output file already exists
+    #[arg(
+        long,
+        help = "Write to <name>-<n>.<ext> when the output file exists or is used by another output"
+    )]
+    suffix_on_conflict: bool,
+
+    /// Use stored pixel data as is, ignoring EXIF and TIFF orientation tags
+    #[arg(
+        long,
+        help = "Ignore EXIF/TIFF orientation; capture coordinates refer to the stored pixels"
+    )]
+    no_auto_orient: bool,
+
+    /// Ignore per-image sidecar files (e.g. scan001.cutout.json)
+    #[arg(
+        long,
+        help = "Ignore per-image sidecar files (e.g. scan001.cutout.json)"
+    )]
+    no_sidecars: bool,
+
+    /// Report failed inputs and captures, finish the rest, then summarise
+    ///
+    /// Exits with status 3 if some inputs failed and 1 if all of them did.
+    #[arg(
+        long,
+        help = "Carry on past failed inputs and captures, then print a summary"
+    )]
+    keep_going: bool,
+
+    /// Number of worker threads (default: one per CPU core)
+    ///
+    /// Threads share out both the input images and the captures of each image.
+    #[arg(
+        long,
+        short = 'j',
+        value_name = "N",
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Number of worker threads processing images and captures (default: one per CPU core)"
+    )]
+    jobs: Option<u16>,
+
+    /// Limit the decoded image data held at once, e.g. 4G
+    ///
+    /// Each image's decoded size is estimated from its header before it is
+    /// opened, and images wait until enough of the budget is free. An image
+    /// larger than the whole budget is processed alone.
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser,
+        help = "Limit decoded image data held at once, e.g. 512M or 4G (default: no limit)"
+    )]
+    max_memory: Option<memory::Bytes>,
+
+    /// Enable verbose output with timing information
+    #[arg(
+        long,
+        short = 'v',
+        help = "Enable verbose output with timing information"
+    )]
+    verbose: bool,
+
+    /// Print a JSON record for each input, and a final summary, to stdout
+    ///
+    /// Records give the input's dimensions, each capture's resolved
+    /// rectangle, output path and status, and timings. Works with --dry-run.
+    #[arg(
+        long,
+        help = "Print a JSON line per input with captures, outputs, status and timings"
+    )]
+    json: bool,
+
+    /// Validate capture specifications without processing images
+    #[arg(
+        long,
+        help = "Validate capture specifications without processing images"
+    )]
+    dry_run: bool,
+}
+
+fn main() -> Result<ExitCode> {
+    let cli = Cli::parse();
+
+    // Parse capture specs, starting with any loaded from a spec file
+    let mut specs: Vec<CaptureSpec> = match &cli.spec_file {
+        Some(path) => spec_file::load(path, cli.layout.as_deref())?,
+        None => Vec::new(),
+    };
+    for s in &cli.capture {
+        specs.push(parse_capture_spec(s)?);
+    }
+    let grid = match (cli.grid, cli.grid_size, cli.tile) {
+        (Some((rows, cols)), _, _) => Some(Grid::Count { rows, cols }),
+        (None, Some((width, height)), _) => Some(Grid::CellSize { width, height }),
+        (None, None, Some((width, height))) => {
+            let (stride_x, stride_y) = match (cli.tile_stride, cli.tile_overlap) {
+                (Some(stride), _) => stride,
+                (None, Some((overlap_x, overlap_y))) => {
+                    if overlap_x >= width || overlap_y >= height {
+                        return Err(anyhow!(
+                            "Tile overlap {overlap_x}x{overlap_y} must be smaller than tile size {width}x{height}"
+                        ));
+                    }
+                    (width - overlap_x, height - overlap_y)
+                }
+                (None, None) => (width, height),
+            };
+            if stride_x == 0 || stride_y == 0 {
+                return Err(anyhow!("Tile stride must be positive"));
+            }
+            Some(Grid::Tiles {
+                width,
+                height,
+                stride_x,
+                stride_y,
+                edge: cli.tile_edge,
+            })
+        }
+        (None, None, None) => None,
+    };
+    let pyramid = cli
+        .pyramid
+        .map(|layout| pyramid::settings(layout, cli.pyramid_tile_size, cli.pyramid_overlap))
+        .transpose()?;
+    if specs.is_empty() && grid.is_none() && pyramid.is_none() && cli.no_sidecars {
+        return Err(anyhow!(
+            "No capture specs given. Use --capture, --spec-file, --grid or --tile"
+        ));
+    }
+
+    let options = Options {
+        origin: cli.origin,
+        sidecars: !cli.no_sidecars,
+        auto_orient: !cli.no_auto_orient,
+        grid,
+        out_of_bounds: cli.out_of_bounds,
+        pyramid,
+        pages: cli.pages,
+        output: output::OutputTemplate::new(&cli.out_template, cli.output.clone(), cli.format)?,
+        encoding: output::Encoding {
+            format: cli.format,
+            jpeg_quality: cli.jpeg_quality,
+            png_compression: cli.png_compression,
+            tiff_compression: cli.tiff_compression,
+            allow_downconvert: cli.allow_downconvert,
+        },
+        jpeg_crop: cli.jpeg_crop,
+        outputs: output::OutputRegistry::new(if cli.overwrite {
+            output::Conflict::Overwrite
+        } else if cli.no_clobber {
+            output::Conflict::Skip
+        } else if cli.suffix_on_conflict {
+            output::Conflict::Suffix
+        } else {
+            output::Conflict::Error
+        }),
+        keep_going: cli.keep_going,
+        json: cli.json,
+        verbose: cli.verbose,
+    };
+
+    if cli.dry_run && cli.json {
+        // Report each image's plan as a record instead of text, checking
+        // every input before failing
+        let mut summary = Summary::default();
+        for input in &cli.inputs {
+            let mut record = report::ImageRecord::new(input);
+            let validated = InputSpecs::resolve(input, &specs, &options)
+                .and_then(|specs| validate_image(input, &specs, &options, &mut record));
+            if let Err(e) = validated {
+                record.fail(&e);
+            }
+            report::emit(&record)?;
+            summary = summary + Summary::validated(&record);
+        }
+        report::emit(&summary)?;
+        return Ok(if summary.failed_images > 0 {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        });
+    }
+
+    if cli.dry_run {
+        // Validate mode: check specs against image dimensions without processing
+        eprintln!(
+            "Dry run mode: validating {} capture specs against {} images",
+            specs.len(),
+            cli.inputs.len()
+        );
+        for spec in &specs {
+            let anchor = spec.anchor.unwrap_or_else(|| cli.origin.anchor());
+            eprint!(
+                "  Capture '{}': {}x{} at ({}, {}) from {}",
+                spec.name, spec.width, spec.height, spec.x, spec.y, anchor
+            );
+            match &spec.pages {
+                Some(pages) => eprintln!(", pages {pages}"),
+                None => eprintln!(),
+            }
+        }
+        if let Some(pages) = &options.pages {
+            eprintln!("  Pages: {pages}");
+        }
+        if let Some(grid) = options.grid {
+            eprintln!("  Grid: {grid}");
+        }
+        if options.out_of_bounds != OutOfBounds::Error {
+            eprintln!("  Out of bounds: {}", options.out_of_bounds);
+        }
+        if let Some(pyramid) = options.pyramid {
+            eprintln!(
+                "  Pyramid: {} with {}px tiles and {}px overlap",
+                pyramid.layout, pyramid.tile_size, pyramid.overlap
+            );
+        }
+        eprintln!();
+
+        // Check every input before failing, so that one run lists all problems
+        let mut failed = 0;
+        for input in &cli.inputs {
+            let mut record = report::ImageRecord::new(input);
+            let validated = InputSpecs::resolve(input, &specs, &options)
+                .and_then(|specs| validate_image(input, &specs, &options, &mut record));
+            if let Err(e) = validated {
+                eprintln!("Error: {e:#}");
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(anyhow!(
+                "Validation failed for {failed} of {} images",
+                cli.inputs.len()
+            ));
+        }
+
+        eprintln!("Validation successful. All capture specifications are valid.");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let budget = cli.max_memory.map(memory::MemoryBudget::new);
+    let mut pool = rayon::ThreadPoolBuilder::new();
+    if let Some(jobs) = cli.jobs {
+        pool = pool.num_threads(jobs.into());
+    }
+    let pool = pool.build().context("Failed to start worker threads")?;
+    let summary = process_images(&cli.inputs, &specs, &options, &pool, budget.as_ref())?;
+
+    if options.keep_going {
+        eprintln!("{summary}");
+    }
+    if options.json {
+        report::emit(&summary)?;
+    }
+    Ok(summary.exit_code())
+}
+
+/// Process the inputs in parallel on `pool`, holding back images that would
+/// exceed the memory budget until others finish.
+///
+/// The calling thread reserves each image's share of the budget before
+/// handing it to the pool. Workers never wait for the budget themselves:
+/// while one waits, it may have taken up another image's job from inside
+/// its own parallel capture work, and the reservation it waits for might
+/// only be released once that work finishes.
+fn process_images(
+    inputs: &[PathBuf],
+    specs: &[CaptureSpec],
+    options: &Options,
+    pool: &rayon::ThreadPool,
+    budget: Option<&memory::MemoryBudget>,
+) -> Result<Summary> {
+    let total = Mutex::new(Ok(Summary::default()));
+    // Set once a failure ends the run, so that no further images start
+    let stopped = AtomicBool::new(false);
+    pool.in_place_scope(|scope| {
+        for input in inputs {
+            if stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            // With a budget, the specs are resolved here to estimate the pages
+            // that will be decoded, and otherwise by the worker
+            let reserved = budget.map(|budget| {
+                let resolved = InputSpecs::resolve(input, specs, options);
+                // An input whose sidecar fails reserves nothing, and reports
+                // the error once processed
+                let estimate = resolved.as_ref().map_or(0, |resolved| {
+                    memory::estimate(input, resolved.pages.as_ref())
+                });
+                if options.verbose && estimate > budget.limit().0 {
+                    eprintln!(
+                        "Processing {} alone: about {} MiB decoded exceeds --max-memory {}",
+                        input.display(),
+                        estimate.div_ceil(1 << 20),
+                        budget.limit()
+                    );
+                }
+                (resolved, budget.reserve(estimate))
+            });
+            let (total, stopped) = (&total, &stopped);
+            scope.spawn(move |_| {
+                if stopped.load(Ordering::Relaxed) {
+                    return;
+                }
+                let (resolved, _reservation) = match reserved {
+                    Some((resolved, reservation)) => (resolved, Some(reservation)),
+                    None => (InputSpecs::resolve(input, specs, options), None),
+                };
+                let processed = process_input(input, resolved, options);
+                let mut total = total.lock().unwrap_or_else(|e| e.into_inner());
+                match (processed, &mut *total) {
+                    (Ok(summary), Ok(total)) => *total = *total + summary,
+                    (Err(e), Ok(_)) => {
+                        stopped.store(true, Ordering::Relaxed);
+                        *total = Err(e);
+                    }
+                    (_, Err(_)) => {}
+                }
+            });
+        }
+    });
+    total.into_inner().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Process one input of a batch, given its resolved specs or the error
+/// resolving them, printing its JSON record if asked to.
+///
+/// With --keep-going a failed input is reported and counted instead of
+/// returned.
+fn process_input(input: &Path, specs: Result<InputSpecs>, options: &Options) -> Result<Summary> {
+    let mut record = report::ImageRecord::new(input);
+    let processed = specs
+        .and_then(|specs| process_image(input, &specs, options, &mut record))
+        .with_context(|| format!("Failed to process input image: {}", input.display()));
+    if options.json {
+        if let Err(e) = &processed {
+            record.fail(e);
+        }
+        report::emit(&record)?;
+    }
+    match processed {
+        Err(e) if options.keep_going => {
+            eprintln!("Error: {e:#}");
+            Ok(Summary {
+                images: 1,
+                failed_images: 1,
+                ..Summary::default()
+            })
+        }
+        processed => processed,
+    }
+}
+
+/// Parse a single capture specification string.
+///
+/// Format: <name>[@<anchor>]:<x>x<y>:<width>x<height>
+/// Example: left:200x300:1200x1850
+/// Example: left:0%x0%:50%x100%
+/// Example: footer:0x-200:100%x200
+/// Example: badge@se:20x20:64x64
+fn parse_capture_spec(s: &str) -> Result<CaptureSpec> {
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() != 3 {
+        return Err(anyhow!(
+            "Invalid capture spec '{s}'. Expected format: <name>:<x>x<y>:<width>x<height>"
+        ));
+    }
+
+    let (name, anchor) = match parts[0].split_once('@') {
+        Some((name, anchor)) => {
+            let anchor: Anchor = anchor
+                .parse()
+                .map_err(|e: String| anyhow!(e))
+                .with_context(|| format!("Failed to parse anchor in capture spec '{s}'"))?;
+            (name.to_string(), Some(anchor))
+        }
+        None => (parts[0].to_string(), None),
+    };
+
+    build_capture_spec(name, anchor, parts[1], parts[2], s)
+}
+
+/// Build a capture spec from its `<x>x<y>` position and `<width>x<height>` size.
+///
+/// Shared by command-line specs and spec files so both accept the same units.
+fn build_capture_spec(
+    name: String,
+    anchor: Option<Anchor>,
+    position: &str,
+    size: &str,
+    original_spec: &str,
+) -> Result<CaptureSpec> {
+    let (x, y) = parse_pair(position, 'x', "x", original_spec)?;
+    let (w, h) = parse_pair(size, 'x', "width x height", original_spec)?;
+
+    if !w.is_positive() || !h.is_positive() {
         return Err(anyhow!(
-            "Width and height must be positive in capture spec '{s}'"
+            "Width and height must be positive in capture spec '{original_spec}'"
         ));
     }
 
     Ok(CaptureSpec {
         name,
+        anchor,
         x,
         y,
         width: w,
         height: h,
+        out_of_bounds: None,
+        pages: None,
     })
 }
 
-/// Parse a pair of u32 values separated by a given separator character.
-fn parse_pair(raw: &str, sep: char, label: &str, original_spec: &str) -> Result<(u32, u32)> {
+/// Parse a pair of lengths separated by a given separator character.
+fn parse_pair(raw: &str, sep: char, label: &str, original_spec: &str) -> Result<(Length, Length)> {
     let mut parts = raw.split(sep);
     let first = parts
         .next()
@@ -199,202 +1446,1041 @@ fn parse_pair(raw: &str, sep: char, label: &str, original_spec: &str) -> Result<
         ));
     }
 
-    let a: u32 = first.parse().with_context(|| {
-        format!("Failed to parse first {label} value '{first}' in capture spec '{original_spec}'")
-    })?;
-    let b: u32 = second.parse().with_context(|| {
-        format!("Failed to parse second {label} value '{second}' in capture spec '{original_spec}'")
-    })?;
+    let a: Length = first
+        .parse()
+        .map_err(|e: String| anyhow!(e))
+        .with_context(|| {
+            format!(
+                "Failed to parse first {label} value '{first}' in capture spec '{original_spec}'"
+            )
+        })?;
+    let b: Length = second
+        .parse()
+        .map_err(|e: String| anyhow!(e))
+        .with_context(|| {
+            format!(
+                "Failed to parse second {label} value '{second}' in capture spec '{original_spec}'"
+            )
+        })?;
 
     Ok((a, b))
 }
 
 /// Convert capture spec coordinates to absolute image coordinates based on origin.
-/// Returns (`abs_x`, `abs_y`) in top-left coordinate system.
+/// Relative lengths are resolved against the image dimensions first, and the
+/// spec's anchor, if any, takes precedence over the origin.
+/// Returns the rectangle in top-left coordinate system, or `None` if the
+/// spec's out-of-bounds policy skips it.
 fn convert_coordinates(
     spec: &CaptureSpec,
     origin: Origin,
     img_width: u32,
     img_height: u32,
-) -> Result<(u32, u32)> {
-    let abs_x = spec.x;
-    let abs_y = match origin {
-        Origin::TopLeft => spec.y,
-        Origin::BottomLeft => {
-            if spec.y > img_height {
+) -> Result<Option<Rect>> {
+    let policy = spec.out_of_bounds.unwrap_or(OutOfBounds::Error);
+    let (width, height) = resolve_size(spec, img_width, img_height)?;
+    // Placing fails only for offsets outside the image, which skip covers
+    let rect = place_rect(spec, origin, width, height, img_width, img_height);
+    match (policy, rect) {
+        (OutOfBounds::Skip, Ok(rect)) if within(rect, img_width, img_height) => Ok(Some(rect)),
+        (OutOfBounds::Skip, _) => Ok(None),
+        (OutOfBounds::Pad(_), rect) => rect.map(Some),
+        (OutOfBounds::Clamp, rect) => {
+            let rect = rect?;
+            let left = rect.x.clamp(0, i64::from(img_width));
+            let top = rect.y.clamp(0, i64::from(img_height));
+            let right = (rect.x + i64::from(rect.width)).min(i64::from(img_width));
+            let bottom = (rect.y + i64::from(rect.height)).min(i64::from(img_height));
+            if right <= left || bottom <= top {
                 return Err(anyhow!(
-                    "Capture '{}' y={} is outside image height={}",
+                    "Capture '{}' rectangle ({}, {}, {}x{}) lies entirely outside image {}x{}",
                     spec.name,
-                    spec.y,
+                    rect.x,
+                    rect.y,
+                    rect.width,
+                    rect.height,
+                    img_width,
                     img_height,
                 ));
             }
-            img_height
-                .checked_sub(spec.y)
-                .and_then(|v| v.checked_sub(spec.height))
-                .ok_or_else(|| {
-                    anyhow!(
-                        "Capture '{}' (y={}, height={}) is outside image height={}",
-                        spec.name,
-                        spec.y,
-                        spec.height,
-                        img_height,
-                    )
-                })?
+            Ok(Some(Rect {
+                x: left,
+                y: top,
+                width: (right - left) as u32,
+                height: (bottom - top) as u32,
+            }))
         }
-    };
+        (OutOfBounds::Error, rect) => {
+            let rect = rect?;
+            check_bounds(spec, rect, img_width, img_height)?;
+            Ok(Some(rect))
+        }
+    }
+}
 
-    if abs_x >= img_width || abs_y >= img_height {
+/// Whether a rectangle lies entirely within the image.
+fn within(rect: Rect, img_width: u32, img_height: u32) -> bool {
+    rect.x >= 0
+        && rect.y >= 0
+        && rect.x + i64::from(rect.width) <= i64::from(img_width)
+        && rect.y + i64::from(rect.height) <= i64::from(img_height)
+}
+
+/// Resolve the width and height of a capture spec, which must not be empty.
+fn resolve_size(spec: &CaptureSpec, img_width: u32, img_height: u32) -> Result<(u32, u32)> {
+    let width = spec.width.resolve_extent(img_width);
+    let height = spec.height.resolve_extent(img_height);
+
+    if width == 0 || height == 0 {
+        return Err(anyhow!(
+            "Capture '{}' ({}x{}) resolves to an empty rectangle in image {}x{}",
+            spec.name,
+            spec.width,
+            spec.height,
+            img_width,
+            img_height,
+        ));
+    }
+    Ok((width, height))
+}
+
+/// Place a capture of the resolved size, which may extend past the image.
+///
+/// Fails if an offset from the image edge lies outside the image.
+fn place_rect(
+    spec: &CaptureSpec,
+    origin: Origin,
+    width: u32,
+    height: u32,
+    img_width: u32,
+    img_height: u32,
+) -> Result<Rect> {
+    let (x_align, y_align) = spec.anchor.unwrap_or_else(|| origin.anchor()).alignment();
+    let abs_x = place_on_axis(spec, "x", "width", spec.x, width, img_width, x_align)?;
+    let abs_y = place_on_axis(spec, "y", "height", spec.y, height, img_height, y_align)?;
+    Ok(Rect {
+        x: abs_x,
+        y: abs_y,
+        width,
+        height,
+    })
+}
+
+/// Ensure a resolved rectangle lies entirely within the image.
+fn check_bounds(spec: &CaptureSpec, rect: Rect, img_width: u32, img_height: u32) -> Result<()> {
+    for (axis, dim, offset, start, size, total) in [
+        ("x", "width", spec.x, rect.x, rect.width, img_width),
+        ("y", "height", spec.y, rect.y, rect.height, img_height),
+    ] {
+        if start < 0 {
+            return Err(anyhow!(
+                "Capture '{}' ({axis}={offset}, {dim}={size}) is outside image {dim}={total}",
+                spec.name,
+            ));
+        }
+    }
+
+    if rect.x >= i64::from(img_width) || rect.y >= i64::from(img_height) {
         return Err(anyhow!(
             "Capture '{}' origin ({}, {}) is outside image bounds {}x{}",
             spec.name,
-            abs_x,
-            abs_y,
+            rect.x,
+            rect.y,
             img_width,
             img_height,
         ));
     }
 
-    let max_w = img_width - abs_x;
-    let max_h = img_height - abs_y;
+    let max_w = i64::from(img_width) - rect.x;
+    let max_h = i64::from(img_height) - rect.y;
 
-    if spec.width > max_w || spec.height > max_h {
+    if i64::from(rect.width) > max_w || i64::from(rect.height) > max_h {
         return Err(anyhow!(
             "Capture '{}' rectangle ({}, {}, {}x{}) exceeds image bounds {}x{}",
             spec.name,
-            abs_x,
-            abs_y,
-            spec.width,
-            spec.height,
+            rect.x,
+            rect.y,
+            rect.width,
+            rect.height,
             img_width,
             img_height,
         ));
     }
 
-    Ok((abs_x, abs_y))
+    Ok(())
+}
+
+/// Resolve the leading (left or top) edge of a capture along one axis.
+///
+/// `Start` and `End` offsets are distances inward from the matching image edge,
+/// with negative offsets measured from the opposite edge. `Middle` offsets are
+/// signed shifts of a centred rectangle. The result is negative if the
+/// rectangle starts before the image.
+fn place_on_axis(
+    spec: &CaptureSpec,
+    axis: &str,
+    dim: &str,
+    offset: Length,
+    size: u32,
+    total: u32,
+    align: Align,
+) -> Result<i64> {
+    let start = match align {
+        Align::Start | Align::End => {
+            let distance = offset
+                .resolve_offset(total)
+                .filter(|&d| d <= total)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Capture '{}' {axis}={offset} is outside image {dim}={total}",
+                        spec.name,
+                    )
+                })?;
+            if align == Align::Start {
+                i64::from(distance)
+            } else {
+                i64::from(total) - i64::from(distance) - i64::from(size)
+            }
+        }
+        Align::Middle => {
+            (i64::from(total) - i64::from(size)).div_euclid(2) + offset.resolve_signed(total)
+        }
+    };
+    Ok(start)
+}
+
+/// The capture specs of one input, with its sidecar file applied, and the
+/// pages they read.
+///
+/// Resolved once per input, since the sidecar is looked for and parsed both
+/// to estimate the input's memory and to process it.
+struct InputSpecs<'a> {
+    specs: Cow<'a, [CaptureSpec]>,
+    /// The pages that the captures read, or `None` for all of them
+    pages: Option<frames::Pages>,
+}
+
+impl<'a> InputSpecs<'a> {
+    /// Apply an input's sidecar file to the global specs and work out the
+    /// pages they read. Pyramids only read the first page, and no sidecar.
+    fn resolve(path: &Path, global: &'a [CaptureSpec], options: &Options) -> Result<Self> {
+        if options.pyramid.is_some() {
+            return Ok(InputSpecs {
+                specs: Cow::Borrowed(global),
+                pages: Some(frames::Pages::first()),
+            });
+        }
+        let specs = if options.sidecars {
+            spec_file::apply_sidecar(path, global)?
+        } else {
+            Cow::Borrowed(global)
+        };
+        // Grid cells use the global selection
+        let grid = options.grid.map(|_| options.pages.as_ref());
+        let selections: Option<Vec<&frames::Pages>> = specs
+            .iter()
+            .map(|spec| spec.pages.as_ref().or(options.pages.as_ref()))
+            .chain(grid)
+            .collect();
+        let pages = selections.map(frames::Pages::union);
+        Ok(InputSpecs { specs, pages })
+    }
+
+    /// The capture specs for the image: the resolved specs followed by any
+    /// grid cells.
+    fn for_image(
+        &self,
+        path: &Path,
+        options: &Options,
+        img_width: u32,
+        img_height: u32,
+    ) -> Result<Vec<CaptureSpec>> {
+        let mut specs = self.specs.to_vec();
+        if let Some(grid) = options.grid {
+            specs.extend(grid.specs(img_width, img_height)?);
+        }
+        for spec in &mut specs {
+            spec.out_of_bounds.get_or_insert(options.out_of_bounds);
+        }
+
+        if specs.is_empty() {
+            return Err(anyhow!(
+                "No capture specs for '{}'. Use --capture, --spec-file, --grid, --tile or a sidecar file",
+                path.display()
+            ));
+        }
+        Ok(specs)
+    }
+}
+
+/// Validate capture specifications against an image without processing.
+/// Opens the image, checks dimensions, and validates all capture specs.
+fn validate_image(
+    path: &Path,
+    specs: &InputSpecs,
+    options: &Options,
+    record: &mut report::ImageRecord,
+) -> Result<()> {
+    let (input, _) = region::Input::open(path, options.auto_orient, specs.pages.as_ref())?;
+    let pages = input.pages();
+    let jpeg = lossless_header(path, options)?;
+    let (img_width, img_height) = (pages[0].width, pages[0].height);
+    record.width = Some(img_width);
+    record.height = Some(img_height);
+    record.pages = Some(pages.len());
+
+    // With --json, the record is printed instead of this text
+    if !options.json {
+        if pages.len() > 1 {
+            eprintln!(
+                "Validating {} ({}x{}, {} pages)",
+                path.display(),
+                img_width,
+                img_height,
+                pages.len()
+            );
+        } else {
+            eprintln!(
+                "Validating {} ({}x{})",
+                path.display(),
+                img_width,
+                img_height
+            );
+        }
+    }
+
+    if let Some(pyramid) = options.pyramid {
+        let levels = pyramid.levels(img_width, img_height).len();
+        let out_dir = options.output.output_dir(path);
+        let tile_dir = pyramid.tile_dir(path, &out_dir)?;
+        let plan = pyramid.plan(
+            path,
+            &out_dir,
+            &options.encoding,
+            &options.outputs,
+            img_width,
+            img_height,
+        )?;
+        let tiles = pyramid.tile_count(img_width, img_height);
+        if !options.json {
+            match plan.skipped() {
+                0 => eprintln!("  {levels} levels, {tiles} tiles -> {}", tile_dir.display()),
+                skipped => eprintln!(
+                    "  {levels} levels, {tiles} tiles -> {} ({skipped} exist, skipped)",
+                    tile_dir.display()
+                ),
+            }
+        }
+        record.pyramid = Some(report::PyramidRecord {
+            layout: pyramid.layout.to_string(),
+            levels,
+            tiles: plan.tiles(),
+            output: tile_dir.display().to_string(),
+        });
+        return Ok(());
+    }
+
+    let specs = specs.for_image(path, options, img_width, img_height)?;
+
+    // Output conflicts are listed with their captures, and fail the image
+    // once all of them are checked
+    let mut conflicts = 0;
+    for (index, spec) in specs.iter().enumerate() {
+        let outputs = plan_outputs(path, spec, index, &pages, jpeg.as_ref(), options)
+            .with_context(|| {
+                format!(
+                    "Invalid capture spec '{}' for image '{}'",
+                    spec.name,
+                    path.display()
+                )
+            })?;
+        if outputs.is_empty() {
+            if !options.json {
+                eprintln!("  '{}' -> skipped, outside the image", spec.name);
+            }
+            record.captures.push(capture_record(
+                spec,
+                index,
+                None,
+                &pages,
+                report::Status::Outside,
+            ));
+        }
+
+        for output in outputs {
+            let page_note = match (output.frames.len(), output.lossless) {
+                (1, false) => String::new(),
+                (1, true) => " (lossless)".to_string(),
+                (n, _) => format!(" ({n} pages)"),
+            };
+            let (output, status) = match options
+                .outputs
+                .claim(&output.path, &output_owner(path, spec))
+            {
+                Err(e) => {
+                    if !options.json {
+                        eprintln!(
+                            "  '{}' -> {}{page_note} (conflict: {e})",
+                            spec.name,
+                            output.path.display()
+                        );
+                    }
+                    let mut capture =
+                        capture_record(spec, index, Some(&output), &pages, report::Status::Failed);
+                    capture.error = Some(format!("{e:#}"));
+                    record.captures.push(capture);
+                    conflicts += 1;
+                    continue;
+                }
+                Ok(Some(out_path)) => {
+                    if !options.json {
+                        eprintln!("  '{}' -> {}{page_note}", spec.name, out_path.display());
+                    }
+                    let output = Output {
+                        path: out_path,
+                        ..output
+                    };
+                    (output, report::Status::Planned)
+                }
+                Ok(None) => {
+                    if !options.json {
+                        eprintln!(
+                            "  '{}' -> {}{page_note} (exists, skipped)",
+                            spec.name,
+                            output.path.display()
+                        );
+                    }
+                    (output, report::Status::Exists)
+                }
+            };
+            record
+                .captures
+                .push(capture_record(spec, index, Some(&output), &pages, status));
+        }
+    }
+
+    if conflicts > 0 {
+        return Err(anyhow!(
+            "{conflicts} output(s) of image '{}' cannot be written",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Process a single image file:
+/// - Open the image, apply its orientation and read its metadata
+/// - In pyramid mode, write the tile pyramid instead of captures
+/// - Apply the input's sidecar file and grid, if any, to the capture specs
+/// - For each capture spec and selected page, compute absolute coordinates
+///   based on origin
+/// - Claim each output path, applying the overwrite policy
+/// - Decode the parts of the image the outputs cover, if it was not decoded
+///   whole
+/// - Crop and save the outputs in parallel, to the paths given by the output
+///   template
+fn process_image(
+    path: &Path,
+    specs: &InputSpecs,
+    options: &Options,
+    record: &mut report::ImageRecord,
+) -> Result<Summary> {
+    let start = Instant::now();
+    let mut summary = Summary {
+        images: 1,
+        ..Summary::default()
+    };
+
+    if let Some(pyramid) = options.pyramid {
+        // Pyramids use the first page
+        let (decoded, metadata) = metadata::open(path, options.auto_orient, specs.pages.as_ref())?;
+        let decode_ms = start.elapsed().as_millis();
+        let image = &decoded.frames[0].image;
+        let (width, height) = image.dimensions();
+        record.width = Some(width);
+        record.height = Some(height);
+        record.pages = Some(decoded.pages.len());
+        let tiles_start = Instant::now();
+        let out_dir = options.output.output_dir(path);
+        let plan = pyramid.plan(
+            path,
+            &out_dir,
+            &options.encoding,
+            &options.outputs,
+            width,
+            height,
+        )?;
+        let tiles = pyramid.export(&plan, &options.encoding, image, metadata.cmyk)?;
+        record.pyramid = Some(report::PyramidRecord {
+            layout: pyramid.layout.to_string(),
+            levels: pyramid.levels(width, height).len(),
+            tiles,
+            output: pyramid.tile_dir(path, &out_dir)?.display().to_string(),
+        });
+        record.timings = Some(report::Timings {
+            decode_ms,
+            save_ms: tiles_start.elapsed().as_millis(),
+        });
+        if options.verbose {
+            eprintln!(
+                "Processed {} (decode: {} ms, pyramid: {} tiles in {} ms)",
+                path.display(),
+                decode_ms,
+                tiles,
+                tiles_start.elapsed().as_millis()
+            );
+        }
+        summary.written = tiles;
+        summary.skipped = plan.skipped();
+        return Ok(summary);
+    }
+
+    let (input, metadata) = region::Input::open(path, options.auto_orient, specs.pages.as_ref())?;
+    let pages = input.pages();
+    record.width = Some(pages[0].width);
+    record.height = Some(pages[0].height);
+    record.pages = Some(pages.len());
+    let jpeg = lossless_header(path, options)?;
+    let decode_ms = start.elapsed().as_millis();
+
+    // Grids use the first page
+    let specs = specs.for_image(path, options, pages[0].width, pages[0].height)?;
+
+    let crop_start = Instant::now();
+
+    // Resolve and claim every output before writing any, so that conflicts
+    // are reported before the image is partially written. `slots` holds the
+    // position of each output's capture record.
+    let mut outputs = Vec::with_capacity(specs.len());
+    let mut slots = Vec::with_capacity(specs.len());
+    for (index, spec) in specs.iter().enumerate() {
+        let planned = match plan_outputs(path, spec, index, &pages, jpeg.as_ref(), options)
+            .with_context(|| format!("Processing image '{}'", path.display()))
+        {
+            Ok(planned) => planned,
+            Err(e) => {
+                let error = format!("{e:#}");
+                summary.fail(e, options.keep_going)?;
+                let mut capture = capture_record(spec, index, None, &pages, report::Status::Failed);
+                capture.error = Some(error);
+                record.captures.push(capture);
+                continue;
+            }
+        };
+        if planned.is_empty() {
+            if options.verbose {
+                eprintln!("Skipping '{}': outside the image", spec.name);
+            }
+            summary.outside += 1;
+            record.captures.push(capture_record(
+                spec,
+                index,
+                None,
+                &pages,
+                report::Status::Outside,
+            ));
+        }
+
+        for output in planned {
+            match options
+                .outputs
+                .claim(&output.path, &output_owner(path, spec))
+            {
+                Ok(Some(out_path)) => {
+                    let output = Output {
+                        path: out_path,
+                        ..output
+                    };
+                    slots.push(record.captures.len());
+                    record.captures.push(capture_record(
+                        spec,
+                        index,
+                        Some(&output),
+                        &pages,
+                        report::Status::Planned,
+                    ));
+                    outputs.push(output);
+                }
+                Ok(None) => {
+                    if options.verbose {
+                        eprintln!("Skipping '{}': already exists", output.path.display());
+                    }
+                    summary.skipped += 1;
+                    record.captures.push(capture_record(
+                        spec,
+                        index,
+                        Some(&output),
+                        &pages,
+                        report::Status::Exists,
+                    ));
+                }
+                Err(e) => {
+                    let error = format!("{e:#}");
+                    summary.fail(e, options.keep_going)?;
+                    let mut capture =
+                        capture_record(spec, index, Some(&output), &pages, report::Status::Failed);
+                    capture.error = Some(error);
+                    record.captures.push(capture);
+                }
+            }
+        }
+    }
+
+    // Only the MCU rows of the lossless crops are read, not the whole JPEG
+    let lossless: Vec<Rect> = outputs
+        .iter()
+        .filter(|output| output.lossless)
+        .map(|output| output.frames[0].1)
+        .collect();
+    let jpeg = match jpeg {
+        Some(header) if !lossless.is_empty() => Some(header.source(path, &lossless)?),
+        _ => None,
+    };
+    let frames = match input {
+        region::Input::Decoded(decoded) => decoded_frames(decoded, &mut outputs)?,
+        region::Input::Partial(partial) => decode_partial(&partial, &mut outputs)
+            .with_context(|| format!("Processing image '{}'", path.display()))?,
+    };
+    // Outputs are independent, so idle threads steal them from images with
+    // many captures instead of encoding one after another
+    let failures = outputs
+        .par_iter()
+        .map(|output| {
+            // Crop and save using the most native representation we can.
+            match crop_and_save(&frames, output, jpeg.as_ref(), &options.encoding, &metadata) {
+                Ok(()) => Ok(None),
+                Err(e) if options.keep_going => Ok(Some(e)),
+                Err(e) => Err(e),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for (&slot, failure) in slots.iter().zip(failures) {
+        let capture = &mut record.captures[slot];
+        match failure {
+            None => {
+                capture.status = report::Status::Written;
+                summary.written += 1;
+            }
+            Some(e) => {
+                capture.status = report::Status::Failed;
+                capture.error = Some(format!("{e:#}"));
+                summary.fail(e, options.keep_going)?;
+            }
+        }
+    }
+
+    let crop_ms = crop_start.elapsed().as_millis();
+    record.timings = Some(report::Timings {
+        decode_ms,
+        save_ms: crop_ms,
+    });
+    if options.verbose {
+        eprintln!(
+            "Processed {} (decode: {} ms, crop+save: {} ms)",
+            path.display(),
+            decode_ms,
+            crop_ms
+        );
+    }
+
+    if summary.failed > 0 {
+        summary.failed_images = 1;
+        record.status = report::Status::Failed;
+    }
+    Ok(summary)
 }
 
-/// Validate capture specifications against an image without processing.
-/// Opens the image, checks dimensions, and validates all capture specs.
-fn validate_image(path: &Path, origin: Origin, specs: &[CaptureSpec]) -> Result<()> {
-    let img =
-        image::open(path).with_context(|| format!("Unable to open image '{}'", path.display()))?;
-    let (img_width, img_height) = img.dimensions();
+/// Describe an output of the capture at `index`, or the capture itself if it
+/// has no output.
+fn capture_record(
+    spec: &CaptureSpec,
+    index: usize,
+    output: Option<&Output>,
+    pages: &[frames::PageSize],
+    status: report::Status,
+) -> report::CaptureRecord {
+    report::CaptureRecord {
+        name: spec.name.clone(),
+        index,
+        status,
+        error: None,
+        output: output.map(|output| output.path.display().to_string()),
+        lossless: output.is_some_and(|output| output.lossless),
+        rects: output
+            .map(|output| {
+                output
+                    .frames
+                    .iter()
+                    .map(|&(i, rect)| report::PageRect::new(pages[i].page, rect))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
 
-    eprintln!(
-        "Validating {} ({}x{})",
-        path.display(),
-        img_width,
-        img_height
-    );
+/// One output file of a capture.
+#[derive(Debug)]
+struct Output {
+    path: PathBuf,
+    /// Index into the input's frames and the rectangle cropped from each;
+    /// several frames are written as one multi-page or animated file
+    frames: Vec<(usize, Rect)>,
+    /// Copy the JPEG input's DCT coefficients instead of re-encoding
+    lossless: bool,
+    /// Color of the area outside the image, if not zeroed pixels
+    fill: Option<Rgba<u8>>,
+}
 
-    for spec in specs {
-        convert_coordinates(spec, origin, img_width, img_height).with_context(|| {
-            format!(
-                "Invalid capture spec '{}' for image '{}'",
-                spec.name,
+/// Read the headers of a JPEG input for lossless cropping, if --jpeg-crop
+/// asks for it.
+fn lossless_header(path: &Path, options: &Options) -> Result<Option<jpeg::Header>> {
+    if options.jpeg_crop == jpeg::JpegCrop::Reencode
+        || options
+            .encoding
+            .format
+            .is_some_and(|format| format != output::OutputFormat::Jpeg)
+    {
+        return Ok(None);
+    }
+    let header = jpeg::Header::read(path)?;
+    if let Some(header) = &header {
+        if options.auto_orient && header.orientation() != image::metadata::Orientation::NoTransforms
+        {
+            return Err(anyhow!(
+                "'{}' is stored rotated or flipped, and lossless JPEG cropping works on the stored \
+                 pixels. Use --no-auto-orient with coordinates of the stored image",
                 path.display()
-            )
-        })?;
+            ));
+        }
+    }
+    Ok(header)
+}
 
-        let out_path = make_output_path(path, &spec.name)?;
-        eprintln!("  '{}' -> {}", spec.name, out_path.display());
+/// Decode the parts of a partially decoded input that `outputs` crop, as one
+/// frame per output, and point each output at its frame.
+///
+/// Each output's rectangle is moved to its frame, which holds the part of
+/// the rectangle inside the image; lossless outputs need no pixels.
+fn decode_partial(partial: &region::Partial, outputs: &mut [Output]) -> Result<Vec<frames::Frame>> {
+    let (width, height) = partial.dimensions();
+    let mut regions = Vec::new();
+    for output in outputs.iter_mut().filter(|output| !output.lossless) {
+        for (frame, rect) in &mut output.frames {
+            let left = rect.x.clamp(0, i64::from(width));
+            let top = rect.y.clamp(0, i64::from(height));
+            let right = (rect.x + i64::from(rect.width)).clamp(left, i64::from(width));
+            let bottom = (rect.y + i64::from(rect.height)).clamp(top, i64::from(height));
+            regions.push(Rect {
+                x: left,
+                y: top,
+                width: (right - left) as u32,
+                height: (bottom - top) as u32,
+            });
+            *frame = regions.len() - 1;
+            rect.x -= left;
+            rect.y -= top;
+        }
     }
 
-    Ok(())
+    Ok(partial
+        .decode(&regions)?
+        .into_iter()
+        .map(|image| frames::Frame {
+            page: 1,
+            image,
+            delay: None,
+        })
+        .collect())
 }
 
-/// Process a single image file:
-/// - Open the image
-/// - For each capture spec, compute absolute coordinates based on origin
-/// - Crop and save as <basename>_<spec.name>.<ext>
-fn process_image(path: &Path, origin: Origin, specs: &[CaptureSpec], verbose: bool) -> Result<()> {
-    let start = Instant::now();
-    let img =
-        image::open(path).with_context(|| format!("Unable to open image '{}'", path.display()))?;
-    let decode_ms = start.elapsed().as_millis();
+/// Point each output of a decoded input at the frame of its page, as only the
+/// selected pages are decoded.
+fn decoded_frames(decoded: frames::Decoded, outputs: &mut [Output]) -> Result<Vec<frames::Frame>> {
+    for output in outputs.iter_mut() {
+        for (frame, _) in &mut output.frames {
+            let page = decoded.pages[*frame].page;
+            *frame = decoded
+                .frames
+                .iter()
+                .position(|decoded| decoded.page == page)
+                .ok_or_else(|| anyhow!("Page {page} was not decoded"))?;
+        }
+    }
+    Ok(decoded.frames)
+}
 
-    let (img_width, img_height) = img.dimensions();
+/// Resolve the output files of the capture at `index` of an input.
+///
+/// The capture is cropped from every selected page. Several pages go into
+/// one file when the output format holds multiple frames (TIFF and GIF) and
+/// the template has no `{page}`; otherwise each page gets its own file,
+/// with `_p<page>` added to the name if the template does not include it.
+///
+/// Captures of a JPEG input written losslessly, whose headers are `jpeg`,
+/// are aligned to its block grid.
+fn plan_outputs(
+    input: &Path,
+    spec: &CaptureSpec,
+    index: usize,
+    frames: &[frames::PageSize],
+    jpeg: Option<&jpeg::Header>,
+    options: &Options,
+) -> Result<Vec<Output>> {
+    let pages = spec.pages.as_ref().or(options.pages.as_ref());
+    let placed = frames
+        .iter()
+        .enumerate()
+        .filter(|(_, frame)| pages.is_none_or(|pages| pages.contains(frame.page)))
+        .map(|(i, frame)| {
+            let rect = convert_coordinates(spec, options.origin, frame.width, frame.height)
+                .with_context(|| format!("On page {}", frame.page))?;
+            Ok((i, rect))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if placed.is_empty() {
+        return Err(anyhow!(
+            "Capture '{}' selects pages {} but the image has {} page(s)",
+            spec.name,
+            pages.map(ToString::to_string).unwrap_or_default(),
+            frames.len()
+        ));
+    }
 
-    let crop_start = Instant::now();
+    // Pages the out-of-bounds policy skips are left out; if that is all of
+    // them, so is the capture
+    let selected: Vec<(usize, Rect)> = placed
+        .into_iter()
+        .filter_map(|(i, rect)| rect.map(|rect| (i, rect)))
+        .collect();
+    let Some(&(first, rect)) = selected.first() else {
+        return Ok(Vec::new());
+    };
+    let fill = match spec.out_of_bounds {
+        Some(OutOfBounds::Pad(fill)) => fill,
+        _ => None,
+    };
+    let path = make_output_path(
+        input,
+        &options.output,
+        &spec.name,
+        index,
+        frames[first].page,
+        rect,
+    )?;
+    if let Some(jpeg) = jpeg {
+        if options.encoding.format_for(&path)? == output::OutputFormat::Jpeg {
+            let rect = jpeg.align(rect, options.jpeg_crop)?;
+            return Ok(vec![Output {
+                path: make_output_path(input, &options.output, &spec.name, index, 1, rect)?,
+                frames: vec![(first, rect)],
+                lossless: true,
+                fill,
+            }]);
+        }
+    }
+    if selected.len() == 1
+        || (!options.output.has_page() && options.encoding.format_for(&path)?.holds_frames())
+    {
+        return Ok(vec![Output {
+            path,
+            frames: selected,
+            lossless: false,
+            fill,
+        }]);
+    }
 
-    for spec in specs {
-        let (abs_x, abs_y) = convert_coordinates(spec, origin, img_width, img_height)
-            .with_context(|| format!("Processing image '{}'", path.display()))?;
+    selected
+        .into_iter()
+        .map(|(i, rect)| {
+            let page = frames[i].page;
+            let path = make_output_path(input, &options.output, &spec.name, index, page, rect)?;
+            Ok(Output {
+                path: if options.output.has_page() {
+                    path
+                } else {
+                    output::with_page(&path, page)
+                },
+                frames: vec![(i, rect)],
+                lossless: false,
+                fill,
+            })
+        })
+        .collect()
+}
 
-        let out_path = make_output_path(path, &spec.name)?;
+/// Crop and save using a representation close to the original image,
+/// encoded as configured and carrying over the source's metadata.
+///
+/// Parts of the rectangle outside the image are filled with the output's
+/// fill color or, without one, zeroed pixels, or black on CMYK images.
+/// Missing parent directories of the output path are created.
+fn crop_and_save(
+    frames: &[frames::Frame],
+    output: &Output,
+    jpeg: Option<&jpeg::Source>,
+    encoding: &output::Encoding,
+    metadata: &metadata::Metadata,
+) -> Result<()> {
+    let out_path = &output.path;
+    if let Some(parent) = out_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Unable to create directory '{}'", parent.display()))?;
+    }
+    // CMYK images are padded with the color's CMYK equivalent, and with
+    // black rather than zeroed samples, which would be white
+    let fill = if metadata.cmyk {
+        Some(output::rgb_to_cmyk(
+            output.fill.unwrap_or(Rgba([0, 0, 0, 255])),
+        ))
+    } else {
+        output.fill
+    };
+    let saved = match output.frames.as_slice() {
+        &[(_, rect)] if output.lossless => {
+            let jpeg = jpeg.ok_or_else(|| anyhow!("No JPEG source for a lossless crop"))?;
+            output::write_atomic(out_path, |writer| Ok(writer.write_all(&jpeg.crop(rect))?))
+        }
+        &[(i, rect)] => encoding.save(&crop(&frames[i].image, rect, fill), out_path, metadata),
+        selected => {
+            let cropped: Vec<frames::Frame> = selected
+                .iter()
+                .map(|&(i, rect)| frames::Frame {
+                    page: frames[i].page,
+                    image: crop(&frames[i].image, rect, fill),
+                    delay: frames[i].delay,
+                })
+                .collect();
+            encoding.save_frames(&cropped, out_path, metadata)
+        }
+    };
+    saved.with_context(|| format!("Unable to save image to '{}'", out_path.display()))
+}
 
-        // Crop and save using the most native representation we can.
-        crop_and_save(&img, abs_x, abs_y, spec.width, spec.height, &out_path)?;
+/// Crop a rectangle from the image, padding any part outside it with `fill`,
+/// or with zeroed (black or transparent) pixels.
+fn crop(img: &DynamicImage, rect: Rect, fill: Option<Rgba<u8>>) -> DynamicImage {
+    let (img_width, img_height) = img.dimensions();
+    let left = rect.x.max(0);
+    let top = rect.y.max(0);
+    let right = (rect.x + i64::from(rect.width)).min(i64::from(img_width));
+    let bottom = (rect.y + i64::from(rect.height)).min(i64::from(img_height));
+
+    if left == rect.x
+        && top == rect.y
+        && right - left == i64::from(rect.width)
+        && bottom - top == i64::from(rect.height)
+    {
+        return img.crop_imm(left as u32, top as u32, rect.width, rect.height);
     }
 
-    if verbose {
-        let crop_ms = crop_start.elapsed().as_millis();
-        eprintln!(
-            "Processed {} (decode: {} ms, crop+save: {} ms)",
-            path.display(),
-            decode_ms,
-            crop_ms
+    let mut canvas = match fill {
+        Some(fill) => output::filled(rect.width, rect.height, img.color(), fill),
+        None => DynamicImage::new(rect.width, rect.height, img.color()),
+    };
+    if right > left && bottom > top {
+        let part = img.crop_imm(
+            left as u32,
+            top as u32,
+            (right - left) as u32,
+            (bottom - top) as u32,
         );
+        paste(&mut canvas, &part, left - rect.x, top - rect.y);
     }
+    canvas
+}
 
-    Ok(())
+/// Paste `part` onto `canvas` at (`x`, `y`).
+///
+/// `imageops::replace` on two `DynamicImage`s copies 8-bit RGBA pixels, so
+/// images of the same color type are pasted buffer to buffer to keep 16-bit
+/// and floating-point samples intact.
+fn paste(canvas: &mut DynamicImage, part: &DynamicImage, x: i64, y: i64) {
+    use image::imageops::replace;
+
+    match (canvas, part) {
+        (DynamicImage::ImageLuma8(c), DynamicImage::ImageLuma8(p)) => replace(c, p, x, y),
+        (DynamicImage::ImageLumaA8(c), DynamicImage::ImageLumaA8(p)) => replace(c, p, x, y),
+        (DynamicImage::ImageRgb8(c), DynamicImage::ImageRgb8(p)) => replace(c, p, x, y),
+        (DynamicImage::ImageRgba8(c), DynamicImage::ImageRgba8(p)) => replace(c, p, x, y),
+        (DynamicImage::ImageLuma16(c), DynamicImage::ImageLuma16(p)) => replace(c, p, x, y),
+        (DynamicImage::ImageLumaA16(c), DynamicImage::ImageLumaA16(p)) => replace(c, p, x, y),
+        (DynamicImage::ImageRgb16(c), DynamicImage::ImageRgb16(p)) => replace(c, p, x, y),
+        (DynamicImage::ImageRgba16(c), DynamicImage::ImageRgba16(p)) => replace(c, p, x, y),
+        (DynamicImage::ImageRgb32F(c), DynamicImage::ImageRgb32F(p)) => replace(c, p, x, y),
+        (DynamicImage::ImageRgba32F(c), DynamicImage::ImageRgba32F(p)) => replace(c, p, x, y),
+        (canvas, part) => replace(canvas, part, x, y),
+    }
 }
 
-/// Crop and save using a representation close to the original image.
-fn crop_and_save(
-    img: &DynamicImage,
-    x: u32,
-    y: u32,
-    width: u32,
-    height: u32,
-    out_path: &Path,
-) -> Result<()> {
-    img.crop_imm(x, y, width, height)
-        .save(out_path)
-        .with_context(|| format!("Unable to save image to '{}'", out_path.display()))?;
-    Ok(())
+/// Build the output path for the capture at `index` of an input by rendering
+/// the output template, by default <dir>/<basename>_<`capture_name`>.<ext>
+fn make_output_path(
+    input: &Path,
+    template: &output::OutputTemplate,
+    capture_name: &str,
+    index: usize,
+    page: u32,
+    rect: Rect,
+) -> Result<PathBuf> {
+    let (stem, ext) = split_file_name(input)?;
+    Ok(template.render(
+        input,
+        &output::OutputFields {
+            stem: &stem,
+            ext: &ext,
+            name: capture_name,
+            index,
+            page,
+            rect,
+        },
+    ))
+}
+
+/// Describe a capture of an input for output conflict messages.
+fn output_owner(input: &Path, spec: &CaptureSpec) -> String {
+    format!("capture '{}' of '{}'", spec.name, input.display())
 }
 
-/// Build output filename: <basename>_<`segment_name`>.<ext>
-fn make_output_path(input: &Path, segment_name: &str) -> Result<PathBuf> {
+/// Split an input's file name into its stem and extension.
+/// The extension defaults to png if the file name has none.
+fn split_file_name(input: &Path) -> Result<(String, String)> {
     let file_name = input
         .file_name()
         .ok_or_else(|| anyhow!("Input path '{}' has no file name", input.display()))?
         .to_string_lossy();
 
-    let (stem, ext) = match file_name.rsplit_once('.') {
-        Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() => (stem.to_string(), ext),
-        _ => (file_name.to_string(), "png"), // default to png if no extension
-    };
-
-    let new_file_name = format!("{stem}_{segment_name}.{ext}");
-    Ok(input.with_file_name(new_file_name))
+    Ok(match file_name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() => {
+            (stem.to_string(), ext.to_string())
+        }
+        _ => (file_name.to_string(), "png".to_string()), // default to png if no extension
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::TempDir;
     use std::path::PathBuf;
 
     #[test]
     fn test_parse_capture_spec_valid() {
         let spec = parse_capture_spec("left:200x300:1200x1850").unwrap();
         assert_eq!(spec.name, "left");
-        assert_eq!(spec.x, 200);
-        assert_eq!(spec.y, 300);
-        assert_eq!(spec.width, 1200);
-        assert_eq!(spec.height, 1850);
+        assert_eq!(spec.x, Length::Pixels(200));
+        assert_eq!(spec.y, Length::Pixels(300));
+        assert_eq!(spec.width, Length::Pixels(1200));
+        assert_eq!(spec.height, Length::Pixels(1850));
     }
 
     #[test]
     fn test_parse_capture_spec_zero_coordinates() {
         let spec = parse_capture_spec("top:0x0:100x100").unwrap();
         assert_eq!(spec.name, "top");
-        assert_eq!(spec.x, 0);
-        assert_eq!(spec.y, 0);
-        assert_eq!(spec.width, 100);
-        assert_eq!(spec.height, 100);
+        assert_eq!(spec.x, Length::Pixels(0));
+        assert_eq!(spec.y, Length::Pixels(0));
+        assert_eq!(spec.width, Length::Pixels(100));
+        assert_eq!(spec.height, Length::Pixels(100));
     }
 
     #[test]
@@ -431,101 +2517,587 @@ mod tests {
     }
 
     #[test]
-    fn test_parse_capture_spec_zero_height() {
-        let result = parse_capture_spec("left:200x300:1200x0");
-        assert!(result.is_err());
-        assert!(result
-            .unwrap_err()
-            .to_string()
-            .contains("Width and height must be positive"));
+    fn test_parse_capture_spec_zero_height() {
+        let result = parse_capture_spec("left:200x300:1200x0");
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Width and height must be positive"));
+    }
+
+    #[test]
+    fn test_parse_capture_spec_percentages() {
+        let spec = parse_capture_spec("left:0%x0%:50%x100%").unwrap();
+        assert_eq!(spec.x, Length::Fraction(0.0));
+        assert_eq!(spec.y, Length::Fraction(0.0));
+        assert_eq!(spec.width, Length::Fraction(0.5));
+        assert_eq!(spec.height, Length::Fraction(1.0));
+    }
+
+    #[test]
+    fn test_parse_capture_spec_fractions_and_pixels() {
+        let spec = parse_capture_spec("mixed:0.25x10:0.5x200").unwrap();
+        assert_eq!(spec.x, Length::Fraction(0.25));
+        assert_eq!(spec.y, Length::Pixels(10));
+        assert_eq!(spec.width, Length::Fraction(0.5));
+        assert_eq!(spec.height, Length::Pixels(200));
+    }
+
+    #[test]
+    fn test_parse_capture_spec_zero_percent_width() {
+        let result = parse_capture_spec("left:0x0:0%x100%");
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Width and height must be positive"));
+    }
+
+    #[test]
+    fn test_parse_capture_spec_negative_offsets() {
+        let spec = parse_capture_spec("footer:0x-200:100%x200").unwrap();
+        assert_eq!(spec.x, Length::Pixels(0));
+        assert_eq!(spec.y, Length::Pixels(-200));
+        assert_eq!(spec.width, Length::Fraction(1.0));
+        assert_eq!(spec.height, Length::Pixels(200));
+
+        let spec = parse_capture_spec("edge:-10%x-0.25:5x5").unwrap();
+        assert_eq!(spec.x, Length::Fraction(-0.1));
+        assert_eq!(spec.y, Length::Fraction(-0.25));
+    }
+
+    #[test]
+    fn test_parse_capture_spec_negative_width() {
+        let result = parse_capture_spec("left:0x0:-100x100");
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Width and height must be positive"));
+    }
+
+    #[test]
+    fn test_parse_capture_spec_with_anchor() {
+        let spec = parse_capture_spec("logo@center:0x0:400x400").unwrap();
+        assert_eq!(spec.name, "logo");
+        assert_eq!(spec.anchor, Some(Anchor::Center));
+
+        let spec = parse_capture_spec("badge@se:20x20:64x64").unwrap();
+        assert_eq!(spec.name, "badge");
+        assert_eq!(spec.anchor, Some(Anchor::SouthEast));
+
+        let spec = parse_capture_spec("plain:0x0:10x10").unwrap();
+        assert_eq!(spec.anchor, None);
+    }
+
+    #[test]
+    fn test_parse_capture_spec_invalid_anchor() {
+        let result = parse_capture_spec("logo@middle:0x0:400x400");
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Failed to parse anchor"));
+    }
+
+    #[test]
+    fn test_anchor_from_str_variants() {
+        assert_eq!("nw".parse::<Anchor>().unwrap(), Anchor::NorthWest);
+        assert_eq!("top-left".parse::<Anchor>().unwrap(), Anchor::NorthWest);
+        assert_eq!("N".parse::<Anchor>().unwrap(), Anchor::North);
+        assert_eq!("top_right".parse::<Anchor>().unwrap(), Anchor::NorthEast);
+        assert_eq!("left".parse::<Anchor>().unwrap(), Anchor::West);
+        assert_eq!("centre".parse::<Anchor>().unwrap(), Anchor::Center);
+        assert_eq!("e".parse::<Anchor>().unwrap(), Anchor::East);
+        assert_eq!("bl".parse::<Anchor>().unwrap(), Anchor::SouthWest);
+        assert_eq!("bottom".parse::<Anchor>().unwrap(), Anchor::South);
+        assert_eq!("SE".parse::<Anchor>().unwrap(), Anchor::SouthEast);
+        assert!("middle".parse::<Anchor>().is_err());
+    }
+
+    #[test]
+    fn test_length_from_str_invalid() {
+        assert!("abc%".parse::<Length>().is_err());
+        assert!("1.2.3".parse::<Length>().is_err());
+        assert!("NaN%".parse::<Length>().is_err());
+    }
+
+    #[test]
+    fn test_parse_pair_valid() {
+        let result = parse_pair("100x200", 'x', "test", "spec").unwrap();
+        assert_eq!(result, (Length::Pixels(100), Length::Pixels(200)));
+    }
+
+    #[test]
+    fn test_parse_pair_missing_second() {
+        let result = parse_pair("100", 'x', "test", "spec");
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("Missing second"));
+    }
+
+    #[test]
+    fn test_parse_pair_too_many_parts() {
+        let result = parse_pair("100x200x300", 'x', "test", "spec");
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Too many components"));
+    }
+
+    #[test]
+    fn test_parse_pair_invalid_first_number() {
+        let result = parse_pair("abcx200", 'x', "test", "spec");
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Failed to parse first"));
+    }
+
+    #[test]
+    fn test_parse_pair_invalid_second_number() {
+        let result = parse_pair("100xabc", 'x', "test", "spec");
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Failed to parse second"));
+    }
+
+    #[test]
+    fn test_origin_from_str_top_left_variants() {
+        assert_eq!("tl".parse::<Origin>().unwrap(), Origin::TopLeft);
+        assert_eq!("top-left".parse::<Origin>().unwrap(), Origin::TopLeft);
+        assert_eq!("top_left".parse::<Origin>().unwrap(), Origin::TopLeft);
+        assert_eq!("TL".parse::<Origin>().unwrap(), Origin::TopLeft);
+        assert_eq!("Top-Left".parse::<Origin>().unwrap(), Origin::TopLeft);
+    }
+
+    #[test]
+    fn test_origin_from_str_bottom_left_variants() {
+        assert_eq!("bl".parse::<Origin>().unwrap(), Origin::BottomLeft);
+        assert_eq!("bottom-left".parse::<Origin>().unwrap(), Origin::BottomLeft);
+        assert_eq!("bottom_left".parse::<Origin>().unwrap(), Origin::BottomLeft);
+        assert_eq!("BL".parse::<Origin>().unwrap(), Origin::BottomLeft);
+        assert_eq!("Bottom-Left".parse::<Origin>().unwrap(), Origin::BottomLeft);
+    }
+
+    #[test]
+    fn test_origin_from_str_right_and_center_variants() {
+        assert_eq!("tr".parse::<Origin>().unwrap(), Origin::TopRight);
+        assert_eq!("top-right".parse::<Origin>().unwrap(), Origin::TopRight);
+        assert_eq!("top_right".parse::<Origin>().unwrap(), Origin::TopRight);
+        assert_eq!("br".parse::<Origin>().unwrap(), Origin::BottomRight);
+        assert_eq!(
+            "Bottom-Right".parse::<Origin>().unwrap(),
+            Origin::BottomRight
+        );
+        assert_eq!(
+            "bottom_right".parse::<Origin>().unwrap(),
+            Origin::BottomRight
+        );
+        assert_eq!("c".parse::<Origin>().unwrap(), Origin::Center);
+        assert_eq!("Center".parse::<Origin>().unwrap(), Origin::Center);
+        assert_eq!("centre".parse::<Origin>().unwrap(), Origin::Center);
+    }
+
+    #[test]
+    fn test_origin_from_str_invalid() {
+        let result = "invalid".parse::<Origin>();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Invalid origin"));
+    }
+
+    #[test]
+    fn test_parse_grid_arg() {
+        assert_eq!(parse_grid_arg("8x4").unwrap(), (8, 4));
+        assert!(parse_grid_arg("8").is_err());
+        assert!(parse_grid_arg("0x4").is_err());
+        assert!(parse_grid_arg("8xa").is_err());
+    }
+
+    #[test]
+    fn test_grid_count_specs() {
+        let specs = Grid::Count { rows: 2, cols: 3 }.specs(300, 200).unwrap();
+        let names: Vec<&str> = specs.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["r0c0", "r0c1", "r0c2", "r1c0", "r1c1", "r1c2"]);
+        assert_eq!(specs[4].x, Length::Pixels(100));
+        assert_eq!(specs[4].y, Length::Pixels(100));
+        assert_eq!(specs[4].width, Length::Pixels(100));
+        assert_eq!(specs[4].height, Length::Pixels(100));
+    }
+
+    #[test]
+    fn test_grid_count_uneven_tiles_exactly() {
+        let specs = Grid::Count { rows: 1, cols: 3 }.specs(10, 5).unwrap();
+        let spans: Vec<(Length, Length)> = specs.iter().map(|s| (s.x, s.width)).collect();
+        assert_eq!(
+            spans,
+            vec![
+                (Length::Pixels(0), Length::Pixels(3)),
+                (Length::Pixels(3), Length::Pixels(3)),
+                (Length::Pixels(6), Length::Pixels(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_grid_count_ignores_origin() {
+        let specs = Grid::Count { rows: 2, cols: 1 }.specs(10, 10).unwrap();
+        let rect = convert_coordinates(&specs[0], Origin::BottomLeft, 10, 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(rect.y, 0);
+    }
+
+    #[test]
+    fn test_grid_count_too_fine() {
+        let result = Grid::Count { rows: 2, cols: 20 }.specs(10, 10);
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("more cells than pixels"));
+    }
+
+    #[test]
+    fn test_grid_cell_size_specs() {
+        let specs = Grid::CellSize {
+            width: 32,
+            height: 32,
+        }
+        .specs(100, 64)
+        .unwrap();
+        assert_eq!(specs.len(), 6);
+        assert_eq!(specs[5].name, "r1c2");
+        assert_eq!(specs[5].x, Length::Pixels(64));
+        assert_eq!(specs[5].y, Length::Pixels(32));
+        assert_eq!(specs[5].width, Length::Pixels(32));
+    }
+
+    #[test]
+    fn test_grid_cell_size_too_large() {
+        let result = Grid::CellSize {
+            width: 200,
+            height: 10,
+        }
+        .specs(100, 100);
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("larger than image"));
+    }
+
+    #[test]
+    fn test_parse_tile_step() {
+        assert_eq!(parse_tile_step("64").unwrap(), (64, 64));
+        assert_eq!(parse_tile_step("64x32").unwrap(), (64, 32));
+        assert_eq!(parse_tile_step("0").unwrap(), (0, 0));
+        assert!(parse_tile_step("64x").is_err());
+    }
+
+    #[test]
+    fn test_tile_edge_from_str() {
+        assert_eq!("drop".parse::<TileEdge>().unwrap(), TileEdge::Drop);
+        assert_eq!("PAD".parse::<TileEdge>().unwrap(), TileEdge::Pad);
+        assert_eq!("shift-in".parse::<TileEdge>().unwrap(), TileEdge::Shift);
+        assert!("wrap".parse::<TileEdge>().is_err());
+    }
+
+    #[test]
+    fn test_tile_spans_exact_fit() {
+        let spans = tile_spans(512, 448, 1408, TileEdge::Drop);
+        assert_eq!(spans, vec![(0, 512), (448, 512), (896, 512)]);
+    }
+
+    #[test]
+    fn test_tile_spans_edges() {
+        assert_eq!(
+            tile_spans(512, 448, 1000, TileEdge::Drop),
+            vec![(0, 512), (448, 512)]
+        );
+        assert_eq!(
+            tile_spans(512, 448, 1000, TileEdge::Pad),
+            vec![(0, 512), (448, 512), (896, 512)]
+        );
+        assert_eq!(
+            tile_spans(512, 448, 1000, TileEdge::Shift),
+            vec![(0, 512), (448, 512), (488, 512)]
+        );
+    }
+
+    #[test]
+    fn test_tile_spans_shift_does_not_duplicate() {
+        // With a stride larger than the remainder, shifting lands on a new start
+        assert_eq!(
+            tile_spans(100, 100, 250, TileEdge::Shift),
+            vec![(0, 100), (100, 100), (150, 100)]
+        );
+        assert_eq!(
+            tile_spans(100, 100, 200, TileEdge::Shift),
+            vec![(0, 100), (100, 100)]
+        );
     }
 
     #[test]
-    fn test_parse_pair_valid() {
-        let result = parse_pair("100x200", 'x', "test", "spec").unwrap();
-        assert_eq!(result, (100, 200));
+    fn test_tile_spans_smaller_than_tile() {
+        assert_eq!(tile_spans(512, 448, 300, TileEdge::Drop), vec![]);
+        assert_eq!(tile_spans(512, 448, 300, TileEdge::Pad), vec![(0, 512)]);
+        assert_eq!(tile_spans(512, 448, 300, TileEdge::Shift), vec![(0, 512)]);
     }
 
     #[test]
-    fn test_parse_pair_missing_second() {
-        let result = parse_pair("100", 'x', "test", "spec");
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("Missing second"));
+    fn test_grid_tiles_pad_marks_overflowing_tiles() {
+        let grid = Grid::Tiles {
+            width: 64,
+            height: 64,
+            stride_x: 48,
+            stride_y: 64,
+            edge: TileEdge::Pad,
+        };
+        let specs = grid.specs(100, 64).unwrap();
+        let names: Vec<&str> = specs.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["r0c0", "r0c1"]);
+        assert_eq!(specs[0].out_of_bounds, None);
+        assert_eq!(specs[1].out_of_bounds, Some(OutOfBounds::Pad(None)));
+        assert_eq!(specs[1].x, Length::Pixels(48));
+
+        let rect = convert_coordinates(&specs[1], Origin::TopLeft, 100, 64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rect,
+            Rect {
+                x: 48,
+                y: 0,
+                width: 64,
+                height: 64
+            }
+        );
     }
 
     #[test]
-    fn test_parse_pair_too_many_parts() {
-        let result = parse_pair("100x200x300", 'x', "test", "spec");
-        assert!(result.is_err());
-        assert!(result
-            .unwrap_err()
-            .to_string()
-            .contains("Too many components"));
+    fn test_convert_coordinates_pad_allows_negative_start() {
+        let mut spec = parse_capture_spec("big@center:0x0:120x50").unwrap();
+        assert!(convert_coordinates(&spec, Origin::TopLeft, 100, 100).is_err());
+
+        spec.out_of_bounds = Some(OutOfBounds::Pad(None));
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 100, 100)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rect,
+            Rect {
+                x: -10,
+                y: 25,
+                width: 120,
+                height: 50
+            }
+        );
     }
 
     #[test]
-    fn test_parse_pair_invalid_first_number() {
-        let result = parse_pair("abcx200", 'x', "test", "spec");
-        assert!(result.is_err());
-        assert!(result
-            .unwrap_err()
-            .to_string()
-            .contains("Failed to parse first"));
+    fn test_convert_coordinates_clamp_and_skip() {
+        let mut spec = parse_capture_spec("scan:10x-50:100x52").unwrap();
+        spec.y = Length::Pixels(50);
+        assert!(convert_coordinates(&spec, Origin::TopLeft, 100, 100).is_err());
+
+        spec.out_of_bounds = Some(OutOfBounds::Clamp);
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 100, 100)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rect,
+            Rect {
+                x: 10,
+                y: 50,
+                width: 90,
+                height: 50
+            }
+        );
+
+        spec.out_of_bounds = Some(OutOfBounds::Skip);
+        assert_eq!(
+            convert_coordinates(&spec, Origin::TopLeft, 100, 100).unwrap(),
+            None
+        );
+        assert!(convert_coordinates(&spec, Origin::TopLeft, 200, 200)
+            .unwrap()
+            .is_some());
+
+        // Only captures outside the image are skipped, not invalid ones
+        let mut empty = spec.clone();
+        empty.width = Length::Fraction(1e-9);
+        assert!(convert_coordinates(&empty, Origin::TopLeft, 100, 100).is_err());
+
+        // Offsets past the edge leave nothing to clamp to
+        spec.x = Length::Pixels(150);
+        spec.out_of_bounds = Some(OutOfBounds::Clamp);
+        assert!(convert_coordinates(&spec, Origin::TopLeft, 100, 100).is_err());
+        spec.out_of_bounds = Some(OutOfBounds::Skip);
+        assert_eq!(
+            convert_coordinates(&spec, Origin::TopLeft, 100, 100).unwrap(),
+            None
+        );
     }
 
     #[test]
-    fn test_parse_pair_invalid_second_number() {
-        let result = parse_pair("100xabc", 'x', "test", "spec");
-        assert!(result.is_err());
-        assert!(result
-            .unwrap_err()
-            .to_string()
-            .contains("Failed to parse second"));
+    fn test_parse_out_of_bounds() {
+        assert_eq!("error".parse(), Ok(OutOfBounds::Error));
+        assert_eq!("Clamp".parse(), Ok(OutOfBounds::Clamp));
+        assert_eq!("pad".parse(), Ok(OutOfBounds::Pad(None)));
+        assert_eq!("skip".parse(), Ok(OutOfBounds::Skip));
+        assert_eq!(
+            "pad:white".parse(),
+            Ok(OutOfBounds::Pad(Some(Rgba([255, 255, 255, 255]))))
+        );
+        assert_eq!(
+            "pad:#FF8800".parse(),
+            Ok(OutOfBounds::Pad(Some(Rgba([255, 136, 0, 255]))))
+        );
+        assert_eq!(
+            "pad:00000080".parse(),
+            Ok(OutOfBounds::Pad(Some(Rgba([0, 0, 0, 128]))))
+        );
+        assert!("pad:#ff88".parse::<OutOfBounds>().is_err());
+        assert!("pad:chartreuse".parse::<OutOfBounds>().is_err());
+        assert!("wrap".parse::<OutOfBounds>().is_err());
+
+        for policy in [
+            "error",
+            "clamp",
+            "pad",
+            "pad:#ff8800",
+            "pad:00000000",
+            "skip",
+        ] {
+            let parsed: OutOfBounds = policy.parse().unwrap();
+            assert_eq!(parsed.to_string().parse(), Ok(parsed));
+        }
     }
 
     #[test]
-    fn test_origin_from_str_top_left_variants() {
-        assert_eq!("tl".parse::<Origin>().unwrap(), Origin::TopLeft);
-        assert_eq!("top-left".parse::<Origin>().unwrap(), Origin::TopLeft);
-        assert_eq!("top_left".parse::<Origin>().unwrap(), Origin::TopLeft);
-        assert_eq!("TL".parse::<Origin>().unwrap(), Origin::TopLeft);
-        assert_eq!("Top-Left".parse::<Origin>().unwrap(), Origin::TopLeft);
+    fn test_crop_pads_outside_image() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            4,
+            4,
+            image::Rgb([200, 100, 50]),
+        ));
+        let cropped = crop(
+            &img,
+            Rect {
+                x: -1,
+                y: 2,
+                width: 3,
+                height: 3,
+            },
+            None,
+        );
+        assert_eq!(cropped.dimensions(), (3, 3));
+        let rgb = cropped.to_rgb8();
+        assert_eq!(rgb.get_pixel(0, 0), &image::Rgb([0, 0, 0]));
+        assert_eq!(rgb.get_pixel(1, 0), &image::Rgb([200, 100, 50]));
+        assert_eq!(rgb.get_pixel(2, 1), &image::Rgb([200, 100, 50]));
+        assert_eq!(rgb.get_pixel(1, 2), &image::Rgb([0, 0, 0]));
+
+        let img =
+            DynamicImage::ImageLuma16(image::ImageBuffer::from_pixel(4, 4, image::Luma([1000u16])));
+        let cropped = crop(
+            &img,
+            Rect {
+                x: 3,
+                y: 0,
+                width: 2,
+                height: 1,
+            },
+            Some(Rgba([255, 255, 255, 255])),
+        );
+        assert_eq!(cropped.to_luma16().into_raw(), vec![1000, u16::MAX]);
     }
 
     #[test]
-    fn test_origin_from_str_bottom_left_variants() {
-        assert_eq!("bl".parse::<Origin>().unwrap(), Origin::BottomLeft);
-        assert_eq!("bottom-left".parse::<Origin>().unwrap(), Origin::BottomLeft);
-        assert_eq!("bottom_left".parse::<Origin>().unwrap(), Origin::BottomLeft);
-        assert_eq!("BL".parse::<Origin>().unwrap(), Origin::BottomLeft);
-        assert_eq!("Bottom-Left".parse::<Origin>().unwrap(), Origin::BottomLeft);
+    fn test_process_image_pads_cmyk_with_black() {
+        let dir = TempDir::new("pad-cmyk");
+        let input = dir.join("print.tif");
+        let cmyk = metadata::Metadata {
+            cmyk: true,
+            ..metadata::Metadata::default()
+        };
+        let img =
+            DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(10, 10, Rgba([10, 20, 30, 0])));
+        output::Encoding::default()
+            .save(&img, &input, &cmyk)
+            .unwrap();
+
+        let mut specs = vec![parse_capture_spec("edge:8x0:4x2").unwrap()];
+        specs[0].out_of_bounds = Some(OutOfBounds::Pad(None));
+        let options = test_options(output::DEFAULT_TEMPLATE);
+        let mut record = report::ImageRecord::new(&input);
+        process_image(
+            &input,
+            &InputSpecs::resolve(&input, &specs, &options).unwrap(),
+            &options,
+            &mut record,
+        )
+        .unwrap();
+
+        let (decoded, is_cmyk) = frames::read_tiff(&dir.join("print_edge.tif"), None).unwrap();
+        assert!(is_cmyk);
+        let samples = decoded.frames[0].image.to_rgba8();
+        assert_eq!(samples.get_pixel(1, 0), &Rgba([10, 20, 30, 0]));
+        assert_eq!(samples.get_pixel(2, 0), &Rgba([0, 0, 0, 255]));
     }
 
     #[test]
-    fn test_origin_from_str_invalid() {
-        let result = "invalid".parse::<Origin>();
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Invalid origin"));
+    fn test_crop_inside_image() {
+        let img = DynamicImage::ImageLuma8(image::GrayImage::from_fn(4, 4, |x, y| {
+            image::Luma([(x + y * 4) as u8])
+        }));
+        let cropped = crop(
+            &img,
+            Rect {
+                x: 1,
+                y: 1,
+                width: 2,
+                height: 2,
+            },
+            None,
+        );
+        assert_eq!(cropped.to_luma8().into_raw(), vec![5, 6, 9, 10]);
+    }
+
+    fn default_output_path(input: &Path, capture_name: &str) -> Result<PathBuf> {
+        let template = output::OutputTemplate::new(output::DEFAULT_TEMPLATE, None, None).unwrap();
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        make_output_path(input, &template, capture_name, 0, 1, rect)
     }
 
     #[test]
     fn test_make_output_path_with_extension() {
         let input = PathBuf::from("/path/to/image.jpg");
-        let output = make_output_path(&input, "left").unwrap();
+        let output = default_output_path(&input, "left").unwrap();
         assert_eq!(output, PathBuf::from("/path/to/image_left.jpg"));
     }
 
     #[test]
     fn test_make_output_path_with_multiple_dots() {
         let input = PathBuf::from("/path/to/my.image.file.png");
-        let output = make_output_path(&input, "crop").unwrap();
+        let output = default_output_path(&input, "crop").unwrap();
         assert_eq!(output, PathBuf::from("/path/to/my.image.file_crop.png"));
     }
 
     #[test]
     fn test_make_output_path_no_extension() {
         let input = PathBuf::from("/path/to/image");
-        let output = make_output_path(&input, "output").unwrap();
+        let output = default_output_path(&input, "output").unwrap();
         assert_eq!(output, PathBuf::from("/path/to/image_output.png"));
     }
 
@@ -534,7 +3106,7 @@ mod tests {
         let extensions = vec!["jpg", "png", "gif", "bmp", "tiff", "webp"];
         for ext in extensions {
             let input = PathBuf::from(format!("/path/to/image.{ext}"));
-            let output = make_output_path(&input, "test").unwrap();
+            let output = default_output_path(&input, "test").unwrap();
             assert_eq!(output, PathBuf::from(format!("/path/to/image_test.{ext}")));
         }
     }
@@ -542,25 +3114,491 @@ mod tests {
     #[test]
     fn test_make_output_path_special_characters_in_name() {
         let input = PathBuf::from("/path/to/image-with-dashes.jpg");
-        let output = make_output_path(&input, "segment_name").unwrap();
+        let output = default_output_path(&input, "segment_name").unwrap();
         assert_eq!(
             output,
             PathBuf::from("/path/to/image-with-dashes_segment_name.jpg")
         );
     }
 
+    #[test]
+    fn test_make_output_path_template() {
+        let template = output::OutputTemplate::new(
+            "{dir}/{stem}/{index:02}_{name}_{w}x{h}.{ext}",
+            Some(PathBuf::from("/out")),
+            None,
+        )
+        .unwrap();
+        let rect = Rect {
+            x: 5,
+            y: 6,
+            width: 30,
+            height: 40,
+        };
+        let output =
+            make_output_path(Path::new("/in/scan.jpg"), &template, "left", 3, 1, rect).unwrap();
+        assert_eq!(output, PathBuf::from("/out/scan/03_left_30x40.jpg"));
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut image = Summary {
+            images: 1,
+            written: 2,
+            ..Summary::default()
+        };
+        assert!(image.fail(anyhow!("stop"), false).is_err());
+        assert_eq!(image.failed, 0);
+        image.fail(anyhow!("carry on"), true).unwrap();
+        assert_eq!(image.failed, 1);
+
+        let ok = Summary {
+            images: 2,
+            written: 5,
+            skipped: 1,
+            ..Summary::default()
+        };
+        assert_eq!(ok.exit_code(), ExitCode::SUCCESS);
+        let partial = ok
+            + Summary {
+                failed_images: 1,
+                ..image
+            };
+        assert_eq!(partial.exit_code(), ExitCode::from(EXIT_PARTIAL_FAILURE));
+        assert_eq!(
+            partial.to_string(),
+            "Processed 3 images: 2 succeeded, 1 failed; 7 outputs written, 1 skipped, 1 failed"
+        );
+        let outside = Summary {
+            outside: 2,
+            ..partial
+        };
+        assert_eq!(
+            outside.to_string(),
+            "Processed 3 images: 2 succeeded, 1 failed; 7 outputs written, 1 skipped, 1 failed; \
+             2 captures outside the image"
+        );
+        let failed = Summary {
+            images: 1,
+            failed_images: 1,
+            ..Summary::default()
+        };
+        assert_eq!(failed.exit_code(), ExitCode::FAILURE);
+    }
+
+    #[test]
+    fn test_plan_outputs_pages() {
+        let frames: Vec<frames::PageSize> = (1..=3)
+            .map(|page| frames::PageSize {
+                page,
+                width: 20,
+                height: 10,
+            })
+            .collect();
+        let spec = parse_capture_spec("left:0x0:5x5").unwrap();
+        let options = |template: &str, pages: Option<&str>| Options {
+            origin: Origin::TopLeft,
+            sidecars: false,
+            auto_orient: true,
+            grid: None,
+            out_of_bounds: OutOfBounds::Error,
+            pyramid: None,
+            pages: pages.map(|p| p.parse().unwrap()),
+            output: output::OutputTemplate::new(template, None, None).unwrap(),
+            encoding: output::Encoding::default(),
+            jpeg_crop: jpeg::JpegCrop::Reencode,
+            outputs: output::OutputRegistry::new(output::Conflict::Error),
+            keep_going: false,
+            json: false,
+            verbose: false,
+        };
+        let paths = |input: &str, options: &Options| -> Vec<(PathBuf, usize)> {
+            plan_outputs(Path::new(input), &spec, 0, &frames, None, options)
+                .unwrap()
+                .into_iter()
+                .map(|output| (output.path, output.frames.len()))
+                .collect()
+        };
+
+        // Multi-page formats keep the pages together
+        let default = options(output::DEFAULT_TEMPLATE, None);
+        assert_eq!(
+            paths("/in/fax.tif", &default),
+            vec![(PathBuf::from("/in/fax_left.tif"), 3)]
+        );
+        // Others get one file per page
+        assert_eq!(
+            paths("/in/anim.webp", &default),
+            vec![
+                (PathBuf::from("/in/anim_left_p1.webp"), 1),
+                (PathBuf::from("/in/anim_left_p2.webp"), 1),
+                (PathBuf::from("/in/anim_left_p3.webp"), 1),
+            ]
+        );
+        // As does a template with {page}
+        let per_page = options("{dir}/{stem}_{name}_{page:02}.{ext}", Some("2-"));
+        assert_eq!(
+            paths("/in/fax.tif", &per_page),
+            vec![
+                (PathBuf::from("/in/fax_left_02.tif"), 1),
+                (PathBuf::from("/in/fax_left_03.tif"), 1),
+            ]
+        );
+        // A single selected page is written without a suffix
+        let second = options(output::DEFAULT_TEMPLATE, Some("2"));
+        assert_eq!(
+            paths("/in/anim.webp", &second),
+            vec![(PathBuf::from("/in/anim_left.webp"), 1)]
+        );
+
+        let missing = options(output::DEFAULT_TEMPLATE, Some("4-"));
+        let result = plan_outputs(Path::new("/in/fax.tif"), &spec, 0, &frames, None, &missing);
+        assert!(result.unwrap_err().to_string().contains("has 3 page(s)"));
+    }
+
+    /// Options of a plain run writing outputs to `template`.
+    fn test_options(template: &str) -> Options {
+        Options {
+            origin: Origin::TopLeft,
+            sidecars: false,
+            auto_orient: true,
+            grid: None,
+            out_of_bounds: OutOfBounds::Error,
+            pyramid: None,
+            pages: None,
+            output: output::OutputTemplate::new(template, None, None).unwrap(),
+            encoding: output::Encoding::default(),
+            jpeg_crop: jpeg::JpegCrop::Reencode,
+            outputs: output::OutputRegistry::new(output::Conflict::Error),
+            keep_going: false,
+            json: true,
+            verbose: false,
+        }
+    }
+
+    #[test]
+    fn test_validate_image_reports_conflicts() {
+        let dir = TempDir::new("validate");
+        let input = dir.join("scan.png");
+        image::RgbImage::new(20, 10).save(&input).unwrap();
+        std::fs::write(dir.join("scan_left.png"), b"").unwrap();
+        let specs = vec![
+            parse_capture_spec("left:0x0:5x5").unwrap(),
+            parse_capture_spec("right:10x0:5x5").unwrap(),
+        ];
+
+        // The conflict is listed with its capture, and the rest still checked
+        let mut record = report::ImageRecord::new(&input);
+        let options = test_options(output::DEFAULT_TEMPLATE);
+        let err = validate_image(
+            &input,
+            &InputSpecs::resolve(&input, &specs, &options).unwrap(),
+            &options,
+            &mut record,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("1 output(s)"), "{err}");
+        let statuses: Vec<_> = record.captures.iter().map(|c| c.status).collect();
+        assert_eq!(statuses, [report::Status::Failed, report::Status::Planned]);
+        let error = record.captures[0].error.as_deref().unwrap();
+        assert!(error.contains("already exists"), "{error}");
+    }
+
+    #[test]
+    fn test_validate_then_process_image_records() {
+        let dir = TempDir::new("records");
+        let input = dir.join("scan.png");
+        image::RgbImage::new(20, 10).save(&input).unwrap();
+        let mut specs = vec![
+            parse_capture_spec("left:0x0:50%x100%").unwrap(),
+            parse_capture_spec("badge@se:2x2:4x4").unwrap(),
+            parse_capture_spec("beyond:18x0:5x5").unwrap(),
+        ];
+        specs[2].out_of_bounds = Some(OutOfBounds::Skip);
+        let rects = [
+            vec![report::PageRect {
+                page: 1,
+                x: 0,
+                y: 0,
+                width: 10,
+                height: 10,
+            }],
+            vec![report::PageRect {
+                page: 1,
+                x: 14,
+                y: 4,
+                width: 4,
+                height: 4,
+            }],
+            vec![],
+        ];
+        let outputs = [
+            Some(dir.join("scan_left.png").display().to_string()),
+            Some(dir.join("scan_badge.png").display().to_string()),
+            None,
+        ];
+
+        // A dry run plans each capture without writing it
+        let mut record = report::ImageRecord::new(&input);
+        let options = test_options(output::DEFAULT_TEMPLATE);
+        validate_image(
+            &input,
+            &InputSpecs::resolve(&input, &specs, &options).unwrap(),
+            &options,
+            &mut record,
+        )
+        .unwrap();
+        assert_eq!(record.status, report::Status::Ok);
+        assert_eq!(
+            (record.width, record.height, record.pages),
+            (Some(20), Some(10), Some(1))
+        );
+        let statuses: Vec<_> = record.captures.iter().map(|c| c.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                report::Status::Planned,
+                report::Status::Planned,
+                report::Status::Outside
+            ]
+        );
+        for (capture, (rects, output)) in record.captures.iter().zip(rects.iter().zip(&outputs)) {
+            assert_eq!(&capture.rects, rects, "{}", capture.name);
+            assert_eq!(&capture.output, output, "{}", capture.name);
+        }
+        assert_eq!(
+            Summary::validated(&record),
+            Summary {
+                images: 1,
+                outside: 1,
+                ..Summary::default()
+            }
+        );
+        assert!(!dir.join("scan_left.png").exists());
+
+        // Processing writes the same outputs from the same rectangles
+        let mut record = report::ImageRecord::new(&input);
+        let options = test_options(output::DEFAULT_TEMPLATE);
+        let summary = process_image(
+            &input,
+            &InputSpecs::resolve(&input, &specs, &options).unwrap(),
+            &options,
+            &mut record,
+        )
+        .unwrap();
+        assert_eq!((summary.written, summary.outside), (2, 1));
+        let statuses: Vec<_> = record.captures.iter().map(|c| c.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                report::Status::Written,
+                report::Status::Written,
+                report::Status::Outside
+            ]
+        );
+        for (capture, (rects, output)) in record.captures.iter().zip(rects.iter().zip(&outputs)) {
+            assert_eq!(&capture.rects, rects, "{}", capture.name);
+            assert_eq!(&capture.output, output, "{}", capture.name);
+        }
+        assert!(dir.join("scan_left.png").is_file());
+
+        // A failed image counts as such in the dry-run summary
+        let missing = dir.join("missing.png");
+        let mut record = report::ImageRecord::new(&missing);
+        let err = validate_image(
+            &missing,
+            &InputSpecs::resolve(&missing, &specs, &options).unwrap(),
+            &options,
+            &mut record,
+        )
+        .unwrap_err();
+        record.fail(&err);
+        assert_eq!(Summary::validated(&record).failed_images, 1);
+    }
+
+    #[test]
+    fn test_process_image_selected_pages() {
+        let dir = TempDir::new("selected");
+        let input = dir.join("fax.tif");
+        let pages: Vec<frames::Frame> = (1..=3)
+            .map(|page| frames::Frame {
+                page,
+                image: DynamicImage::ImageLuma8(image::GrayImage::from_pixel(
+                    10,
+                    10,
+                    image::Luma([page as u8 * 50]),
+                )),
+                delay: None,
+            })
+            .collect();
+        output::Encoding::default()
+            .save_frames(&pages, &input, &metadata::Metadata::default())
+            .unwrap();
+
+        // Only page 3 is decoded, and the capture is cut from it
+        let mut specs = vec![parse_capture_spec("last:0x0:4x4").unwrap()];
+        specs[0].pages = Some("3".parse().unwrap());
+        let options = test_options(output::DEFAULT_TEMPLATE);
+        let mut record = report::ImageRecord::new(&input);
+        let summary = process_image(
+            &input,
+            &InputSpecs::resolve(&input, &specs, &options).unwrap(),
+            &options,
+            &mut record,
+        )
+        .unwrap();
+        assert_eq!(summary.written, 1);
+        assert_eq!(record.pages, Some(3));
+        let capture = image::open(dir.join("fax_last.tif")).unwrap();
+        assert_eq!(capture.to_luma8().get_pixel(0, 0), &image::Luma([150]));
+    }
+
+    #[test]
+    fn test_process_image_keep_going() {
+        let dir = TempDir::new("keep-going");
+        let input = dir.join("scan.png");
+        image::RgbImage::new(20, 10).save(&input).unwrap();
+        // A file where the capture's directory should be
+        std::fs::write(dir.join("bad"), b"").unwrap();
+        let specs = vec![
+            parse_capture_spec("good:0x0:5x5").unwrap(),
+            parse_capture_spec("bad:10x0:5x5").unwrap(),
+        ];
+        let mut options = test_options("{dir}/{name}/{stem}.{ext}");
+        options.keep_going = true;
+
+        let mut record = report::ImageRecord::new(&input);
+        let summary = process_image(
+            &input,
+            &InputSpecs::resolve(&input, &specs, &options).unwrap(),
+            &options,
+            &mut record,
+        )
+        .unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                images: 1,
+                failed_images: 1,
+                written: 1,
+                skipped: 0,
+                outside: 0,
+                failed: 1,
+            }
+        );
+        assert_eq!(summary.exit_code(), ExitCode::FAILURE);
+        assert_eq!(record.status, report::Status::Failed);
+        let statuses: Vec<_> = record.captures.iter().map(|c| c.status).collect();
+        assert_eq!(statuses, [report::Status::Written, report::Status::Failed]);
+        assert!(record.captures[1].error.is_some());
+        assert!(dir.join("good/scan.png").is_file());
+
+        // Another image that succeeds makes it a partial failure
+        let other = dir.join("other.png");
+        image::RgbImage::new(20, 10).save(&other).unwrap();
+        let mut record = report::ImageRecord::new(&other);
+        let total = summary
+            + process_image(
+                &other,
+                &InputSpecs::resolve(&other, &specs[..1], &options).unwrap(),
+                &options,
+                &mut record,
+            )
+            .unwrap();
+        assert_eq!(record.status, report::Status::Ok);
+        assert_eq!(total.exit_code(), ExitCode::from(EXIT_PARTIAL_FAILURE));
+    }
+
+    #[test]
+    fn test_process_images_within_memory_budget() {
+        let dir = TempDir::new("budget");
+        // Two small images fit the budget together, a large one only alone
+        let inputs: Vec<PathBuf> = (0..16)
+            .flat_map(|i| {
+                [
+                    (format!("small{i}.png"), 100),
+                    (format!("large{i}.png"), 136),
+                ]
+            })
+            .map(|(name, size)| {
+                let path = dir.join(name);
+                image::RgbImage::new(size, size).save(&path).unwrap();
+                path
+            })
+            .collect();
+        let mut options = test_options(output::DEFAULT_TEMPLATE);
+        options.grid = Some(Grid::CellSize {
+            width: 20,
+            height: 20,
+        });
+
+        // Workers that wait for the budget inside nested capture work deadlock,
+        // so processing runs on its own thread to fail rather than hang
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(8)
+                .build()
+                .unwrap();
+            let budget = memory::MemoryBudget::new(memory::Bytes(62_500));
+            let summary = process_images(&inputs, &[], &options, &pool, Some(&budget));
+            sender.send(summary.unwrap()).unwrap();
+        });
+        let summary = receiver
+            .recv_timeout(std::time::Duration::from_secs(60))
+            .expect("processing finishes");
+        assert_eq!(summary.images, 32);
+        assert_eq!(summary.written, 16 * 5 * 5 + 16 * 6 * 6);
+    }
+
+    #[test]
+    fn test_process_images_budget_reports_sidecar_errors() {
+        let dir = TempDir::new("budget-sidecar");
+        let inputs: Vec<PathBuf> = ["good.png", "bad.png"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                image::RgbImage::new(40, 40).save(&path).unwrap();
+                path
+            })
+            .collect();
+        std::fs::write(dir.join("bad.png.cutout.json"), "{").unwrap();
+        let mut options = test_options(output::DEFAULT_TEMPLATE);
+        options.sidecars = true;
+        options.keep_going = true;
+        options.grid = Some(Grid::CellSize {
+            width: 20,
+            height: 20,
+        });
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let budget = memory::MemoryBudget::new(memory::Bytes(1 << 20));
+        let summary = process_images(&inputs, &[], &options, &pool, Some(&budget)).unwrap();
+        assert_eq!(summary.images, 2);
+        assert_eq!(summary.failed_images, 1);
+        assert_eq!(summary.written, 4);
+    }
+
     #[test]
     fn test_convert_coordinates_top_left_origin() {
         let spec = CaptureSpec {
             name: "test".to_string(),
-            x: 100,
-            y: 200,
-            width: 50,
-            height: 75,
+            anchor: None,
+            x: Length::Pixels(100),
+            y: Length::Pixels(200),
+            width: Length::Pixels(50),
+            height: Length::Pixels(75),
+            out_of_bounds: None,
+            pages: None,
         };
-        let (abs_x, abs_y) = convert_coordinates(&spec, Origin::TopLeft, 1000, 1000).unwrap();
-        assert_eq!(abs_x, 100);
-        assert_eq!(abs_y, 200);
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 1000, 1000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(rect.x, 100);
+        assert_eq!(rect.y, 200);
     }
 
     #[test]
@@ -569,14 +3607,19 @@ mod tests {
         // should convert to top-left (0, 900)
         let spec = CaptureSpec {
             name: "test".to_string(),
-            x: 0,
-            y: 0,
-            width: 100,
-            height: 100,
+            anchor: None,
+            x: Length::Pixels(0),
+            y: Length::Pixels(0),
+            width: Length::Pixels(100),
+            height: Length::Pixels(100),
+            out_of_bounds: None,
+            pages: None,
         };
-        let (abs_x, abs_y) = convert_coordinates(&spec, Origin::BottomLeft, 1000, 1000).unwrap();
-        assert_eq!(abs_x, 0);
-        assert_eq!(abs_y, 900);
+        let rect = convert_coordinates(&spec, Origin::BottomLeft, 1000, 1000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(rect.x, 0);
+        assert_eq!(rect.y, 900);
     }
 
     #[test]
@@ -586,28 +3629,38 @@ mod tests {
         // Formula: abs_y = img_height - spec.y - spec.height = 1000 - 200 - 100 = 700
         let spec = CaptureSpec {
             name: "test".to_string(),
-            x: 50,
-            y: 200,
-            width: 100,
-            height: 100,
+            anchor: None,
+            x: Length::Pixels(50),
+            y: Length::Pixels(200),
+            width: Length::Pixels(100),
+            height: Length::Pixels(100),
+            out_of_bounds: None,
+            pages: None,
         };
-        let (abs_x, abs_y) = convert_coordinates(&spec, Origin::BottomLeft, 1000, 1000).unwrap();
-        assert_eq!(abs_x, 50);
-        assert_eq!(abs_y, 700);
+        let rect = convert_coordinates(&spec, Origin::BottomLeft, 1000, 1000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(rect.x, 50);
+        assert_eq!(rect.y, 700);
     }
 
     #[test]
     fn test_convert_coordinates_top_left_at_edge() {
         let spec = CaptureSpec {
             name: "test".to_string(),
-            x: 900,
-            y: 900,
-            width: 100,
-            height: 100,
+            anchor: None,
+            x: Length::Pixels(900),
+            y: Length::Pixels(900),
+            width: Length::Pixels(100),
+            height: Length::Pixels(100),
+            out_of_bounds: None,
+            pages: None,
         };
-        let (abs_x, abs_y) = convert_coordinates(&spec, Origin::TopLeft, 1000, 1000).unwrap();
-        assert_eq!(abs_x, 900);
-        assert_eq!(abs_y, 900);
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 1000, 1000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(rect.x, 900);
+        assert_eq!(rect.y, 900);
     }
 
     #[test]
@@ -616,24 +3669,308 @@ mod tests {
         // For a 1000px tall image, y=900 height=100 should give abs_y=0
         let spec = CaptureSpec {
             name: "test".to_string(),
-            x: 0,
-            y: 900,
-            width: 100,
-            height: 100,
+            anchor: None,
+            x: Length::Pixels(0),
+            y: Length::Pixels(900),
+            width: Length::Pixels(100),
+            height: Length::Pixels(100),
+            out_of_bounds: None,
+            pages: None,
+        };
+        let rect = convert_coordinates(&spec, Origin::BottomLeft, 1000, 1000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(rect.x, 0);
+        assert_eq!(rect.y, 0);
+    }
+
+    #[test]
+    fn test_convert_coordinates_percentages() {
+        let spec = parse_capture_spec("right:50%x25%:50%x50%").unwrap();
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 800, 600)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rect,
+            Rect {
+                x: 400,
+                y: 150,
+                width: 400,
+                height: 300
+            }
+        );
+    }
+
+    #[test]
+    fn test_convert_coordinates_fractions_tile_odd_dimensions() {
+        // Halves of an odd width must cover the whole image without overrunning it
+        let left = parse_capture_spec("left:0x0:50%x100%").unwrap();
+        let right = parse_capture_spec("right:50%x0:50%x100%").unwrap();
+        let left = convert_coordinates(&left, Origin::TopLeft, 1001, 10)
+            .unwrap()
+            .unwrap();
+        let right = convert_coordinates(&right, Origin::TopLeft, 1001, 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!((left.x, left.width), (0, 501));
+        assert_eq!((right.x, right.width), (500, 501));
+    }
+
+    #[test]
+    fn test_convert_coordinates_fraction_rounding_error() {
+        // 0.1 * 1000 is not exactly 100.0 in floating point
+        let spec = parse_capture_spec("tenth:0.1x0.1:0.1x0.1").unwrap();
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 1000, 1000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(rect.x, 100);
+        assert_eq!(rect.width, 100);
+    }
+
+    #[test]
+    fn test_convert_coordinates_fraction_bottom_left() {
+        let spec = parse_capture_spec("bottom:0x0:100%x10%").unwrap();
+        let rect = convert_coordinates(&spec, Origin::BottomLeft, 500, 1000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rect,
+            Rect {
+                x: 0,
+                y: 900,
+                width: 500,
+                height: 100
+            }
+        );
+    }
+
+    #[test]
+    fn test_convert_coordinates_negative_offsets_top_left() {
+        let spec = parse_capture_spec("footer:0x-200:100%x200").unwrap();
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 640, 1000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rect,
+            Rect {
+                x: 0,
+                y: 800,
+                width: 640,
+                height: 200
+            }
+        );
+
+        let spec = parse_capture_spec("corner:-64x-10%:64x10%").unwrap();
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 640, 1000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rect,
+            Rect {
+                x: 576,
+                y: 900,
+                width: 64,
+                height: 100
+            }
+        );
+    }
+
+    #[test]
+    fn test_convert_coordinates_negative_offset_bottom_left() {
+        // In bottom-left coordinates the far edge is the top of the image,
+        // so y=-200 puts the bottom of the rectangle 200px below the top
+        let spec = parse_capture_spec("header:0x-200:100x200").unwrap();
+        let rect = convert_coordinates(&spec, Origin::BottomLeft, 1000, 1000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(rect.x, 0);
+        assert_eq!(rect.y, 0);
+    }
+
+    #[test]
+    fn test_convert_coordinates_negative_offset_too_large() {
+        let spec = parse_capture_spec("test:-1001x0:10x10").unwrap();
+        let result = convert_coordinates(&spec, Origin::TopLeft, 1000, 1000);
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("is outside image width"));
+    }
+
+    #[test]
+    fn test_convert_coordinates_anchor_center() {
+        let spec = parse_capture_spec("logo@center:0x0:400x400").unwrap();
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 1000, 800)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rect,
+            Rect {
+                x: 300,
+                y: 200,
+                width: 400,
+                height: 400
+            }
+        );
+
+        // Offsets shift a centred rectangle right and down
+        let spec = parse_capture_spec("logo@center:10x-20:400x400").unwrap();
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 1000, 800)
+            .unwrap()
+            .unwrap();
+        assert_eq!((rect.x, rect.y), (310, 180));
+    }
+
+    #[test]
+    fn test_convert_coordinates_anchor_corners() {
+        let spec = parse_capture_spec("badge@se:20x20:64x64").unwrap();
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 1000, 800)
+            .unwrap()
+            .unwrap();
+        assert_eq!((rect.x, rect.y), (916, 716));
+
+        let spec = parse_capture_spec("badge@ne:20x20:64x64").unwrap();
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 1000, 800)
+            .unwrap()
+            .unwrap();
+        assert_eq!((rect.x, rect.y), (916, 20));
+
+        let spec = parse_capture_spec("badge@sw:0x0:64x64").unwrap();
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 1000, 800)
+            .unwrap()
+            .unwrap();
+        assert_eq!((rect.x, rect.y), (0, 736));
+    }
+
+    #[test]
+    fn test_convert_coordinates_anchor_edges() {
+        let spec = parse_capture_spec("top@n:0x10:100x50").unwrap();
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 1001, 800)
+            .unwrap()
+            .unwrap();
+        assert_eq!((rect.x, rect.y), (450, 10));
+
+        let spec = parse_capture_spec("side@e:5x0:100x50%").unwrap();
+        let rect = convert_coordinates(&spec, Origin::TopLeft, 1000, 800)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rect,
+            Rect {
+                x: 895,
+                y: 200,
+                width: 100,
+                height: 400
+            }
+        );
+    }
+
+    #[test]
+    fn test_convert_coordinates_anchor_overrides_origin() {
+        let spec = parse_capture_spec("top@nw:0x0:100x100").unwrap();
+        let rect = convert_coordinates(&spec, Origin::BottomLeft, 1000, 1000)
+            .unwrap()
+            .unwrap();
+        assert_eq!((rect.x, rect.y), (0, 0));
+    }
+
+    #[test]
+    fn test_convert_coordinates_anchor_center_too_large() {
+        let spec = parse_capture_spec("big@center:0x0:1200x100").unwrap();
+        let result = convert_coordinates(&spec, Origin::TopLeft, 1000, 1000);
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("is outside image width"));
+    }
+
+    #[test]
+    fn test_convert_coordinates_top_right_origin() {
+        // Formula: abs_x = img_width - spec.x - spec.width = 1000 - 200 - 100 = 700
+        let spec = CaptureSpec {
+            name: "test".to_string(),
+            anchor: None,
+            x: Length::Pixels(200),
+            y: Length::Pixels(50),
+            width: Length::Pixels(100),
+            height: Length::Pixels(100),
+            out_of_bounds: None,
+            pages: None,
+        };
+        let rect = convert_coordinates(&spec, Origin::TopRight, 1000, 1000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(rect.x, 700);
+        assert_eq!(rect.y, 50);
+    }
+
+    #[test]
+    fn test_convert_coordinates_bottom_right_origin() {
+        let spec = CaptureSpec {
+            name: "test".to_string(),
+            anchor: None,
+            x: Length::Pixels(0),
+            y: Length::Pixels(0),
+            width: Length::Pixels(100),
+            height: Length::Pixels(100),
+            out_of_bounds: None,
+            pages: None,
+        };
+        let rect = convert_coordinates(&spec, Origin::BottomRight, 1000, 800)
+            .unwrap()
+            .unwrap();
+        assert_eq!(rect.x, 900);
+        assert_eq!(rect.y, 700);
+    }
+
+    #[test]
+    fn test_convert_coordinates_center_origin() {
+        let spec = CaptureSpec {
+            name: "test".to_string(),
+            anchor: None,
+            x: Length::Pixels(-100),
+            y: Length::Pixels(0),
+            width: Length::Pixels(200),
+            height: Length::Pixels(200),
+            out_of_bounds: None,
+            pages: None,
+        };
+        let rect = convert_coordinates(&spec, Origin::Center, 1000, 800)
+            .unwrap()
+            .unwrap();
+        assert_eq!(rect.x, 300);
+        assert_eq!(rect.y, 300);
+    }
+
+    #[test]
+    fn test_convert_coordinates_top_right_overflow() {
+        let spec = CaptureSpec {
+            name: "test".to_string(),
+            anchor: None,
+            x: Length::Pixels(950),
+            y: Length::Pixels(0),
+            width: Length::Pixels(100),
+            height: Length::Pixels(100),
+            out_of_bounds: None,
+            pages: None,
         };
-        let (abs_x, abs_y) = convert_coordinates(&spec, Origin::BottomLeft, 1000, 1000).unwrap();
-        assert_eq!(abs_x, 0);
-        assert_eq!(abs_y, 0);
+        let result = convert_coordinates(&spec, Origin::TopRight, 1000, 1000);
+        assert!(result.is_err());
     }
 
     #[test]
     fn test_convert_coordinates_x_out_of_bounds() {
         let spec = CaptureSpec {
             name: "test".to_string(),
-            x: 1000,
-            y: 0,
-            width: 100,
-            height: 100,
+            anchor: None,
+            x: Length::Pixels(1000),
+            y: Length::Pixels(0),
+            width: Length::Pixels(100),
+            height: Length::Pixels(100),
+            out_of_bounds: None,
+            pages: None,
         };
         let result = convert_coordinates(&spec, Origin::TopLeft, 1000, 1000);
         assert!(result.is_err());
@@ -647,10 +3984,13 @@ mod tests {
     fn test_convert_coordinates_y_out_of_bounds_top_left() {
         let spec = CaptureSpec {
             name: "test".to_string(),
-            x: 0,
-            y: 1000,
-            width: 100,
-            height: 100,
+            anchor: None,
+            x: Length::Pixels(0),
+            y: Length::Pixels(1000),
+            width: Length::Pixels(100),
+            height: Length::Pixels(100),
+            out_of_bounds: None,
+            pages: None,
         };
         let result = convert_coordinates(&spec, Origin::TopLeft, 1000, 1000);
         assert!(result.is_err());
@@ -664,10 +4004,13 @@ mod tests {
     fn test_convert_coordinates_y_out_of_bounds_bottom_left() {
         let spec = CaptureSpec {
             name: "test".to_string(),
-            x: 0,
-            y: 1001,
-            width: 100,
-            height: 100,
+            anchor: None,
+            x: Length::Pixels(0),
+            y: Length::Pixels(1001),
+            width: Length::Pixels(100),
+            height: Length::Pixels(100),
+            out_of_bounds: None,
+            pages: None,
         };
         let result = convert_coordinates(&spec, Origin::BottomLeft, 1000, 1000);
         assert!(result.is_err());
@@ -681,10 +4024,13 @@ mod tests {
     fn test_convert_coordinates_width_exceeds_bounds() {
         let spec = CaptureSpec {
             name: "test".to_string(),
-            x: 900,
-            y: 0,
-            width: 200,
-            height: 100,
+            anchor: None,
+            x: Length::Pixels(900),
+            y: Length::Pixels(0),
+            width: Length::Pixels(200),
+            height: Length::Pixels(100),
+            out_of_bounds: None,
+            pages: None,
         };
         let result = convert_coordinates(&spec, Origin::TopLeft, 1000, 1000);
         assert!(result.is_err());
@@ -698,10 +4044,13 @@ mod tests {
     fn test_convert_coordinates_height_exceeds_bounds() {
         let spec = CaptureSpec {
             name: "test".to_string(),
-            x: 0,
-            y: 900,
-            width: 100,
-            height: 200,
+            anchor: None,
+            x: Length::Pixels(0),
+            y: Length::Pixels(900),
+            width: Length::Pixels(100),
+            height: Length::Pixels(200),
+            out_of_bounds: None,
+            pages: None,
         };
         let result = convert_coordinates(&spec, Origin::TopLeft, 1000, 1000);
         assert!(result.is_err());
@@ -716,10 +4065,13 @@ mod tests {
         // When y + height > img_height in bottom-left coordinates
         let spec = CaptureSpec {
             name: "test".to_string(),
-            x: 0,
-            y: 950,
-            width: 100,
-            height: 100,
+            anchor: None,
+            x: Length::Pixels(0),
+            y: Length::Pixels(950),
+            width: Length::Pixels(100),
+            height: Length::Pixels(100),
+            out_of_bounds: None,
+            pages: None,
         };
         let result = convert_coordinates(&spec, Origin::BottomLeft, 1000, 1000);
         assert!(result.is_err());
@@ -0,0 +1,244 @@
+//! Limiting how much decoded image data a run holds at once.
+//!
+//! Each input reserves its estimated decoded size from a shared
+//! [`MemoryBudget`] before it is opened, and waits while other inputs hold
+//! too much of it. The estimate comes from the image header, so nothing is
+//! decoded while waiting.
+
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+
+use image::{ImageDecoder, ImageFormat, ImageReader};
+
+use crate::frames::{self, PageSize, Pages};
+
+/// A number of bytes, given as e.g. `512M` or `4G`.
+///
+/// Suffixes are binary multiples: K is 1024 bytes, M is 1024 K and so on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Bytes(pub(crate) u64);
+
+const UNITS: [(&str, u64); 4] = [
+    ("T", 1 << 40),
+    ("G", 1 << 30),
+    ("M", 1 << 20),
+    ("K", 1 << 10),
+];
+
+impl FromStr for Bytes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.trim().to_ascii_uppercase();
+        let number = upper
+            .strip_suffix("IB")
+            .or_else(|| upper.strip_suffix('B'))
+            .unwrap_or(&upper);
+        let (number, scale) = UNITS
+            .iter()
+            .find_map(|&(unit, scale)| number.strip_suffix(unit).map(|n| (n, scale)))
+            .unwrap_or((number, 1));
+        let value: u64 = number.parse().map_err(|_| {
+            format!("Invalid size '{s}'. Expected bytes or a number with K, M, G or T, e.g. 512M")
+        })?;
+        let bytes = value
+            .checked_mul(scale)
+            .ok_or_else(|| format!("Invalid size '{s}': too large"))?;
+        if bytes == 0 {
+            return Err(format!("Invalid size '{s}': must be positive"));
+        }
+        Ok(Bytes(bytes))
+    }
+}
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match UNITS
+            .iter()
+            .find(|&&(_, scale)| self.0.is_multiple_of(scale))
+        {
+            Some((unit, scale)) => write!(f, "{}{unit}", self.0 / scale),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Estimate the decoded size of the pages of an image that `selected` picks,
+/// or of all of them, from its headers.
+///
+/// Each TIFF page counts its width × height at the first page's bytes per
+/// pixel. GIF and WebP animations are decoded whole, so every frame counts.
+/// Returns 0 if the header cannot be read; opening the image reports why.
+pub(crate) fn estimate(path: &Path, selected: Option<&Pages>) -> u64 {
+    let Ok(reader) = ImageReader::open(path).and_then(|reader| reader.with_guessed_format()) else {
+        return 0;
+    };
+    let format = reader.format();
+    let Ok(decoder) = reader.into_decoder() else {
+        return 0;
+    };
+    let first = decoder.total_bytes();
+    match format {
+        Some(ImageFormat::Tiff) => tiff_estimate(path, selected, first).unwrap_or(first),
+        Some(format) => first.saturating_mul(frames::count_frames(path, format).unwrap_or(1)),
+        None => first,
+    }
+}
+
+/// Scale the decoded size of a TIFF's first page to the selected pages.
+fn tiff_estimate(path: &Path, selected: Option<&Pages>, first: u64) -> Option<u64> {
+    let mut decoder = tiff::decoder::Decoder::new(BufReader::new(File::open(path).ok()?)).ok()?;
+    let pages = frames::tiff_pages(&mut decoder).ok()?;
+    let pixels = |size: PageSize| u64::from(size.width) * u64::from(size.height);
+    let per_pixel = first / pixels(pages.first()?.size).max(1);
+    Some(
+        pages
+            .iter()
+            .map(|page| page.size)
+            .filter(|size| selected.is_none_or(|selected| selected.contains(size.page)))
+            .map(|size| pixels(size) * per_pixel)
+            .sum(),
+    )
+}
+
+/// Bytes of decoded image data that inputs may hold at the same time.
+#[derive(Debug)]
+pub(crate) struct MemoryBudget {
+    limit: u64,
+    used: Mutex<u64>,
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limit: Bytes) -> Self {
+        MemoryBudget {
+            limit: limit.0,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn limit(&self) -> Bytes {
+        Bytes(self.limit)
+    }
+
+    /// Wait until `bytes` are free and reserve them until the returned guard
+    /// is dropped.
+    ///
+    /// Reservations larger than the whole budget wait until nothing else is
+    /// reserved and then take all of it, so an oversized image runs alone
+    /// rather than failing.
+    ///
+    /// Reserve before handing an image to the thread pool, not from a
+    /// worker, which could be holding up the releases it waits for.
+    pub(crate) fn reserve(&self, bytes: u64) -> Reservation<'_> {
+        let bytes = bytes.min(self.limit);
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        while *used + bytes > self.limit {
+            used = self.released.wait(used).unwrap_or_else(|e| e.into_inner());
+        }
+        *used += bytes;
+        Reservation {
+            budget: self,
+            bytes,
+        }
+    }
+}
+
+/// Part of a [`MemoryBudget`] held by one input, released when dropped.
+#[derive(Debug)]
+pub(crate) struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut used = self.budget.used.lock().unwrap_or_else(|e| e.into_inner());
+        *used -= self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frames::Frame;
+    use crate::metadata::Metadata;
+    use crate::output::Encoding;
+    use crate::test_util::TempDir;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!("1024".parse::<Bytes>(), Ok(Bytes(1024)));
+        assert_eq!("512M".parse::<Bytes>(), Ok(Bytes(512 << 20)));
+        assert_eq!("4g".parse::<Bytes>(), Ok(Bytes(4 << 30)));
+        assert_eq!("2GiB".parse::<Bytes>(), Ok(Bytes(2 << 30)));
+        assert_eq!("64kb".parse::<Bytes>(), Ok(Bytes(64 << 10)));
+        assert!("0".parse::<Bytes>().is_err());
+        assert!("1.5G".parse::<Bytes>().is_err());
+        assert!("lots".parse::<Bytes>().is_err());
+        assert!("99999999T".parse::<Bytes>().is_err());
+
+        assert_eq!(Bytes(4 << 30).to_string(), "4G");
+        assert_eq!(Bytes(1536 << 20).to_string(), "1536M");
+        assert_eq!(Bytes(1000).to_string(), "1000");
+    }
+
+    #[test]
+    fn test_estimate_from_header() {
+        let dir = TempDir::new("memory");
+        let path = dir.join("in.png");
+        image::RgbaImage::new(30, 20).save(&path).unwrap();
+
+        assert_eq!(estimate(&path, None), 30 * 20 * 4);
+        assert_eq!(estimate(&dir.join("missing.png"), None), 0);
+
+        // Every selected page of a TIFF counts
+        let frames: Vec<Frame> = [(30, 20), (10, 10), (40, 5)]
+            .into_iter()
+            .zip(1..)
+            .map(|((width, height), page)| Frame {
+                page,
+                image: image::DynamicImage::ImageRgb8(image::RgbImage::new(width, height)),
+                delay: None,
+            })
+            .collect();
+        let path = dir.join("pages.tif");
+        Encoding::default()
+            .save_frames(&frames, &path, &Metadata::default())
+            .unwrap();
+        assert_eq!(estimate(&path, None), (600 + 100 + 200) * 3);
+        let selected: Pages = "2-".parse().unwrap();
+        assert_eq!(estimate(&path, Some(&selected)), (100 + 200) * 3);
+    }
+
+    #[test]
+    fn test_reservations_wait_for_room() {
+        let budget = MemoryBudget::new(Bytes(100));
+        let first = budget.reserve(60);
+        let (sender, receiver) = mpsc::channel();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let _second = budget.reserve(60);
+                sender.send(()).unwrap();
+            });
+            assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+            drop(first);
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        });
+
+        // Oversized reservations take the whole budget instead of waiting forever
+        let whole = budget.reserve(1000);
+        assert_eq!(whole.bytes, 100);
+        drop(whole);
+        assert_eq!(*budget.used.lock().unwrap(), 0);
+    }
+}
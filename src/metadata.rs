@@ -0,0 +1,845 @@
+//! Image metadata carried over from each input into its crops.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+
+use crate::frames::{self, Decoded, Pages};
+
+/// Centimetres per inch, for converting metric resolutions.
+const CM_PER_INCH: f64 = 2.54;
+
+/// Physical resolution in pixels per inch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Resolution {
+    pub(crate) x: f64,
+    pub(crate) y: f64,
+}
+
+/// Metadata of a source image that applies to every crop of it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Metadata {
+    /// Embedded ICC color profile
+    pub(crate) icc_profile: Option<Vec<u8>>,
+    /// Raw TIFF-structured EXIF block, without the JPEG `Exif\0\0` header
+    pub(crate) exif: Option<Vec<u8>>,
+    /// XMP packet
+    pub(crate) xmp: Option<Vec<u8>>,
+    pub(crate) resolution: Option<Resolution>,
+    /// The pixels are CMYK samples, held in RGBA-shaped images
+    pub(crate) cmyk: bool,
+}
+
+impl Metadata {
+    /// Update the metadata for an image that has had `orientation` applied,
+    /// returning whether it swapped the image's width and height.
+    fn apply_orientation(&mut self, orientation: Orientation) -> bool {
+        if let Some(exif) = &mut self.exif {
+            let _ = Orientation::remove_from_exif_chunk(exif);
+        }
+        let transposed = matches!(
+            orientation,
+            Orientation::Rotate90
+                | Orientation::Rotate270
+                | Orientation::Rotate90FlipH
+                | Orientation::Rotate270FlipH
+        );
+        if let Some(resolution) = &mut self.resolution {
+            if transposed {
+                std::mem::swap(&mut resolution.x, &mut resolution.y);
+            }
+        }
+        transposed
+    }
+
+    /// The source EXIF with its pixel dimensions updated for a crop of the
+    /// given size.
+    pub(crate) fn exif_for(&self, width: u32, height: u32) -> Option<Vec<u8>> {
+        let mut exif = self.exif.clone()?;
+        set_exif_dimensions(&mut exif, width, height);
+        Some(exif)
+    }
+}
+
+/// Decode the pages or frames of an image that `selected` picks, or all of
+/// them, along with the metadata to carry over into its crops. The metadata
+/// is that of the first page. Only TIFFs skip decoding unselected pages.
+///
+/// With `auto_orient`, the frames are rotated and flipped as the EXIF or TIFF
+/// orientation tag says, so that it matches what a viewer displays, and the
+/// carried EXIF orientation is reset. Metadata is best effort: unreadable
+/// metadata is dropped rather than failing the whole image.
+pub(crate) fn open(
+    path: &Path,
+    auto_orient: bool,
+    selected: Option<&Pages>,
+) -> Result<(Decoded, Metadata)> {
+    let open_error = || format!("Unable to open image '{}'", path.display());
+    let reader = ImageReader::open(path).with_context(open_error)?;
+    let format = reader.format();
+
+    let mut metadata = Metadata::default();
+    let (mut decoded, orientation) = if format == Some(ImageFormat::Tiff) {
+        let (decoded, cmyk) = frames::read_tiff(path, selected)?;
+        metadata.cmyk = cmyk;
+        (decoded, read_tiff_metadata(path, &mut metadata))
+    } else {
+        let mut decoder = reader.into_decoder().with_context(open_error)?;
+        let orientation = read_decoder_metadata(&mut decoder, &mut metadata);
+        // Animations are decoded frame by frame instead of by `decoder`, which
+        // only decodes the first
+        let frames = match frames::read_animation(path, format)? {
+            Some(frames) => frames,
+            None => vec![frames::still(
+                DynamicImage::from_decoder(decoder).with_context(open_error)?,
+            )],
+        };
+        (Decoded::all(frames), orientation)
+    };
+    metadata.resolution = read_any_resolution(path, format, &metadata);
+
+    if auto_orient && orientation != Orientation::NoTransforms {
+        for frame in &mut decoded.frames {
+            frame.image.apply_orientation(orientation);
+        }
+        if metadata.apply_orientation(orientation) {
+            for page in &mut decoded.pages {
+                std::mem::swap(&mut page.width, &mut page.height);
+            }
+        }
+    }
+    Ok((decoded, metadata))
+}
+
+/// Read the metadata to carry over into an image's crops and its orientation,
+/// without decoding any pixels. Whether a TIFF is CMYK is left unset.
+pub(crate) fn read(path: &Path) -> Result<(Metadata, Orientation)> {
+    let open_error = || format!("Unable to open image '{}'", path.display());
+    let reader = ImageReader::open(path).with_context(open_error)?;
+    let format = reader.format();
+
+    let mut metadata = Metadata::default();
+    let orientation = if format == Some(ImageFormat::Tiff) {
+        read_tiff_metadata(path, &mut metadata)
+    } else {
+        let mut decoder = reader.into_decoder().with_context(open_error)?;
+        read_decoder_metadata(&mut decoder, &mut metadata)
+    };
+    metadata.resolution = read_any_resolution(path, format, &metadata);
+    Ok((metadata, orientation))
+}
+
+/// Take the ICC profile, EXIF and XMP from an image crate decoder, returning
+/// its orientation.
+fn read_decoder_metadata(decoder: &mut impl ImageDecoder, metadata: &mut Metadata) -> Orientation {
+    metadata.icc_profile = decoder.icc_profile().ok().flatten();
+    metadata.exif = decoder.exif_metadata().ok().flatten();
+    metadata.xmp = decoder.xmp_metadata().ok().flatten();
+    decoder.orientation().unwrap_or(Orientation::NoTransforms)
+}
+
+/// The resolution from the file's own headers, or else from its EXIF.
+fn read_any_resolution(
+    path: &Path,
+    format: Option<ImageFormat>,
+    metadata: &Metadata,
+) -> Option<Resolution> {
+    format
+        .and_then(|format| read_resolution(path, format))
+        .or_else(|| metadata.exif.as_deref().and_then(exif_resolution))
+}
+
+/// Read the resolution stored in an image file's own headers.
+fn read_resolution(path: &Path, format: ImageFormat) -> Option<Resolution> {
+    let file = File::open(path).ok()?;
+    match format {
+        ImageFormat::Png => {
+            let reader = png::Decoder::new(BufReader::new(file)).read_info().ok()?;
+            let dims = reader.info().pixel_dims?;
+            (dims.unit == png::Unit::Meter).then(|| Resolution {
+                x: f64::from(dims.xppu) * CM_PER_INCH / 100.0,
+                y: f64::from(dims.yppu) * CM_PER_INCH / 100.0,
+            })
+        }
+        ImageFormat::Jpeg => {
+            // JFIF APP0 segment directly after SOI
+            let mut header = [0u8; 18];
+            BufReader::new(file).read_exact(&mut header).ok()?;
+            if header[..4] != [0xFF, 0xD8, 0xFF, 0xE0] || &header[6..11] != b"JFIF\0" {
+                return None;
+            }
+            let x = f64::from(u16::from_be_bytes([header[14], header[15]]));
+            let y = f64::from(u16::from_be_bytes([header[16], header[17]]));
+            match header[13] {
+                1 => Some(Resolution { x, y }),
+                2 => Some(Resolution {
+                    x: x * CM_PER_INCH,
+                    y: y * CM_PER_INCH,
+                }),
+                _ => None,
+            }
+        }
+        ImageFormat::Tiff => {
+            use tiff::decoder::ifd::Value;
+            use tiff::tags::Tag;
+
+            let mut decoder = tiff::decoder::Decoder::new(BufReader::new(file)).ok()?;
+            let mut rational = |tag| match decoder.find_tag(tag).ok()?? {
+                Value::Rational(n, d) if n > 0 && d > 0 => Some(f64::from(n) / f64::from(d)),
+                _ => None,
+            };
+            let x = rational(Tag::XResolution)?;
+            let y = rational(Tag::YResolution)?;
+            match decoder.find_tag_unsigned::<u16>(Tag::ResolutionUnit).ok()? {
+                Some(2) | None => Some(Resolution { x, y }),
+                Some(3) => Some(Resolution {
+                    x: x * CM_PER_INCH,
+                    y: y * CM_PER_INCH,
+                }),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Read the ICC profile, EXIF and XMP of a TIFF, returning its
+/// orientation. The image crate's decoder looks the ICC profile up under a tag
+/// number that never matches, and its buffer limits reject XMP packets stored
+/// out of line.
+fn read_tiff_metadata(path: &Path, metadata: &mut Metadata) -> Orientation {
+    use tiff::tags::Tag;
+
+    let Some(mut decoder) = File::open(path)
+        .ok()
+        .and_then(|file| tiff::decoder::Decoder::new(BufReader::new(file)).ok())
+    else {
+        return Orientation::NoTransforms;
+    };
+    let mut bytes = |tag| decoder.find_tag(tag).ok()??.into_u8_vec().ok();
+    metadata.icc_profile = bytes(Tag::IccProfile);
+    // The EXIF tags live in the TIFF's own IFDs rather than in a block
+    metadata.exif = File::open(path)
+        .ok()
+        .and_then(|file| ExifTags::read(&mut BufReader::new(file)))
+        .map(|tags| tags.to_block());
+    // XMLPacket
+    metadata.xmp = bytes(Tag::Unknown(700));
+    decoder
+        .find_tag_unsigned::<u16>(Tag::Orientation)
+        .ok()
+        .flatten()
+        .and_then(|value| Orientation::from_exif(value.min(255) as u8))
+        .unwrap_or(Orientation::NoTransforms)
+}
+
+// EXIF tags used below
+const TAG_IMAGE_WIDTH: u16 = 0x0100;
+const TAG_IMAGE_LENGTH: u16 = 0x0101;
+const TAG_X_RESOLUTION: u16 = 0x011A;
+const TAG_Y_RESOLUTION: u16 = 0x011B;
+const TAG_RESOLUTION_UNIT: u16 = 0x0128;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_INTEROP_IFD: u16 = 0xA005;
+const TAG_PIXEL_X_DIMENSION: u16 = 0xA002;
+const TAG_PIXEL_Y_DIMENSION: u16 = 0xA003;
+
+// EXIF value types used below
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+const TYPE_IFD: u16 = 13;
+
+/// Read-only view of a TIFF-structured EXIF block.
+struct Exif<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Exif<'a> {
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..4)? {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+        Some(Exif { data, big_endian })
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Offset of the first IFD.
+    fn ifd0(&self) -> Option<usize> {
+        Some(self.u32(4)? as usize)
+    }
+
+    /// Offset of the Exif sub-IFD.
+    fn exif_ifd(&self) -> Option<usize> {
+        let entry = self.find(self.ifd0()?, TAG_EXIF_IFD)?;
+        Some(self.unsigned(entry)? as usize)
+    }
+
+    /// Offset of the 12-byte entry for `tag` in the IFD at `ifd`.
+    fn find(&self, ifd: usize, tag: u16) -> Option<usize> {
+        let count = usize::from(self.u16(ifd)?);
+        (0..count)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| self.u16(entry) == Some(tag))
+    }
+
+    /// Value of a single SHORT or LONG entry.
+    fn unsigned(&self, entry: usize) -> Option<u32> {
+        match self.u16(entry + 2)? {
+            TYPE_SHORT => self.u16(entry + 8).map(u32::from),
+            TYPE_LONG => self.u32(entry + 8),
+            _ => None,
+        }
+    }
+
+    /// Value of a single RATIONAL entry.
+    fn rational(&self, entry: usize) -> Option<f64> {
+        if self.u16(entry + 2)? != TYPE_RATIONAL {
+            return None;
+        }
+        let offset = self.u32(entry + 8)? as usize;
+        let (n, d) = (self.u32(offset)?, self.u32(offset + 4)?);
+        (n > 0 && d > 0).then(|| f64::from(n) / f64::from(d))
+    }
+}
+
+/// Resolution recorded in EXIF, which defaults to inches.
+fn exif_resolution(exif: &[u8]) -> Option<Resolution> {
+    let exif = Exif::parse(exif)?;
+    let ifd0 = exif.ifd0()?;
+    let x = exif.rational(exif.find(ifd0, TAG_X_RESOLUTION)?)?;
+    let y = exif.rational(exif.find(ifd0, TAG_Y_RESOLUTION)?)?;
+    let unit = exif
+        .find(ifd0, TAG_RESOLUTION_UNIT)
+        .and_then(|entry| exif.unsigned(entry));
+    match unit {
+        Some(2) | None => Some(Resolution { x, y }),
+        Some(3) => Some(Resolution {
+            x: x * CM_PER_INCH,
+            y: y * CM_PER_INCH,
+        }),
+        _ => None,
+    }
+}
+
+/// Update the image dimensions recorded in EXIF, in place.
+///
+/// Only existing entries are changed. A SHORT entry that cannot hold the new
+/// value is left alone.
+pub(crate) fn set_exif_dimensions(data: &mut [u8], width: u32, height: u32) {
+    let (big_endian, entries) = {
+        let Some(exif) = Exif::parse(data) else {
+            return;
+        };
+        let mut entries = Vec::new();
+        if let Some(ifd0) = exif.ifd0() {
+            entries.push((exif.find(ifd0, TAG_IMAGE_WIDTH), width));
+            entries.push((exif.find(ifd0, TAG_IMAGE_LENGTH), height));
+        }
+        if let Some(ifd) = exif.exif_ifd() {
+            entries.push((exif.find(ifd, TAG_PIXEL_X_DIMENSION), width));
+            entries.push((exif.find(ifd, TAG_PIXEL_Y_DIMENSION), height));
+        }
+        let entries: Vec<_> = entries
+            .into_iter()
+            .filter_map(|(entry, value)| Some((entry?, exif.u16(entry? + 2)?, value)))
+            .collect();
+        (exif.big_endian, entries)
+    };
+
+    for (entry, value_type, value) in entries {
+        let bytes = match (value_type, u16::try_from(value)) {
+            (TYPE_SHORT, Ok(short)) if big_endian => short.to_be_bytes().to_vec(),
+            (TYPE_SHORT, Ok(short)) => short.to_le_bytes().to_vec(),
+            (TYPE_LONG, _) if big_endian => value.to_be_bytes().to_vec(),
+            (TYPE_LONG, _) => value.to_le_bytes().to_vec(),
+            _ => continue,
+        };
+        // A block cut short after the entry's type has no value to update
+        if let Some(value) = data.get_mut(entry + 8..entry + 8 + bytes.len()) {
+            value.copy_from_slice(&bytes);
+        }
+    }
+}
+
+/// An IFD entry with its value bytes, in the byte order of its source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RawEntry {
+    pub(crate) tag: u16,
+    pub(crate) value_type: u16,
+    pub(crate) count: u32,
+    pub(crate) data: Vec<u8>,
+}
+
+/// The EXIF tags of a TIFF structure, which is either an EXIF block or a
+/// TIFF file: the descriptive tags of IFD0 and the whole Exif IFD.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ExifTags {
+    pub(crate) big_endian: bool,
+    pub(crate) ifd0: Vec<RawEntry>,
+    pub(crate) exif: Vec<RawEntry>,
+}
+
+impl ExifTags {
+    /// Read the EXIF tags of an EXIF block or TIFF file, or `None` if it has
+    /// none. Pointers to further IFDs are dropped, and so is any entry whose
+    /// value cannot be read.
+    pub(crate) fn read(reader: &mut (impl Read + Seek)) -> Option<Self> {
+        let mut header = [0u8; 8];
+        reader.seek(SeekFrom::Start(0)).ok()?;
+        reader.read_exact(&mut header).ok()?;
+        let big_endian = match &header[..4] {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+        let ifd0 = read_ifd(reader, big_endian, read_u32(&header[4..], big_endian))?;
+        let exif = ifd0
+            .iter()
+            .find(|entry| entry.tag == TAG_EXIF_IFD && entry.data.len() == 4)
+            .and_then(|entry| read_ifd(reader, big_endian, read_u32(&entry.data, big_endian)))
+            .unwrap_or_default();
+
+        let tags = ExifTags {
+            big_endian,
+            ifd0: ifd0
+                .into_iter()
+                .filter(|entry| IFD0_EXIF_TAGS.contains(&entry.tag))
+                .collect(),
+            exif: exif
+                .into_iter()
+                .filter(|entry| entry.tag != TAG_INTEROP_IFD && entry.value_type != TYPE_IFD)
+                .collect(),
+        };
+        (!tags.ifd0.is_empty() || !tags.exif.is_empty()).then_some(tags)
+    }
+
+    /// The same tags with their values in the given byte order.
+    pub(crate) fn with_byte_order(mut self, big_endian: bool) -> Self {
+        if big_endian != self.big_endian {
+            for entry in self.ifd0.iter_mut().chain(&mut self.exif) {
+                let unit = type_size(entry.value_type).map_or(1, |(_, unit)| unit);
+                for value in entry.data.chunks_exact_mut(unit) {
+                    value.reverse();
+                }
+            }
+            self.big_endian = big_endian;
+        }
+        self
+    }
+
+    /// Serialize the tags as an EXIF block: a TIFF header, IFD0 and, if
+    /// there are Exif tags, the Exif IFD.
+    pub(crate) fn to_block(&self) -> Vec<u8> {
+        let mut ifd0 = self.ifd0.clone();
+        if !self.exif.is_empty() {
+            // The offset is filled in once the Exif IFD's position is known
+            ifd0.push(RawEntry {
+                tag: TAG_EXIF_IFD,
+                value_type: TYPE_LONG,
+                count: 1,
+                data: vec![0; 4],
+            });
+        }
+        ifd0.sort_by_key(|entry| entry.tag);
+
+        let mut block = Vec::new();
+        block.extend(if self.big_endian { b"MM\0*" } else { b"II*\0" });
+        block.extend(write_u32(8, self.big_endian));
+        let fields = write_ifd(&mut block, &ifd0, self.big_endian);
+        if !self.exif.is_empty() {
+            let mut exif = self.exif.clone();
+            exif.sort_by_key(|entry| entry.tag);
+            let offset = write_u32(block.len() as u32, self.big_endian);
+            let pointer = ifd0.iter().position(|entry| entry.tag == TAG_EXIF_IFD);
+            if let Some(field) = pointer.map(|i| fields[i]) {
+                block[field..field + 4].copy_from_slice(&offset);
+            }
+            write_ifd(&mut block, &exif, self.big_endian);
+        }
+        block
+    }
+}
+
+/// Descriptive IFD0 tags carried between EXIF blocks and TIFF files:
+/// ImageDescription, Make, Model, Orientation, Software, DateTime, Artist and
+/// Copyright. The rest of a TIFF's IFD0 describes its pixel data.
+const IFD0_EXIF_TAGS: [u16; 8] = [
+    0x010E, 0x010F, 0x0110, 0x0112, 0x0131, 0x0132, 0x013B, 0x8298,
+];
+
+/// Largest value copied from an IFD entry, so that a corrupt count cannot
+/// exhaust memory.
+const MAX_VALUE_BYTES: usize = 1 << 20;
+
+/// Size of one value of an IFD field type, and of the units its bytes are
+/// swapped in between byte orders, or `None` for unknown types.
+fn type_size(value_type: u16) -> Option<(usize, usize)> {
+    match value_type {
+        // BYTE, ASCII, SBYTE, UNDEFINED
+        1 | 2 | 6 | 7 => Some((1, 1)),
+        // SHORT, SSHORT
+        3 | 8 => Some((2, 2)),
+        // LONG, SLONG, FLOAT, IFD
+        4 | 9 | 11 | 13 => Some((4, 4)),
+        // RATIONAL, SRATIONAL: two LONGs
+        5 | 10 => Some((8, 4)),
+        // DOUBLE
+        12 => Some((8, 8)),
+        _ => None,
+    }
+}
+
+fn read_u16(bytes: &[u8], big_endian: bool) -> u16 {
+    let bytes = [bytes[0], bytes[1]];
+    if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    }
+}
+
+fn read_u32(bytes: &[u8], big_endian: bool) -> u32 {
+    let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+fn write_u16(value: u16, big_endian: bool) -> [u8; 2] {
+    if big_endian {
+        value.to_be_bytes()
+    } else {
+        value.to_le_bytes()
+    }
+}
+
+fn write_u32(value: u32, big_endian: bool) -> [u8; 4] {
+    if big_endian {
+        value.to_be_bytes()
+    } else {
+        value.to_le_bytes()
+    }
+}
+
+/// Read the entries of the IFD at `offset`, skipping those of unknown types
+/// and those whose values are out of reach.
+fn read_ifd(
+    reader: &mut (impl Read + Seek),
+    big_endian: bool,
+    offset: u32,
+) -> Option<Vec<RawEntry>> {
+    let mut count = [0u8; 2];
+    reader.seek(SeekFrom::Start(offset.into())).ok()?;
+    reader.read_exact(&mut count).ok()?;
+    let mut fields = vec![0u8; usize::from(read_u16(&count, big_endian)) * 12];
+    reader.read_exact(&mut fields).ok()?;
+
+    let mut entries = Vec::new();
+    for field in fields.chunks_exact(12) {
+        let value_type = read_u16(&field[2..], big_endian);
+        let count = read_u32(&field[4..], big_endian);
+        let Some(len) = type_size(value_type)
+            .and_then(|(size, _)| size.checked_mul(count as usize))
+            .filter(|&len| len <= MAX_VALUE_BYTES)
+        else {
+            continue;
+        };
+        let data = if len <= 4 {
+            field[8..8 + len].to_vec()
+        } else {
+            let mut data = vec![0u8; len];
+            let offset = read_u32(&field[8..], big_endian);
+            let read = reader
+                .seek(SeekFrom::Start(offset.into()))
+                .and_then(|_| reader.read_exact(&mut data));
+            if read.is_err() {
+                continue;
+            }
+            data
+        };
+        entries.push(RawEntry {
+            tag: read_u16(field, big_endian),
+            value_type,
+            count,
+            data,
+        });
+    }
+    Some(entries)
+}
+
+/// Append an IFD and its out-of-line values to `block`, returning the
+/// position of each entry's value field.
+fn write_ifd(block: &mut Vec<u8>, entries: &[RawEntry], big_endian: bool) -> Vec<usize> {
+    // IFDs and values start on word boundaries
+    if block.len() % 2 == 1 {
+        block.push(0);
+    }
+    let mut value_offset = block.len() + 2 + entries.len() * 12 + 4;
+    let mut fields = Vec::with_capacity(entries.len());
+    block.extend(write_u16(entries.len() as u16, big_endian));
+    for entry in entries {
+        block.extend(write_u16(entry.tag, big_endian));
+        block.extend(write_u16(entry.value_type, big_endian));
+        block.extend(write_u32(entry.count, big_endian));
+        fields.push(block.len());
+        if entry.data.len() <= 4 {
+            let mut value = [0u8; 4];
+            value[..entry.data.len()].copy_from_slice(&entry.data);
+            block.extend(value);
+        } else {
+            block.extend(write_u32(value_offset as u32, big_endian));
+            value_offset += entry.data.len().next_multiple_of(2);
+        }
+    }
+    // No next IFD
+    block.extend([0; 4]);
+    for entry in entries.iter().filter(|entry| entry.data.len() > 4) {
+        block.extend(&entry.data);
+        if entry.data.len() % 2 == 1 {
+            block.push(0);
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    /// Build an EXIF block with IFD0 holding a 300/1 dpi resolution and an
+    /// Exif IFD holding a SHORT PixelXDimension and a LONG PixelYDimension.
+    fn sample_exif(big_endian: bool) -> Vec<u8> {
+        let u16b = |v: u16| {
+            if big_endian {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            }
+        };
+        let u32b = |v: u32| {
+            if big_endian {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            }
+        };
+        let entry = |tag: u16, value_type: u16, value: [u8; 4]| {
+            let mut e = Vec::new();
+            e.extend(u16b(tag));
+            e.extend(u16b(value_type));
+            e.extend(u32b(1));
+            e.extend(value);
+            e
+        };
+        let short = |v: u16| {
+            let mut value = [0u8; 4];
+            value[..2].copy_from_slice(&u16b(v));
+            value
+        };
+
+        // Layout: header (8), IFD0 with 4 entries (2 + 48 + 4) at 8,
+        // rationals at 62 and 70, Exif IFD with 2 entries at 78
+        let mut data = Vec::new();
+        data.extend(if big_endian { b"MM\0*" } else { b"II*\0" });
+        data.extend(u32b(8));
+        data.extend(u16b(4));
+        data.extend(entry(TAG_X_RESOLUTION, TYPE_RATIONAL, u32b(62)));
+        data.extend(entry(TAG_Y_RESOLUTION, TYPE_RATIONAL, u32b(70)));
+        data.extend(entry(TAG_RESOLUTION_UNIT, TYPE_SHORT, short(2)));
+        data.extend(entry(TAG_EXIF_IFD, TYPE_LONG, u32b(78)));
+        data.extend(u32b(0));
+        data.extend(u32b(300));
+        data.extend(u32b(1));
+        data.extend(u32b(600));
+        data.extend(u32b(2));
+        data.extend(u16b(2));
+        data.extend(entry(TAG_PIXEL_X_DIMENSION, TYPE_SHORT, short(4000)));
+        data.extend(entry(TAG_PIXEL_Y_DIMENSION, TYPE_LONG, u32b(3000)));
+        data.extend(u32b(0));
+        data
+    }
+
+    fn dimensions(data: &[u8]) -> (u32, u32) {
+        let exif = Exif::parse(data).unwrap();
+        let ifd = exif.exif_ifd().unwrap();
+        let x = exif.unsigned(exif.find(ifd, TAG_PIXEL_X_DIMENSION).unwrap());
+        let y = exif.unsigned(exif.find(ifd, TAG_PIXEL_Y_DIMENSION).unwrap());
+        (x.unwrap(), y.unwrap())
+    }
+
+    #[test]
+    fn test_exif_resolution() {
+        for big_endian in [false, true] {
+            let resolution = exif_resolution(&sample_exif(big_endian)).unwrap();
+            assert_eq!(resolution, Resolution { x: 300.0, y: 300.0 });
+        }
+        assert_eq!(exif_resolution(b"not exif"), None);
+    }
+
+    #[test]
+    fn test_set_exif_dimensions() {
+        for big_endian in [false, true] {
+            let mut data = sample_exif(big_endian);
+            assert_eq!(dimensions(&data), (4000, 3000));
+            set_exif_dimensions(&mut data, 640, 480);
+            assert_eq!(dimensions(&data), (640, 480));
+        }
+    }
+
+    #[test]
+    fn test_set_exif_dimensions_short_overflow() {
+        let mut data = sample_exif(false);
+        set_exif_dimensions(&mut data, 70_000, 70_000);
+        // The SHORT width cannot hold the value, the LONG height can
+        assert_eq!(dimensions(&data), (4000, 70_000));
+    }
+
+    #[test]
+    fn test_set_exif_dimensions_truncated() {
+        let mut data = sample_exif(false);
+        data.truncate(60);
+        let original = data.clone();
+        set_exif_dimensions(&mut data, 640, 480);
+        assert_eq!(data, original);
+
+        // Cut inside the value of the last Exif IFD entry
+        let mut data = sample_exif(true);
+        data.truncate(96);
+        set_exif_dimensions(&mut data, 640, 480);
+        assert_eq!(&data[88..90], &640u16.to_be_bytes());
+        assert_eq!(data.len(), 96);
+    }
+
+    #[test]
+    fn test_exif_tags_round_trip() {
+        use std::io::Cursor;
+
+        for big_endian in [false, true] {
+            let tags = ExifTags::read(&mut Cursor::new(sample_exif(big_endian))).unwrap();
+            // IFD0 holds only resolution tags, which TIFFs store themselves
+            assert!(tags.ifd0.is_empty());
+            assert_eq!(tags.exif.len(), 2);
+
+            let block = tags.to_block();
+            assert_eq!(dimensions(&block), (4000, 3000));
+            assert_eq!(ExifTags::read(&mut Cursor::new(block)).unwrap(), tags);
+
+            let swapped = tags.with_byte_order(!big_endian).to_block();
+            assert_eq!(&swapped[..2], if big_endian { b"II" } else { b"MM" });
+            assert_eq!(dimensions(&swapped), (4000, 3000));
+        }
+        assert_eq!(ExifTags::read(&mut Cursor::new(b"not exif")), None);
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        use crate::output::{Encoding, OutputFormat};
+
+        let dir = TempDir::new("metadata");
+        let metadata = Metadata {
+            icc_profile: Some(b"fake icc profile".to_vec()),
+            exif: Some(sample_exif(false)),
+            xmp: Some(b"<x:xmpmeta xmlns:x='adobe:ns:meta/'/>".to_vec()),
+            resolution: Some(Resolution { x: 300.0, y: 150.0 }),
+            cmyk: false,
+        };
+        let img = DynamicImage::ImageRgb8(image::RgbImage::new(8, 6));
+
+        for format in [OutputFormat::Png, OutputFormat::Jpeg, OutputFormat::Tiff] {
+            let path = dir.join(format!("out.{}", format.extension()));
+            Encoding::default().save(&img, &path, &metadata).unwrap();
+            let (_, read) = open(&path, true, None).unwrap();
+
+            assert_eq!(read.icc_profile, metadata.icc_profile, "{format}");
+            assert_eq!(read.xmp, metadata.xmp, "{format}");
+            let resolution = read.resolution.unwrap();
+            assert!((resolution.x - 300.0).abs() < 0.5, "{format}");
+            assert!((resolution.y - 150.0).abs() < 0.5, "{format}");
+            assert_eq!(dimensions(&read.exif.unwrap()), (8, 6), "{format}");
+        }
+    }
+
+    #[test]
+    fn test_open_auto_orient() {
+        use crate::output::Encoding;
+
+        let dir = TempDir::new("orient");
+        let path = dir.join("rotated.png");
+
+        // IFD0 with a single Orientation entry: rotate 90 degrees clockwise
+        let mut exif = b"II*\0\x08\0\0\0\x01\0".to_vec();
+        exif.extend([
+            0x12, 0x01, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00,
+        ]);
+        exif.extend([0; 4]);
+        let metadata = Metadata {
+            exif: Some(exif),
+            resolution: Some(Resolution { x: 300.0, y: 100.0 }),
+            ..Metadata::default()
+        };
+        // 4x2 image whose top-left pixel is white
+        let mut img = image::GrayImage::new(4, 2);
+        img.put_pixel(0, 0, image::Luma([255]));
+        Encoding::default()
+            .save(&DynamicImage::ImageLuma8(img), &path, &metadata)
+            .unwrap();
+
+        let (raw, raw_metadata) = open(&path, false, None).unwrap();
+        let raw = &raw.frames[0].image;
+        assert_eq!((raw.width(), raw.height()), (4, 2));
+        assert_eq!(
+            Orientation::from_exif_chunk(raw_metadata.exif.as_deref().unwrap()),
+            Some(Orientation::Rotate90)
+        );
+
+        let (oriented, oriented_metadata) = open(&path, true, None).unwrap();
+        let oriented = &oriented.frames[0].image;
+        assert_eq!((oriented.width(), oriented.height()), (2, 4));
+        // Rotating clockwise moves the top-left pixel to the top-right
+        assert_eq!(oriented.to_luma8().get_pixel(1, 0), &image::Luma([255]));
+        assert_eq!(
+            Orientation::from_exif_chunk(oriented_metadata.exif.as_deref().unwrap()),
+            Some(Orientation::NoTransforms)
+        );
+        let resolution = oriented_metadata.resolution.unwrap();
+        assert!((resolution.x - 100.0).abs() < 0.5 && (resolution.y - 300.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_metadata_exif_for() {
+        let metadata = Metadata {
+            exif: Some(sample_exif(true)),
+            ..Metadata::default()
+        };
+        assert_eq!(dimensions(&metadata.exif_for(10, 20).unwrap()), (10, 20));
+        assert_eq!(Metadata::default().exif_for(10, 20), None);
+    }
+}
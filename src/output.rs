@@ -0,0 +1,1739 @@
+//! Output path templates, image encoding and output conflict handling.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Cursor, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use image::codecs::bmp::BmpEncoder;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::codecs::jpeg::{JpegEncoder, PixelDensity, PixelDensityUnit};
+use image::codecs::webp::WebPEncoder;
+use image::{Delay, DynamicImage, GenericImageView, ImageEncoder};
+
+use crate::frames::Frame;
+use crate::metadata::{ExifTags, Metadata, RawEntry};
+use crate::Rect;
+
+/// Template used when no --out-template is given.
+pub(crate) const DEFAULT_TEMPLATE: &str = "{dir}/{stem}_{name}.{ext}";
+
+/// A value that can be substituted into an output template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    /// Output directory: --output, or the input's directory
+    Dir,
+    /// Input file name without its extension
+    Stem,
+    /// Capture name
+    Name,
+    /// Position of the capture among the image's captures, from zero
+    Index,
+    /// Page or frame number, from 1
+    Page,
+    /// Left edge of the resolved rectangle
+    X,
+    /// Top edge of the resolved rectangle
+    Y,
+    /// Width of the resolved rectangle
+    W,
+    /// Height of the resolved rectangle
+    H,
+    /// Output file extension
+    Ext,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dir" => Some(Field::Dir),
+            "stem" => Some(Field::Stem),
+            "name" => Some(Field::Name),
+            "index" => Some(Field::Index),
+            "page" => Some(Field::Page),
+            "x" => Some(Field::X),
+            "y" => Some(Field::Y),
+            "w" => Some(Field::W),
+            "h" => Some(Field::H),
+            "ext" => Some(Field::Ext),
+            _ => None,
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(
+            self,
+            Field::Index | Field::Page | Field::X | Field::Y | Field::W | Field::H
+        )
+    }
+}
+
+/// One piece of a parsed template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// A placeholder, with an optional zero-padded width for numeric fields
+    Field(Field, Option<usize>),
+}
+
+/// Values describing one output file.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct OutputFields<'a> {
+    pub(crate) stem: &'a str,
+    pub(crate) ext: &'a str,
+    pub(crate) name: &'a str,
+    pub(crate) index: usize,
+    pub(crate) page: u32,
+    pub(crate) rect: Rect,
+}
+
+/// Image format of the files written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+    Tiff,
+    Bmp,
+    Gif,
+}
+
+impl OutputFormat {
+    /// Format matching a file extension, if it is one we can write.
+    pub(crate) fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "jpg" | "jpeg" => Some(OutputFormat::Jpeg),
+            "webp" => Some(OutputFormat::Webp),
+            "tif" | "tiff" => Some(OutputFormat::Tiff),
+            "bmp" => Some(OutputFormat::Bmp),
+            "gif" => Some(OutputFormat::Gif),
+            _ => None,
+        }
+    }
+
+    /// File extension used for this format.
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Tiff => "tif",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::Gif => "gif",
+        }
+    }
+
+    /// Whether one file can hold several pages or frames.
+    pub(crate) fn holds_frames(self) -> bool {
+        matches!(self, OutputFormat::Tiff | OutputFormat::Gif)
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        OutputFormat::from_extension(s).ok_or_else(|| {
+            format!(
+                "Invalid output format '{s}'. Supported values: png, jpeg, webp, tiff, bmp, gif"
+            )
+        })
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Tiff => "tiff",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::Gif => "gif",
+        };
+        f.write_str(name)
+    }
+}
+
+/// PNG compression effort.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum PngCompression {
+    None,
+    #[default]
+    Fast,
+    Default,
+    Best,
+}
+
+impl std::str::FromStr for PngCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(PngCompression::None),
+            "fast" => Ok(PngCompression::Fast),
+            "default" => Ok(PngCompression::Default),
+            "best" => Ok(PngCompression::Best),
+            other => Err(format!(
+                "Invalid PNG compression '{other}'. Supported values: none, fast, default, best"
+            )),
+        }
+    }
+}
+
+/// TIFF compression scheme.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum TiffCompression {
+    #[default]
+    None,
+    Lzw,
+    Deflate,
+    Packbits,
+}
+
+impl std::str::FromStr for TiffCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(TiffCompression::None),
+            "lzw" => Ok(TiffCompression::Lzw),
+            "deflate" | "zip" => Ok(TiffCompression::Deflate),
+            "packbits" => Ok(TiffCompression::Packbits),
+            other => Err(format!(
+                "Invalid TIFF compression '{other}'. Supported values: none, lzw, deflate, packbits"
+            )),
+        }
+    }
+}
+
+/// How output images are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Encoding {
+    /// Format to write; inferred from each output path's extension if unset
+    pub(crate) format: Option<OutputFormat>,
+    /// JPEG quality from 1 to 100
+    pub(crate) jpeg_quality: u8,
+    pub(crate) png_compression: PngCompression,
+    pub(crate) tiff_compression: TiffCompression,
+    /// Convert images the format cannot store exactly, e.g. 16-bit to 8-bit,
+    /// instead of failing
+    pub(crate) allow_downconvert: bool,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding {
+            format: None,
+            jpeg_quality: 90,
+            png_compression: PngCompression::default(),
+            tiff_compression: TiffCompression::default(),
+            allow_downconvert: false,
+        }
+    }
+}
+
+impl Encoding {
+    /// Format used for an output path.
+    pub(crate) fn format_for(&self, out_path: &Path) -> Result<OutputFormat> {
+        if let Some(format) = self.format {
+            return Ok(format);
+        }
+        out_path
+            .extension()
+            .and_then(|ext| OutputFormat::from_extension(&ext.to_string_lossy()))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unable to determine the output format of '{}'. Use --format",
+                    out_path.display()
+                )
+            })
+    }
+
+    /// Encode an image with the source's metadata and atomically write it
+    /// to `out_path`.
+    pub(crate) fn save(
+        &self,
+        img: &DynamicImage,
+        out_path: &Path,
+        metadata: &Metadata,
+    ) -> Result<()> {
+        let format = self.format_for(out_path)?;
+        let img = encodable(img, format, metadata.cmyk, self.allow_downconvert)?;
+        write_atomic(out_path, |writer| {
+            self.encode(&img, format, metadata, writer)
+                .with_context(|| format!("Unable to encode {format} image"))
+        })
+    }
+
+    /// Encode an image, writing as much of the metadata as the format holds.
+    fn encode<W: Write + Seek>(
+        &self,
+        img: &DynamicImage,
+        format: OutputFormat,
+        metadata: &Metadata,
+        writer: &mut W,
+    ) -> Result<()> {
+        let (width, height) = img.dimensions();
+        let icc_profile = metadata.icc_profile.clone();
+        let exif = metadata.exif_for(width, height);
+        match format {
+            OutputFormat::Png => write_png(img, self.png_compression, metadata, exif, writer)?,
+            OutputFormat::Jpeg => {
+                let mut jpeg = Vec::new();
+                let mut encoder = JpegEncoder::new_with_quality(&mut jpeg, self.jpeg_quality);
+                if let Some(resolution) = metadata.resolution {
+                    encoder.set_pixel_density(PixelDensity {
+                        density: (
+                            resolution.x.round().clamp(1.0, f64::from(u16::MAX)) as u16,
+                            resolution.y.round().clamp(1.0, f64::from(u16::MAX)) as u16,
+                        ),
+                        unit: PixelDensityUnit::Inches,
+                    });
+                }
+                set_metadata(&mut encoder, icc_profile, exif)?;
+                img.write_with_encoder(encoder)?;
+                if let Some(xmp) = &metadata.xmp {
+                    jpeg = insert_jpeg_xmp(jpeg, xmp);
+                }
+                writer.write_all(&jpeg)?;
+            }
+            OutputFormat::Webp => {
+                let mut encoder = WebPEncoder::new_lossless(writer);
+                set_metadata(&mut encoder, icc_profile, exif)?;
+                img.write_with_encoder(encoder)?;
+            }
+            OutputFormat::Bmp => img.write_with_encoder(BmpEncoder::new(writer))?,
+            OutputFormat::Gif => {
+                GifEncoder::new(writer).encode_frame(image::Frame::new(gif_pixels(img)))?
+            }
+            OutputFormat::Tiff => write_tiff(&[img], self.tiff_compression, metadata, writer)?,
+        }
+        Ok(())
+    }
+
+    /// Encode several pages or frames into one multi-page TIFF or animated
+    /// GIF and atomically write it to `out_path`.
+    pub(crate) fn save_frames(
+        &self,
+        frames: &[Frame],
+        out_path: &Path,
+        metadata: &Metadata,
+    ) -> Result<()> {
+        let format = self.format_for(out_path)?;
+        if !format.holds_frames() {
+            return Err(anyhow!("{format} files hold a single image"));
+        }
+        let images = frames
+            .iter()
+            .map(|frame| encodable(&frame.image, format, metadata.cmyk, self.allow_downconvert))
+            .collect::<Result<Vec<_>>>()?;
+        let images: Vec<&DynamicImage> = images.iter().map(AsRef::as_ref).collect();
+        write_atomic(out_path, |writer| {
+            match format {
+                OutputFormat::Gif => write_gif(frames, &images, writer),
+                _ => write_tiff(&images, self.tiff_compression, metadata, writer),
+            }
+            .with_context(|| format!("Unable to encode {format} image"))
+        })
+    }
+}
+
+/// Write an endlessly looping animated GIF. Frames without a delay, such as
+/// TIFF pages, are shown for one second each.
+fn write_gif<W: Write>(frames: &[Frame], images: &[&DynamicImage], writer: &mut W) -> Result<()> {
+    let mut encoder = GifEncoder::new(writer);
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder.encode_frames(frames.iter().zip(images).map(|(frame, img)| {
+        let delay = frame
+            .delay
+            .unwrap_or_else(|| Delay::from_numer_denom_ms(1000, 1));
+        image::Frame::from_parts(gif_pixels(img), 0, 0, delay)
+    }))?;
+    Ok(())
+}
+
+/// Pass the ICC profile and EXIF to an image crate encoder.
+fn set_metadata(
+    encoder: &mut impl ImageEncoder,
+    icc_profile: Option<Vec<u8>>,
+    exif: Option<Vec<u8>>,
+) -> Result<()> {
+    if let Some(icc_profile) = icc_profile {
+        encoder.set_icc_profile(icc_profile)?;
+    }
+    if let Some(exif) = exif {
+        encoder.set_exif_metadata(exif)?;
+    }
+    Ok(())
+}
+
+/// Identifier starting a JPEG APP1 segment that holds XMP.
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// Insert an XMP APP1 segment after the leading APPn segments of a JPEG.
+/// Packets too large for a single segment are left out.
+fn insert_jpeg_xmp(jpeg: Vec<u8>, xmp: &[u8]) -> Vec<u8> {
+    let Ok(length) = u16::try_from(2 + JPEG_XMP_HEADER.len() + xmp.len()) else {
+        return jpeg;
+    };
+
+    // Skip SOI, then every APPn segment
+    let mut pos = 2;
+    while jpeg.len() >= pos + 4 && jpeg[pos] == 0xFF && (0xE0..=0xEF).contains(&jpeg[pos + 1]) {
+        pos += 2 + usize::from(u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]));
+    }
+    let pos = pos.min(jpeg.len());
+
+    let mut out = Vec::with_capacity(jpeg.len() + usize::from(length) + 2);
+    out.extend_from_slice(&jpeg[..pos]);
+    out.extend_from_slice(&[0xFF, 0xE1]);
+    out.extend_from_slice(&length.to_be_bytes());
+    out.extend_from_slice(JPEG_XMP_HEADER);
+    out.extend_from_slice(xmp);
+    out.extend_from_slice(&jpeg[pos..]);
+    out
+}
+
+/// Write a PNG with the png crate directly, since the image crate's encoder
+/// cannot write resolution or XMP.
+fn write_png<W: Write>(
+    img: &DynamicImage,
+    compression: PngCompression,
+    metadata: &Metadata,
+    exif: Option<Vec<u8>>,
+    writer: &mut W,
+) -> Result<()> {
+    use image::ColorType;
+
+    let (color, depth) = match img.color() {
+        ColorType::L8 => (png::ColorType::Grayscale, png::BitDepth::Eight),
+        ColorType::La8 => (png::ColorType::GrayscaleAlpha, png::BitDepth::Eight),
+        ColorType::Rgb8 => (png::ColorType::Rgb, png::BitDepth::Eight),
+        ColorType::Rgba8 => (png::ColorType::Rgba, png::BitDepth::Eight),
+        ColorType::L16 => (png::ColorType::Grayscale, png::BitDepth::Sixteen),
+        ColorType::La16 => (png::ColorType::GrayscaleAlpha, png::BitDepth::Sixteen),
+        ColorType::Rgb16 => (png::ColorType::Rgb, png::BitDepth::Sixteen),
+        ColorType::Rgba16 => (png::ColorType::Rgba, png::BitDepth::Sixteen),
+        other => return Err(anyhow!("PNG output does not support {other:?} images")),
+    };
+
+    let (width, height) = img.dimensions();
+    let mut info = png::Info::with_size(width, height);
+    info.color_type = color;
+    info.bit_depth = depth;
+    info.icc_profile = metadata.icc_profile.as_deref().map(Cow::Borrowed);
+    info.exif_metadata = exif.map(Cow::Owned);
+    info.pixel_dims = metadata.resolution.map(|resolution| png::PixelDimensions {
+        // Pixels per metre
+        xppu: (resolution.x / 0.0254).round() as u32,
+        yppu: (resolution.y / 0.0254).round() as u32,
+        unit: png::Unit::Meter,
+    });
+
+    let mut encoder = png::Encoder::with_info(writer, info)?;
+    encoder.set_compression(match compression {
+        PngCompression::None => png::Compression::NoCompression,
+        PngCompression::Fast => png::Compression::Fast,
+        PngCompression::Default => png::Compression::Balanced,
+        PngCompression::Best => png::Compression::High,
+    });
+    encoder.set_filter(png::Filter::Adaptive);
+    if let Some(xmp) = &metadata.xmp {
+        encoder.add_itxt_chunk(
+            "XML:com.adobe.xmp".to_string(),
+            String::from_utf8_lossy(xmp).into_owned(),
+        )?;
+    }
+
+    let mut writer = encoder.write_header()?;
+    if depth == png::BitDepth::Sixteen {
+        // PNG stores samples big-endian
+        let samples: Vec<u8> = img
+            .as_bytes()
+            .chunks_exact(2)
+            .flat_map(|sample| u16::from_ne_bytes([sample[0], sample[1]]).to_be_bytes())
+            .collect();
+        writer.write_image_data(&samples)?;
+    } else {
+        writer.write_image_data(img.as_bytes())?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Number of temporary files created by this process, to keep names unique.
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Write a file via a temporary file in the same directory that is renamed
+/// into place once complete, so `path` never holds a partially written file.
+/// The temporary file is removed if writing fails.
+pub(crate) fn write_atomic<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<()>,
+{
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Output path '{}' has no file name", path.display()))?
+        .to_string_lossy();
+    let temp_path = path.with_file_name(format!(
+        ".{file_name}.{}.{}.tmp",
+        std::process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)
+        .with_context(|| format!("Unable to create '{}'", temp_path.display()))?;
+    let guard = TempFile(&temp_path);
+
+    let mut writer = BufWriter::new(file);
+    write(&mut writer)?;
+    let file = writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .with_context(|| format!("Unable to write '{}'", temp_path.display()))?;
+    file.sync_all()
+        .with_context(|| format!("Unable to write '{}'", temp_path.display()))?;
+    drop(file);
+
+    std::fs::rename(&temp_path, path).with_context(|| {
+        format!(
+            "Unable to move '{}' to '{}'",
+            temp_path.display(),
+            path.display()
+        )
+    })?;
+    std::mem::forget(guard);
+    Ok(())
+}
+
+/// Removes a temporary file when dropped, unless forgotten after it has been
+/// renamed into place.
+struct TempFile<'a>(&'a Path);
+
+impl Drop for TempFile<'_> {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(self.0);
+    }
+}
+
+/// Convert an image to a pixel layout the format can store, where needed.
+///
+/// JPEG only stores 8-bit gray or RGB, WebP and BMP 8-bit samples, GIF a
+/// palette of 256 8-bit RGB colors with on-off transparency and PNG integer
+/// samples; TIFF stores every layout, including CMYK.
+/// A conversion must be exact, such as dropping an alpha channel that is
+/// fully opaque or 16-bit samples that are multiples of 257, unless
+/// `allow_downconvert` is set. CMYK is converted to RGB for other formats
+/// only when allowed.
+fn encodable(
+    img: &DynamicImage,
+    format: OutputFormat,
+    cmyk: bool,
+    allow_downconvert: bool,
+) -> Result<Cow<'_, DynamicImage>> {
+    use image::ColorType;
+
+    if cmyk {
+        if format == OutputFormat::Tiff {
+            return Ok(Cow::Borrowed(img));
+        }
+        if !allow_downconvert {
+            return Err(anyhow!(
+                "{format} output cannot store CMYK images. Write TIFF, or use --allow-downconvert to convert to RGB"
+            ));
+        }
+        let rgb = cmyk_to_rgb(img);
+        return Ok(Cow::Owned(
+            encodable(&rgb, format, false, true)?.into_owned(),
+        ));
+    }
+
+    let color = img.color();
+    let gray = matches!(
+        color,
+        ColorType::L8 | ColorType::La8 | ColorType::L16 | ColorType::La16
+    );
+    let target = match format {
+        OutputFormat::Tiff => color,
+        OutputFormat::Jpeg if gray => ColorType::L8,
+        OutputFormat::Jpeg => ColorType::Rgb8,
+        OutputFormat::Png => match color {
+            ColorType::Rgb32F => ColorType::Rgb16,
+            ColorType::Rgba32F => ColorType::Rgba16,
+            _ => color,
+        },
+        OutputFormat::Webp | OutputFormat::Bmp => match (gray, color.has_alpha()) {
+            (true, false) => ColorType::L8,
+            (true, true) => ColorType::La8,
+            (false, false) => ColorType::Rgb8,
+            (false, true) => ColorType::Rgba8,
+        },
+        OutputFormat::Gif if color.has_alpha() => ColorType::Rgba8,
+        OutputFormat::Gif => ColorType::Rgb8,
+    };
+    let converted = if target == color {
+        Cow::Borrowed(img)
+    } else {
+        let converted = convert(img, target);
+        // Exact when converting back restores every sample
+        if !allow_downconvert && convert(&converted, color).as_bytes() != img.as_bytes() {
+            return Err(anyhow!(
+                "{format} output cannot store this {color:?} image without losing precision or alpha. Write TIFF, or use --allow-downconvert to convert to {target:?}"
+            ));
+        }
+        Cow::Owned(converted)
+    };
+    if format == OutputFormat::Gif && !allow_downconvert && !fits_gif_palette(&converted) {
+        return Err(anyhow!(
+            "{format} output cannot store this {color:?} image without losing colors or alpha. Write PNG or TIFF, or use --allow-downconvert to reduce it to a 256-color palette"
+        ));
+    }
+    Ok(converted)
+}
+
+/// Whether a GIF palette holds an 8-bit RGB or RGBA image exactly: at most
+/// 256 colors, with every pixel fully opaque or fully transparent. Fully
+/// transparent pixels count as one color, whatever their RGB.
+fn fits_gif_palette(img: &DynamicImage) -> bool {
+    let channels = usize::from(img.color().channel_count());
+    let mut colors = HashSet::new();
+    for pixel in img.as_bytes().chunks_exact(channels) {
+        let color = match pixel {
+            [_, _, _, 0] => [0; 4],
+            [r, g, b, 255] | [r, g, b] => [*r, *g, *b, 255],
+            _ => return false,
+        };
+        if colors.insert(color) && colors.len() > 256 {
+            return false;
+        }
+    }
+    true
+}
+
+/// The pixels of a GIF frame, with fully transparent pixels made alike so
+/// that they share the palette's one transparent entry.
+fn gif_pixels(img: &DynamicImage) -> image::RgbaImage {
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        if pixel.0[3] == 0 {
+            *pixel = image::Rgba([0; 4]);
+        }
+    }
+    rgba
+}
+
+/// Convert an image to one of the image crate's color types.
+fn convert(img: &DynamicImage, color: image::ColorType) -> DynamicImage {
+    use image::ColorType;
+
+    match color {
+        ColorType::L8 => DynamicImage::ImageLuma8(img.to_luma8()),
+        ColorType::La8 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(img.to_rgb8()),
+        ColorType::L16 => DynamicImage::ImageLuma16(img.to_luma16()),
+        ColorType::La16 => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+        ColorType::Rgb16 => DynamicImage::ImageRgb16(img.to_rgb16()),
+        ColorType::Rgba16 => DynamicImage::ImageRgba16(img.to_rgba16()),
+        ColorType::Rgb32F => DynamicImage::ImageRgb32F(img.to_rgb32f()),
+        ColorType::Rgba32F => DynamicImage::ImageRgba32F(img.to_rgba32f()),
+        _ => DynamicImage::ImageRgba8(img.to_rgba8()),
+    }
+}
+
+/// Convert CMYK samples, held in an RGBA-shaped image, to RGB of the same
+/// depth.
+fn cmyk_to_rgb(img: &DynamicImage) -> DynamicImage {
+    let channel = |c: f32, k: f32| (1.0 - c) * (1.0 - k);
+    let cmyk = img.to_rgba32f();
+    let rgb = DynamicImage::ImageRgb32F(image::ImageBuffer::from_fn(
+        cmyk.width(),
+        cmyk.height(),
+        |x, y| {
+            let [c, m, y, k] = cmyk.get_pixel(x, y).0;
+            image::Rgb([channel(c, k), channel(m, k), channel(y, k)])
+        },
+    ));
+    match img.color() {
+        image::ColorType::Rgba16 => convert(&rgb, image::ColorType::Rgb16),
+        _ => convert(&rgb, image::ColorType::Rgb8),
+    }
+}
+
+/// Convert an RGB color to CMYK samples in the order of an RGBA-shaped CMYK
+/// image, the inverse of [`cmyk_to_rgb`]. Alpha is dropped.
+pub(crate) fn rgb_to_cmyk(color: image::Rgba<u8>) -> image::Rgba<u8> {
+    let [r, g, b, _] = color.0.map(|c| f32::from(c) / 255.0);
+    let k = 1.0 - r.max(g).max(b);
+    let channel = |c: f32| {
+        if k < 1.0 {
+            (1.0 - c - k) / (1.0 - k)
+        } else {
+            0.0
+        }
+    };
+    let sample = |v: f32| (v * 255.0).round() as u8;
+    image::Rgba([
+        sample(channel(r)),
+        sample(channel(g)),
+        sample(channel(b)),
+        sample(k),
+    ])
+}
+
+/// An image of the given size and color type filled with one color.
+pub(crate) fn filled(
+    width: u32,
+    height: u32,
+    color: image::ColorType,
+    fill: image::Rgba<u8>,
+) -> DynamicImage {
+    let rgba = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(width, height, fill));
+    convert(&rgba, color)
+}
+
+/// Write a TIFF with the tiff crate directly, since the image crate's encoder
+/// does not expose compression or metadata.
+fn write_tiff<W: Write + Seek>(
+    images: &[&DynamicImage],
+    compression: TiffCompression,
+    metadata: &Metadata,
+    writer: &mut W,
+) -> Result<()> {
+    use tiff::encoder::{Compression, DeflateLevel, TiffEncoder};
+
+    let compression = match compression {
+        TiffCompression::None => Compression::Uncompressed,
+        TiffCompression::Lzw => Compression::Lzw,
+        TiffCompression::Deflate => Compression::Deflate(DeflateLevel::Balanced),
+        TiffCompression::Packbits => Compression::Packbits,
+    };
+    let mut encoder = TiffEncoder::new(writer)?.with_compression(compression);
+    for &img in images {
+        write_tiff_page(&mut encoder, img, metadata)?;
+    }
+    Ok(())
+}
+
+/// Write one image of a TIFF in its own sample layout.
+fn write_tiff_page<W: Write + Seek>(
+    encoder: &mut tiff::encoder::TiffEncoder<W>,
+    img: &DynamicImage,
+    metadata: &Metadata,
+) -> Result<()> {
+    use tiff::encoder::colortype::{
+        Gray16, Gray8, RGB32Float, RGBA32Float, CMYK16, CMYK8, RGB16, RGB8, RGBA16, RGBA8,
+    };
+
+    match img {
+        DynamicImage::ImageRgba8(buf) if metadata.cmyk => {
+            write_tiff_image::<CMYK8, _>(encoder, img, buf, metadata)
+        }
+        DynamicImage::ImageRgba16(buf) if metadata.cmyk => {
+            write_tiff_image::<CMYK16, _>(encoder, img, buf, metadata)
+        }
+        _ if metadata.cmyk => Err(anyhow!(
+            "TIFF output does not support {:?} CMYK images",
+            img.color()
+        )),
+        DynamicImage::ImageLuma8(buf) => write_tiff_image::<Gray8, _>(encoder, img, buf, metadata),
+        DynamicImage::ImageRgb8(buf) => write_tiff_image::<RGB8, _>(encoder, img, buf, metadata),
+        DynamicImage::ImageRgba8(buf) => write_tiff_image::<RGBA8, _>(encoder, img, buf, metadata),
+        DynamicImage::ImageLumaA8(buf) => {
+            write_tiff_image::<GrayAlpha8, _>(encoder, img, buf, metadata)
+        }
+        DynamicImage::ImageLuma16(buf) => {
+            write_tiff_image::<Gray16, _>(encoder, img, buf, metadata)
+        }
+        DynamicImage::ImageLumaA16(buf) => {
+            write_tiff_image::<GrayAlpha16, _>(encoder, img, buf, metadata)
+        }
+        DynamicImage::ImageRgb16(buf) => write_tiff_image::<RGB16, _>(encoder, img, buf, metadata),
+        DynamicImage::ImageRgba16(buf) => {
+            write_tiff_image::<RGBA16, _>(encoder, img, buf, metadata)
+        }
+        DynamicImage::ImageRgb32F(buf) => {
+            write_tiff_image::<RGB32Float, _>(encoder, img, buf, metadata)
+        }
+        DynamicImage::ImageRgba32F(buf) => {
+            write_tiff_image::<RGBA32Float, _>(encoder, img, buf, metadata)
+        }
+        _ => Err(anyhow!(
+            "TIFF output does not support {:?} images",
+            img.color()
+        )),
+    }
+}
+
+/// Write one TIFF image with its ICC profile, EXIF, XMP and resolution tags.
+fn write_tiff_image<C, W>(
+    encoder: &mut tiff::encoder::TiffEncoder<W>,
+    img: &DynamicImage,
+    samples: &[C::Inner],
+    metadata: &Metadata,
+) -> Result<()>
+where
+    C: tiff::encoder::colortype::ColorType,
+    [C::Inner]: tiff::encoder::TiffValue,
+    W: Write + Seek,
+{
+    use tiff::encoder::Rational;
+    use tiff::tags::{PhotometricInterpretation, ResolutionUnit, Tag};
+
+    let (width, height) = img.dimensions();
+    // The Exif IFD is written ahead of the image's IFD, which points to it
+    let exif = metadata
+        .exif_for(width, height)
+        .and_then(|block| ExifTags::read(&mut Cursor::new(block)))
+        .map(|tags| tags.with_byte_order(cfg!(target_endian = "big")));
+    let exif_ifd = match exif.as_ref().filter(|tags| !tags.exif.is_empty()) {
+        Some(tags) => {
+            let mut directory = encoder.extra_directory()?;
+            write_exif_entries(&mut directory, &tags.exif)?;
+            Some(directory.finish_with_offsets()?.offset)
+        }
+        None => None,
+    };
+
+    let mut image = encoder.new_image::<C>(width, height)?;
+    if let Some(tags) = &exif {
+        write_exif_entries(image.encoder(), &tags.ifd0)?;
+    }
+    if let Some(offset) = exif_ifd {
+        image.encoder().write_tag(Tag::ExifDirectory, offset)?;
+    }
+    if let Some(resolution) = metadata.resolution {
+        // Hundredths of a pixel per inch
+        let rational = |v: f64| Rational {
+            n: (v * 100.0).round() as u32,
+            d: 100,
+        };
+        image.resolution_unit(ResolutionUnit::Inch);
+        image.x_resolution(rational(resolution.x));
+        image.y_resolution(rational(resolution.y));
+    }
+    if let Some(icc_profile) = &metadata.icc_profile {
+        image
+            .encoder()
+            .write_tag(Tag::IccProfile, Undefined(icc_profile))?;
+    }
+    if let Some(xmp) = &metadata.xmp {
+        // XMLPacket
+        image
+            .encoder()
+            .write_tag(Tag::Unknown(700), Undefined(xmp))?;
+    }
+    if C::TIFF_VALUE == PhotometricInterpretation::BlackIsZero && C::BITS_PER_SAMPLE.len() == 2 {
+        // Unassociated alpha
+        image.encoder().write_tag(Tag::ExtraSamples, 2u16)?;
+    }
+    image.write_data(samples)?;
+    Ok(())
+}
+
+/// Write EXIF entries, in this machine's byte order, to a TIFF directory with
+/// their own field types.
+fn write_exif_entries<W: Write + Seek>(
+    directory: &mut tiff::encoder::DirectoryEncoder<'_, W, tiff::encoder::TiffKindStandard>,
+    entries: &[RawEntry],
+) -> Result<()> {
+    for entry in entries {
+        let tag = tiff::tags::Tag::from_u16_exhaustive(entry.tag);
+        match entry.value_type {
+            1 => directory.write_tag(tag, ExifValue::<1>(entry)),
+            2 => directory.write_tag(tag, ExifValue::<2>(entry)),
+            3 => directory.write_tag(tag, ExifValue::<3>(entry)),
+            4 => directory.write_tag(tag, ExifValue::<4>(entry)),
+            5 => directory.write_tag(tag, ExifValue::<5>(entry)),
+            6 => directory.write_tag(tag, ExifValue::<6>(entry)),
+            8 => directory.write_tag(tag, ExifValue::<8>(entry)),
+            9 => directory.write_tag(tag, ExifValue::<9>(entry)),
+            10 => directory.write_tag(tag, ExifValue::<10>(entry)),
+            11 => directory.write_tag(tag, ExifValue::<11>(entry)),
+            12 => directory.write_tag(tag, ExifValue::<12>(entry)),
+            _ => directory.write_tag(tag, ExifValue::<7>(entry)),
+        }?;
+    }
+    Ok(())
+}
+
+/// The value of an EXIF entry written as-is with the TIFF field type `T`; the
+/// tiff crate has no slice types for rationals or UNDEFINED.
+struct ExifValue<'a, const T: u16>(&'a RawEntry);
+
+impl<const T: u16> tiff::encoder::TiffValue for ExifValue<'_, T> {
+    const BYTE_LEN: u8 = match T {
+        3 | 8 => 2,
+        4 | 9 | 11 => 4,
+        5 | 10 | 12 => 8,
+        _ => 1,
+    };
+    const FIELD_TYPE: tiff::tags::Type = {
+        use tiff::tags::Type;
+        match T {
+            1 => Type::BYTE,
+            2 => Type::ASCII,
+            3 => Type::SHORT,
+            4 => Type::LONG,
+            5 => Type::RATIONAL,
+            6 => Type::SBYTE,
+            8 => Type::SSHORT,
+            9 => Type::SLONG,
+            10 => Type::SRATIONAL,
+            11 => Type::FLOAT,
+            12 => Type::DOUBLE,
+            _ => Type::UNDEFINED,
+        }
+    };
+
+    fn count(&self) -> usize {
+        self.0.count as usize
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0.data)
+    }
+}
+
+/// 8-bit gray+alpha TIFF samples, which the tiff crate has no color type for.
+struct GrayAlpha8;
+
+impl tiff::encoder::colortype::ColorType for GrayAlpha8 {
+    type Inner = u8;
+    const TIFF_VALUE: tiff::tags::PhotometricInterpretation =
+        tiff::tags::PhotometricInterpretation::BlackIsZero;
+    const BITS_PER_SAMPLE: &'static [u16] = &[8, 8];
+    const SAMPLE_FORMAT: &'static [tiff::tags::SampleFormat] = &[tiff::tags::SampleFormat::Uint; 2];
+
+    fn horizontal_predict(row: &[u8], result: &mut Vec<u8>) {
+        result.extend(row.iter().enumerate().map(|(i, &s)| {
+            if i < 2 {
+                s
+            } else {
+                s.wrapping_sub(row[i - 2])
+            }
+        }));
+    }
+}
+
+/// 16-bit gray+alpha TIFF samples.
+struct GrayAlpha16;
+
+impl tiff::encoder::colortype::ColorType for GrayAlpha16 {
+    type Inner = u16;
+    const TIFF_VALUE: tiff::tags::PhotometricInterpretation =
+        tiff::tags::PhotometricInterpretation::BlackIsZero;
+    const BITS_PER_SAMPLE: &'static [u16] = &[16, 16];
+    const SAMPLE_FORMAT: &'static [tiff::tags::SampleFormat] = &[tiff::tags::SampleFormat::Uint; 2];
+
+    fn horizontal_predict(row: &[u16], result: &mut Vec<u16>) {
+        result.extend(row.iter().enumerate().map(|(i, &s)| {
+            if i < 2 {
+                s
+            } else {
+                s.wrapping_sub(row[i - 2])
+            }
+        }));
+    }
+}
+
+/// Opaque bytes stored with the TIFF UNDEFINED type, as the ICC profile and
+/// XMP tags require; the tiff crate writes plain byte slices as BYTE.
+struct Undefined<'a>(&'a [u8]);
+
+impl tiff::encoder::TiffValue for Undefined<'_> {
+    const BYTE_LEN: u8 = 1;
+    const FIELD_TYPE: tiff::tags::Type = tiff::tags::Type::UNDEFINED;
+
+    fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0)
+    }
+}
+
+/// A parsed output path template, e.g. `{dir}/{stem}_{name}.{ext}`.
+///
+/// Placeholders are `{dir}`, `{stem}`, `{name}`, `{index}`, `{page}`, `{x}`,
+/// `{y}`, `{w}`, `{h}` and `{ext}`. Numeric placeholders accept a zero-padded width
+/// such as `{index:03}`, and `{{`/`}}` produce literal braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OutputTemplate {
+    segments: Vec<Segment>,
+    /// Directory substituted for `{dir}`; the input's directory if unset
+    dir: Option<PathBuf>,
+    /// Format whose extension is substituted for `{ext}`; the input's if unset
+    format: Option<OutputFormat>,
+}
+
+impl OutputTemplate {
+    /// Parse a template, optionally overriding the output directory and format.
+    pub(crate) fn new(
+        template: &str,
+        dir: Option<PathBuf>,
+        format: Option<OutputFormat>,
+    ) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => {
+                                return Err(anyhow!(
+                                    "Unclosed placeholder in output template '{template}'"
+                                ))
+                            }
+                        }
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(parse_placeholder(&placeholder, template)?);
+                }
+                '}' => {
+                    return Err(anyhow!(
+                        "Unmatched '}}' in output template '{template}'. Use '}}}}' for a literal brace"
+                    ))
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        if !segments
+            .iter()
+            .any(|s| matches!(s, Segment::Field(Field::Name | Field::Index, _)))
+        {
+            return Err(anyhow!(
+                "Output template '{template}' must contain {{name}} or {{index}} so captures do not overwrite each other"
+            ));
+        }
+
+        Ok(OutputTemplate {
+            segments,
+            dir,
+            format,
+        })
+    }
+
+    /// Whether the template names each page or frame of an input separately.
+    pub(crate) fn has_page(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|s| matches!(s, Segment::Field(Field::Page, _)))
+    }
+
+    /// Directory outputs for an input are written to, before templating.
+    pub(crate) fn output_dir(&self, input: &Path) -> PathBuf {
+        match &self.dir {
+            Some(dir) => dir.clone(),
+            None => input.parent().map(Path::to_path_buf).unwrap_or_default(),
+        }
+    }
+
+    /// Render the template for one output of an input.
+    pub(crate) fn render(&self, input: &Path, fields: &OutputFields) -> PathBuf {
+        let dir = self.output_dir(input);
+        // An input without a directory renders `{dir}` as `.`, which is then
+        // stripped again so relative inputs produce relative outputs.
+        let implicit_dir = dir.as_os_str().is_empty();
+        let dir = if implicit_dir {
+            ".".to_string()
+        } else {
+            dir.to_string_lossy().into_owned()
+        };
+
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Field(field, width) => {
+                    let value = match field {
+                        Field::Dir => dir.clone(),
+                        Field::Stem => fields.stem.to_string(),
+                        Field::Name => fields.name.to_string(),
+                        Field::Ext => match self.format {
+                            Some(format) => format.extension().to_string(),
+                            None => fields.ext.to_string(),
+                        },
+                        Field::Index => fields.index.to_string(),
+                        Field::Page => fields.page.to_string(),
+                        Field::X => fields.rect.x.to_string(),
+                        Field::Y => fields.rect.y.to_string(),
+                        Field::W => fields.rect.width.to_string(),
+                        Field::H => fields.rect.height.to_string(),
+                    };
+                    match width {
+                        Some(width) => out.push_str(&zero_pad(&value, *width)),
+                        None => out.push_str(&value),
+                    }
+                }
+            }
+        }
+
+        let path = PathBuf::from(out);
+        if implicit_dir {
+            if let Ok(stripped) = path.strip_prefix(".") {
+                return stripped.to_path_buf();
+            }
+        }
+        path
+    }
+}
+
+/// Parse the inside of a `{...}` placeholder.
+fn parse_placeholder(placeholder: &str, template: &str) -> Result<Segment> {
+    let (name, format) = match placeholder.split_once(':') {
+        Some((name, format)) => (name, Some(format)),
+        None => (placeholder, None),
+    };
+    let field = Field::from_name(name).ok_or_else(|| {
+        anyhow!(
+            "Unknown placeholder '{{{name}}}' in output template '{template}'. \
+             Supported: dir, stem, name, index, page, x, y, w, h, ext"
+        )
+    })?;
+
+    let width = match format {
+        None => None,
+        Some(format) => {
+            let digits = format.strip_prefix('0').unwrap_or(format);
+            let width = digits.parse::<usize>().ok().filter(|_| field.is_numeric());
+            match width {
+                Some(width) => Some(width),
+                None => {
+                    return Err(anyhow!(
+                        "Invalid format '{placeholder}' in output template '{template}'. \
+                         Only numeric placeholders accept a width, e.g. {{index:03}}"
+                    ))
+                }
+            }
+        }
+    };
+    Ok(Segment::Field(field, width))
+}
+
+/// What to do when an output file already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Conflict {
+    /// Refuse to replace the file
+    #[default]
+    Error,
+    /// Replace the file
+    Overwrite,
+    /// Leave the file alone and skip the output
+    Skip,
+    /// Write to the first free `<name>-<n>.<ext>` instead
+    Suffix,
+}
+
+/// Output paths claimed during a run, so that no two outputs write the same
+/// file and existing files are handled according to the conflict policy.
+#[derive(Debug, Default)]
+pub(crate) struct OutputRegistry {
+    policy: Conflict,
+    /// Claimed paths and a description of the output that claimed each
+    claimed: Mutex<HashMap<PathBuf, String>>,
+}
+
+impl OutputRegistry {
+    pub(crate) fn new(policy: Conflict) -> Self {
+        OutputRegistry {
+            policy,
+            claimed: Mutex::default(),
+        }
+    }
+
+    /// Claim `path` for the output described by `owner`.
+    ///
+    /// Returns the path to write, which differs from `path` with the suffix
+    /// policy, or `None` if the output should be skipped. Another output of
+    /// this run writing the same path is an error unless suffixing.
+    pub(crate) fn claim(&self, path: &Path, owner: &str) -> Result<Option<PathBuf>> {
+        // Lexically normalise so that `a/./b.png` and `a/b.png` collide
+        let path: PathBuf = path.components().collect();
+        let mut claimed = self
+            .claimed
+            .lock()
+            .map_err(|_| anyhow!("Output registry lock poisoned"))?;
+
+        if let Some(other) = claimed.get(&path) {
+            if self.policy != Conflict::Suffix {
+                return Err(anyhow!(
+                    "Output '{}' for {owner} is also written by {other}. \
+                     Give captures distinct names, change --out-template or use --suffix-on-conflict",
+                    path.display()
+                ));
+            }
+        } else if path.exists() {
+            match self.policy {
+                Conflict::Error => {
+                    return Err(anyhow!(
+                        "Output '{}' for {owner} already exists. \
+                         Use --overwrite, --no-clobber or --suffix-on-conflict",
+                        path.display()
+                    ))
+                }
+                Conflict::Skip => return Ok(None),
+                Conflict::Overwrite | Conflict::Suffix => {}
+            }
+        }
+
+        let path = match self.policy {
+            Conflict::Suffix if claimed.contains_key(&path) || path.exists() => (1..)
+                .map(|n| with_suffix(&path, n))
+                .find(|p| !claimed.contains_key(p) && !p.exists())
+                .expect("unbounded suffix search"),
+            _ => path,
+        };
+        claimed.insert(path.clone(), owner.to_string());
+        Ok(Some(path))
+    }
+}
+
+/// `dir/name.ext` becomes `dir/name-<n>.ext`.
+fn with_suffix(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(ext) => format!("{stem}-{n}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-{n}"),
+    };
+    path.with_file_name(file_name)
+}
+
+/// `dir/name.ext` becomes `dir/name_p<page>.ext`, for pages written to their
+/// own files by a template without `{page}`.
+pub(crate) fn with_page(path: &Path, page: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(ext) => format!("{stem}_p{page}.{}", ext.to_string_lossy()),
+        None => format!("{stem}_p{page}"),
+    };
+    path.with_file_name(file_name)
+}
+
+/// Zero-pad a decimal value to `width` digits, keeping any minus sign first.
+fn zero_pad(value: &str, width: usize) -> String {
+    match value.strip_prefix('-') {
+        Some(digits) => format!("-{digits:0>width$}", width = width.saturating_sub(1)),
+        None => format!("{value:0>width$}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn fields(name: &str) -> OutputFields<'_> {
+        OutputFields {
+            stem: "scan",
+            ext: "tif",
+            name,
+            index: 7,
+            page: 2,
+            rect: Rect {
+                x: 10,
+                y: -5,
+                width: 512,
+                height: 256,
+            },
+        }
+    }
+
+    #[test]
+    fn test_render_default_template() {
+        let template = OutputTemplate::new(DEFAULT_TEMPLATE, None, None).unwrap();
+        let out = template.render(Path::new("/in/scan.tif"), &fields("left"));
+        assert_eq!(out, PathBuf::from("/in/scan_left.tif"));
+    }
+
+    #[test]
+    fn test_render_relative_input_without_directory() {
+        let template = OutputTemplate::new(DEFAULT_TEMPLATE, None, None).unwrap();
+        let out = template.render(Path::new("scan.tif"), &fields("left"));
+        assert_eq!(out, PathBuf::from("scan_left.tif"));
+    }
+
+    #[test]
+    fn test_render_output_dir() {
+        let template =
+            OutputTemplate::new(DEFAULT_TEMPLATE, Some(PathBuf::from("/out")), None).unwrap();
+        let out = template.render(Path::new("/in/scan.tif"), &fields("left"));
+        assert_eq!(out, PathBuf::from("/out/scan_left.tif"));
+    }
+
+    #[test]
+    fn test_render_all_fields() {
+        let template = OutputTemplate::new(
+            "{dir}/{name}/{stem}-{index:03}-p{page:02}-{x}_{y}_{w}x{h}.{ext}",
+            Some(PathBuf::from("out")),
+            None,
+        )
+        .unwrap();
+        let out = template.render(Path::new("/in/scan.tif"), &fields("tile"));
+        assert_eq!(
+            out,
+            PathBuf::from("out/tile/scan-007-p02-10_-5_512x256.tif")
+        );
+    }
+
+    #[test]
+    fn test_render_zero_pad_negative() {
+        let template = OutputTemplate::new("{name}_{y:04}", None, None).unwrap();
+        let out = template.render(Path::new("scan.tif"), &fields("a"));
+        assert_eq!(out, PathBuf::from("a_-005"));
+    }
+
+    #[test]
+    fn test_render_escaped_braces() {
+        let template = OutputTemplate::new("{{{name}}}.png", None, None).unwrap();
+        let out = template.render(Path::new("scan.tif"), &fields("a"));
+        assert_eq!(out, PathBuf::from("{a}.png"));
+    }
+
+    #[test]
+    fn test_template_unknown_placeholder() {
+        let result = OutputTemplate::new("{dir}/{stem}_{label}.{ext}", None, None);
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Unknown placeholder '{label}'"));
+    }
+
+    #[test]
+    fn test_template_invalid_format() {
+        assert!(OutputTemplate::new("{name:03}", None, None).is_err());
+        assert!(OutputTemplate::new("{name}_{index:abc}", None, None).is_err());
+    }
+
+    #[test]
+    fn test_template_unclosed_placeholder() {
+        assert!(OutputTemplate::new("{name", None, None).is_err());
+        assert!(OutputTemplate::new("{name}}", None, None).is_err());
+    }
+
+    #[test]
+    fn test_render_format_extension() {
+        let template =
+            OutputTemplate::new(DEFAULT_TEMPLATE, None, Some(OutputFormat::Jpeg)).unwrap();
+        let out = template.render(Path::new("/in/scan.tif"), &fields("left"));
+        assert_eq!(out, PathBuf::from("/in/scan_left.jpg"));
+    }
+
+    #[test]
+    fn test_output_format_from_str() {
+        assert_eq!("JPG".parse::<OutputFormat>().unwrap(), OutputFormat::Jpeg);
+        assert_eq!("jpeg".parse::<OutputFormat>().unwrap(), OutputFormat::Jpeg);
+        assert_eq!("tiff".parse::<OutputFormat>().unwrap(), OutputFormat::Tiff);
+        assert_eq!("webp".parse::<OutputFormat>().unwrap(), OutputFormat::Webp);
+        assert!("heic".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_format_for_path() {
+        let encoding = Encoding::default();
+        assert_eq!(
+            encoding.format_for(Path::new("a/b.TIF")).unwrap(),
+            OutputFormat::Tiff
+        );
+        assert!(encoding.format_for(Path::new("a/b.xyz")).is_err());
+
+        let encoding = Encoding {
+            format: Some(OutputFormat::Png),
+            ..Encoding::default()
+        };
+        assert_eq!(
+            encoding.format_for(Path::new("a/b.xyz")).unwrap(),
+            OutputFormat::Png
+        );
+    }
+
+    #[test]
+    fn test_encodable_requires_exact_conversion() {
+        use image::ColorType;
+
+        let color = |img: &DynamicImage, format, cmyk, allow| {
+            encodable(img, format, cmyk, allow).map(|img| img.color())
+        };
+
+        let deep = DynamicImage::ImageRgba16(image::ImageBuffer::from_fn(2, 2, |x, _| {
+            image::Rgba([x as u16 * 1000, 1, 2, 65535])
+        }));
+        let err = color(&deep, OutputFormat::Jpeg, false, false).unwrap_err();
+        assert!(err.to_string().contains("--allow-downconvert"), "{err}");
+        assert_eq!(
+            color(&deep, OutputFormat::Jpeg, false, true).unwrap(),
+            ColorType::Rgb8
+        );
+        assert_eq!(
+            color(&deep, OutputFormat::Png, false, false).unwrap(),
+            ColorType::Rgba16
+        );
+
+        // 8-bit values widened to 16 bits and opaque alpha convert exactly
+        let widened = DynamicImage::ImageRgba16(image::ImageBuffer::from_fn(2, 2, |x, _| {
+            image::Rgba([x as u16 * 257, 257, 0, 65535])
+        }));
+        assert_eq!(
+            color(&widened, OutputFormat::Jpeg, false, false).unwrap(),
+            ColorType::Rgb8
+        );
+        let gray =
+            DynamicImage::ImageLumaA8(image::ImageBuffer::from_pixel(2, 2, image::LumaA([7, 255])));
+        assert_eq!(
+            color(&gray, OutputFormat::Jpeg, false, false).unwrap(),
+            ColorType::L8
+        );
+
+        let float = DynamicImage::ImageRgb32F(image::ImageBuffer::from_pixel(
+            2,
+            2,
+            image::Rgb([0.1, 0.2, 0.3]),
+        ));
+        assert!(color(&float, OutputFormat::Png, false, false).is_err());
+        assert_eq!(
+            color(&float, OutputFormat::Png, false, true).unwrap(),
+            ColorType::Rgb16
+        );
+        assert_eq!(
+            color(&float, OutputFormat::Tiff, false, false).unwrap(),
+            ColorType::Rgb32F
+        );
+
+        let cmyk = DynamicImage::ImageRgba8(image::ImageBuffer::from_pixel(
+            2,
+            2,
+            image::Rgba([0, 255, 255, 0]),
+        ));
+        let err = color(&cmyk, OutputFormat::Png, true, false).unwrap_err();
+        assert!(err.to_string().contains("CMYK"), "{err}");
+        assert_eq!(
+            color(&cmyk, OutputFormat::Tiff, true, false).unwrap(),
+            ColorType::Rgba8
+        );
+        let rgb = encodable(&cmyk, OutputFormat::Png, true, true).unwrap();
+        assert_eq!(rgb.to_rgb8().get_pixel(0, 0).0, [255, 0, 0]);
+    }
+
+    #[test]
+    fn test_tiff_preserves_samples() {
+        let dir = TempDir::new("samples");
+        let path = dir.join("out.tif");
+
+        let images = [
+            DynamicImage::ImageLuma16(image::ImageBuffer::from_fn(5, 3, |x, y| {
+                image::Luma([x as u16 * 13_001 + y as u16])
+            })),
+            DynamicImage::ImageLumaA8(image::ImageBuffer::from_fn(5, 3, |x, y| {
+                image::LumaA([x as u8 * 50, y as u8 * 100])
+            })),
+            DynamicImage::ImageLumaA16(image::ImageBuffer::from_fn(5, 3, |x, y| {
+                image::LumaA([x as u16 * 12_345, y as u16 * 30_001])
+            })),
+            DynamicImage::ImageRgb16(image::ImageBuffer::from_fn(5, 3, |x, y| {
+                image::Rgb([x as u16 * 3, y as u16 * 30_000, 40_001])
+            })),
+            DynamicImage::ImageRgb32F(image::ImageBuffer::from_fn(5, 3, |x, y| {
+                image::Rgb([x as f32 * 0.1, -(y as f32), 1e6])
+            })),
+        ];
+        for img in &images {
+            Encoding::default()
+                .save(img, &path, &Metadata::default())
+                .unwrap();
+            let (decoded, read) = crate::metadata::open(&path, true, None).unwrap();
+            assert_eq!(&decoded.frames[0].image, img, "{:?}", img.color());
+            assert!(!read.cmyk);
+        }
+
+        let metadata = Metadata {
+            cmyk: true,
+            ..Metadata::default()
+        };
+        let cmyk = [
+            DynamicImage::ImageRgba8(image::ImageBuffer::from_fn(5, 3, |x, y| {
+                image::Rgba([x as u8, y as u8, 200, 50])
+            })),
+            DynamicImage::ImageRgba16(image::ImageBuffer::from_fn(5, 3, |x, y| {
+                image::Rgba([x as u16 * 9_999, y as u16, 65_535, 1])
+            })),
+        ];
+        for img in &cmyk {
+            Encoding::default().save(img, &path, &metadata).unwrap();
+            let (decoded, read) = crate::metadata::open(&path, true, None).unwrap();
+            assert_eq!(&decoded.frames[0].image, img, "{:?}", img.color());
+            assert!(read.cmyk);
+            let err = Encoding::default()
+                .save(img, &dir.join("out.png"), &read)
+                .unwrap_err();
+            assert!(err.to_string().contains("CMYK"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_save_formats() {
+        let dir = TempDir::new("encoding");
+        let img = DynamicImage::ImageRgba16(image::ImageBuffer::from_fn(6, 4, |x, y| {
+            image::Rgba([x as u16 * 1000, y as u16 * 1000, 500, 65535])
+        }));
+
+        for (format, compression) in [
+            (OutputFormat::Tiff, TiffCompression::Lzw),
+            (OutputFormat::Tiff, TiffCompression::Deflate),
+            (OutputFormat::Tiff, TiffCompression::Packbits),
+        ] {
+            let path = dir.join("out.tif");
+            let encoding = Encoding {
+                format: Some(format),
+                tiff_compression: compression,
+                ..Encoding::default()
+            };
+            encoding.save(&img, &path, &Metadata::default()).unwrap();
+            let decoded = image::open(&path).unwrap();
+            assert_eq!(decoded.color(), image::ColorType::Rgba16);
+            assert_eq!(decoded.as_bytes(), img.as_bytes());
+        }
+
+        let path = dir.join("out.jpg");
+        assert!(Encoding::default()
+            .save(&img, &path, &Metadata::default())
+            .is_err());
+        assert!(!path.exists());
+        let encoding = Encoding {
+            allow_downconvert: true,
+            ..Encoding::default()
+        };
+        encoding.save(&img, &path, &Metadata::default()).unwrap();
+        let decoded = image::open(&path).unwrap();
+        assert_eq!(decoded.dimensions(), (6, 4));
+        assert_eq!(decoded.color(), image::ColorType::Rgb8);
+
+        let path = dir.join("out.png");
+        let encoding = Encoding {
+            png_compression: PngCompression::Best,
+            ..Encoding::default()
+        };
+        encoding.save(&img, &path, &Metadata::default()).unwrap();
+        assert_eq!(image::open(&path).unwrap().as_bytes(), img.as_bytes());
+    }
+
+    #[test]
+    fn test_gif_requires_exact_palette() {
+        let dir = TempDir::new("gif");
+        let path = dir.join("out.gif");
+
+        // Up to 256 colors, one of them transparent, fit a palette exactly
+        let exact =
+            DynamicImage::ImageRgba8(image::ImageBuffer::from_fn(16, 17, |x, y| match (x, y) {
+                (_, 16) => image::Rgba([x as u8, 9, 9, 0]),
+                (15, 15) => image::Rgba([0, 0, 0, 255]),
+                _ => image::Rgba([x as u8 * 16, y as u8 * 16, 0, 255]),
+            }));
+        Encoding::default()
+            .save(&exact, &path, &Metadata::default())
+            .unwrap();
+        let decoded = image::open(&path).unwrap().to_rgba8();
+        for (x, y, pixel) in exact.to_rgba8().enumerate_pixels() {
+            match pixel.0[3] {
+                0 => assert_eq!(decoded.get_pixel(x, y).0[3], 0),
+                _ => assert_eq!(decoded.get_pixel(x, y), pixel),
+            }
+        }
+
+        let truecolor = DynamicImage::ImageRgb8(image::ImageBuffer::from_fn(20, 20, |x, y| {
+            image::Rgb([x as u8, y as u8, 0])
+        }));
+        let translucent = DynamicImage::ImageRgba8(image::ImageBuffer::from_pixel(
+            2,
+            2,
+            image::Rgba([1, 2, 3, 128]),
+        ));
+        for img in [&truecolor, &translucent] {
+            let err = Encoding::default()
+                .save(img, &path, &Metadata::default())
+                .unwrap_err();
+            assert!(err.to_string().contains("--allow-downconvert"), "{err}");
+        }
+        let encoding = Encoding {
+            allow_downconvert: true,
+            ..Encoding::default()
+        };
+        encoding
+            .save(&truecolor, &path, &Metadata::default())
+            .unwrap();
+        assert_eq!(image::open(&path).unwrap().dimensions(), (20, 20));
+    }
+
+    #[test]
+    fn test_with_suffix() {
+        assert_eq!(
+            with_suffix(Path::new("out/a_left.jpg"), 2),
+            PathBuf::from("out/a_left-2.jpg")
+        );
+        assert_eq!(
+            with_suffix(Path::new("a_left"), 1),
+            PathBuf::from("a_left-1")
+        );
+    }
+
+    #[test]
+    fn test_with_page() {
+        assert_eq!(
+            with_page(Path::new("out/fax_left.tif"), 3),
+            PathBuf::from("out/fax_left_p3.tif")
+        );
+    }
+
+    #[test]
+    fn test_save_frames() {
+        let dir = TempDir::new("frames");
+        let frames: Vec<Frame> = (1..=3)
+            .map(|page| Frame {
+                page,
+                image: DynamicImage::ImageLuma8(image::GrayImage::from_pixel(
+                    4 + page,
+                    3,
+                    image::Luma([page as u8 * 50]),
+                )),
+                delay: Some(Delay::from_numer_denom_ms(page * 100, 1)),
+            })
+            .collect();
+
+        let path = dir.join("pages.tif");
+        Encoding::default()
+            .save_frames(&frames, &path, &Metadata::default())
+            .unwrap();
+        let (read, _) = crate::metadata::open(&path, true, None).unwrap();
+        assert_eq!(read.frames.len(), 3);
+        for (read, frame) in read.frames.iter().zip(&frames) {
+            assert_eq!(read.page, frame.page);
+            assert_eq!(read.image.as_bytes(), frame.image.as_bytes());
+        }
+        // Only the selected pages are decoded, but every page is counted
+        let selected = "2".parse().unwrap();
+        let (read, _) = crate::metadata::open(&path, true, Some(&selected)).unwrap();
+        assert_eq!(read.frames.len(), 1);
+        assert_eq!(read.frames[0].page, 2);
+        assert_eq!(read.frames[0].image.as_bytes(), frames[1].image.as_bytes());
+        let sizes: Vec<_> = read.pages.iter().map(|p| (p.page, p.width)).collect();
+        assert_eq!(sizes, [(1, 5), (2, 6), (3, 7)]);
+
+        let path = dir.join("animation.gif");
+        let frames = &frames[1..];
+        Encoding::default()
+            .save_frames(frames, &path, &Metadata::default())
+            .unwrap();
+        let (read, _) = crate::metadata::open(&path, true, None).unwrap();
+        assert_eq!(read.frames.len(), 2);
+        assert_eq!(read.frames[1].image.dimensions(), (6, 3));
+        assert_eq!(
+            read.frames[1].delay,
+            Some(Delay::from_numer_denom_ms(300, 1))
+        );
+
+        let result =
+            Encoding::default().save_frames(frames, &dir.join("frames.png"), &Metadata::default());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_registry_collision_within_run() {
+        let registry = OutputRegistry::new(Conflict::Overwrite);
+        let path = Path::new("/nonexistent/a_left.png");
+        assert_eq!(
+            registry.claim(path, "capture 'left' of 'a.jpg'").unwrap(),
+            Some(path.to_path_buf())
+        );
+        let result = registry.claim(
+            Path::new("/nonexistent/./a_left.png"),
+            "capture 'left' of 'a.png'",
+        );
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("is also written by capture 'left' of 'a.jpg'"));
+    }
+
+    #[test]
+    fn test_registry_existing_files() {
+        let dir = TempDir::new("registry");
+        let existing = dir.join("a_left.png");
+        std::fs::write(&existing, b"").unwrap();
+        std::fs::write(dir.join("a_left-1.png"), b"").unwrap();
+
+        let result = OutputRegistry::new(Conflict::Error).claim(&existing, "capture 'left'");
+        assert!(result.unwrap_err().to_string().contains("already exists"));
+
+        let claimed = OutputRegistry::new(Conflict::Overwrite)
+            .claim(&existing, "capture 'left'")
+            .unwrap();
+        assert_eq!(claimed, Some(existing.clone()));
+
+        let claimed = OutputRegistry::new(Conflict::Skip)
+            .claim(&existing, "capture 'left'")
+            .unwrap();
+        assert_eq!(claimed, None);
+
+        let registry = OutputRegistry::new(Conflict::Suffix);
+        let first = registry.claim(&existing, "capture 'left'").unwrap();
+        assert_eq!(first, Some(dir.join("a_left-2.png")));
+        let second = registry.claim(&existing, "capture 'left'").unwrap();
+        assert_eq!(second, Some(dir.join("a_left-3.png")));
+    }
+
+    #[test]
+    fn test_write_atomic_cleans_up_on_failure() {
+        let dir = TempDir::new("atomic");
+        let path = dir.join("out.jpg");
+        std::fs::write(&path, b"previous").unwrap();
+
+        let result = write_atomic(&path, |writer| {
+            writer.write_all(b"partial")?;
+            Err(anyhow!("encoder failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"previous");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        write_atomic(&path, |writer| Ok(writer.write_all(b"complete")?)).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"complete");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_template_requires_name_or_index() {
+        let result = OutputTemplate::new("{dir}/{stem}.{ext}", None, None);
+        assert!(result.is_err());
+        assert!(OutputTemplate::new("{dir}/{stem}_{index}.{ext}", None, None).is_ok());
+    }
+}
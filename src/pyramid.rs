@@ -0,0 +1,500 @@
+//! Multi-resolution tile pyramid export (Deep Zoom and XYZ layouts).
+
+use std::borrow::Cow;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView};
+
+use crate::metadata::Metadata;
+use crate::output::{write_atomic, Encoding, OutputRegistry};
+use crate::{crop, split_file_name, Rect};
+
+/// Directory layout of a tile pyramid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Layout {
+    /// Deep Zoom: `<stem>.dzi` plus `<stem>_files/<level>/<col>_<row>.<ext>`,
+    /// with level 0 a single pixel and edge tiles cropped to the image
+    Dzi,
+    /// XYZ: `<stem>_tiles/<z>/<x>/<y>.<ext>`, with zoom 0 a single tile and
+    /// edge tiles padded to full size
+    Xyz,
+}
+
+impl Layout {
+    /// Default tile size for this layout.
+    pub(crate) fn default_tile_size(self) -> u32 {
+        match self {
+            Layout::Dzi => 254,
+            Layout::Xyz => 256,
+        }
+    }
+
+    /// Default tile overlap for this layout.
+    pub(crate) fn default_overlap(self) -> u32 {
+        match self {
+            Layout::Dzi => 1,
+            Layout::Xyz => 0,
+        }
+    }
+}
+
+impl std::str::FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dzi" | "deepzoom" | "deep-zoom" => Ok(Layout::Dzi),
+            "xyz" => Ok(Layout::Xyz),
+            other => Err(format!(
+                "Invalid pyramid layout '{other}'. Supported values: dzi, xyz"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Layout::Dzi => f.write_str("dzi"),
+            Layout::Xyz => f.write_str("xyz"),
+        }
+    }
+}
+
+/// Settings for building a tile pyramid from each input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Pyramid {
+    pub(crate) layout: Layout,
+    pub(crate) tile_size: u32,
+    pub(crate) overlap: u32,
+}
+
+impl Pyramid {
+    /// Dimensions of every level, from the smallest (level 0) to full size.
+    pub(crate) fn levels(&self, width: u32, height: u32) -> Vec<(u32, u32)> {
+        let longest = width.max(height);
+        let count = match self.layout {
+            // Halve until a single pixel remains
+            Layout::Dzi => ceil_log2(longest) + 1,
+            // Halve until the image fits in a single tile
+            Layout::Xyz => ceil_log2(longest.div_ceil(self.tile_size)) + 1,
+        };
+
+        let mut levels = vec![(width, height)];
+        for _ in 1..count {
+            let (w, h) = levels[levels.len() - 1];
+            levels.push((w.div_ceil(2), h.div_ceil(2)));
+        }
+        levels.reverse();
+        levels
+    }
+
+    /// Rectangles of the tiles of one level, as `(col, row, rect)`.
+    fn tiles(&self, width: u32, height: u32) -> Vec<(u32, u32, Rect)> {
+        let size = self.tile_size;
+        // Offsets are computed in i64, as the end of the last tile may be
+        // past u32::MAX
+        let offset = |i: u32| i64::from(i) * i64::from(size);
+        let overlap = i64::from(self.overlap);
+        let mut tiles = Vec::new();
+        for row in 0..height.div_ceil(size) {
+            for col in 0..width.div_ceil(size) {
+                let rect = match self.layout {
+                    Layout::Dzi => {
+                        let span = |i: u32, total: u32| {
+                            let start = (offset(i) - overlap).max(0);
+                            let end = (offset(i) + i64::from(size) + overlap).min(i64::from(total));
+                            (start, (end - start) as u32)
+                        };
+                        let (x, w) = span(col, width);
+                        let (y, h) = span(row, height);
+                        Rect {
+                            x,
+                            y,
+                            width: w,
+                            height: h,
+                        }
+                    }
+                    Layout::Xyz => Rect {
+                        x: offset(col),
+                        y: offset(row),
+                        width: size,
+                        height: size,
+                    },
+                };
+                tiles.push((col, row, rect));
+            }
+        }
+        tiles
+    }
+
+    /// Number of tiles in the pyramid for an image of the given size.
+    pub(crate) fn tile_count(&self, width: u32, height: u32) -> usize {
+        self.levels(width, height)
+            .iter()
+            .map(|&(w, h)| {
+                (u64::from(w.div_ceil(self.tile_size)) * u64::from(h.div_ceil(self.tile_size)))
+                    as usize
+            })
+            .sum()
+    }
+
+    /// Path of the directory in `out_dir` holding the tiles for an input.
+    pub(crate) fn tile_dir(&self, input: &Path, out_dir: &Path) -> Result<PathBuf> {
+        let (stem, _) = split_file_name(input)?;
+        let suffix = match self.layout {
+            Layout::Dzi => "files",
+            Layout::Xyz => "tiles",
+        };
+        Ok(out_dir.join(format!("{stem}_{suffix}")))
+    }
+
+    /// Resolve the path of every tile and of the Deep Zoom descriptor for one
+    /// input, and claim each from `outputs` before anything is written.
+    ///
+    /// Tiles use the input's format unless the encoding sets one. Files that
+    /// exist are skipped with --no-clobber; --suffix-on-conflict cannot rename
+    /// them without breaking the layout, so they are an error instead.
+    pub(crate) fn plan(
+        &self,
+        input: &Path,
+        out_dir: &Path,
+        encoding: &Encoding,
+        outputs: &OutputRegistry,
+        width: u32,
+        height: u32,
+    ) -> Result<Plan> {
+        let (stem, ext) = split_file_name(input)?;
+        let ext = encoding.format.map_or(ext, |f| f.extension().to_string());
+        let tile_dir = self.tile_dir(input, out_dir)?;
+        let owner = format!("the pyramid of '{}'", input.display());
+        let claim = |path: PathBuf| -> Result<Option<PathBuf>> {
+            let normalized: PathBuf = path.components().collect();
+            match outputs.claim(&path, &owner)? {
+                Some(claimed) if claimed != normalized => Err(anyhow!(
+                    "Pyramid output '{}' is already taken, and --suffix-on-conflict cannot \
+                     rename pyramid files. Use --overwrite or --no-clobber",
+                    path.display()
+                )),
+                claimed => Ok(claimed),
+            }
+        };
+
+        let mut tiles = Vec::new();
+        for (level, &(w, h)) in self.levels(width, height).iter().enumerate() {
+            let level_tiles = self
+                .tiles(w, h)
+                .into_iter()
+                .map(|(col, row, _)| {
+                    claim(match self.layout {
+                        Layout::Dzi => tile_dir
+                            .join(level.to_string())
+                            .join(format!("{col}_{row}.{ext}")),
+                        Layout::Xyz => tile_dir
+                            .join(level.to_string())
+                            .join(col.to_string())
+                            .join(format!("{row}.{ext}")),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            tiles.push(level_tiles);
+        }
+        let descriptor = match self.layout {
+            Layout::Dzi => claim(out_dir.join(format!("{stem}.dzi")))?,
+            Layout::Xyz => None,
+        };
+
+        Ok(Plan {
+            ext,
+            tiles,
+            descriptor,
+        })
+    }
+
+    /// Write the tiles and descriptor claimed by `plan` for an image,
+    /// returning the number of tiles written. Tiles carry none of the input's
+    /// metadata beyond whether it is CMYK.
+    pub(crate) fn export(
+        &self,
+        plan: &Plan,
+        encoding: &Encoding,
+        img: &DynamicImage,
+        cmyk: bool,
+    ) -> Result<usize> {
+        let metadata = Metadata {
+            cmyk,
+            ..Metadata::default()
+        };
+        let (width, height) = img.dimensions();
+        let levels = self.levels(width, height);
+
+        let mut written = 0;
+        // The top level is cropped from the input itself; only the
+        // downscaled levels are allocated
+        let mut level_img = Cow::Borrowed(img);
+        for (level, &(w, h)) in levels.iter().enumerate().rev() {
+            if level_img.dimensions() != (w, h) {
+                level_img = Cow::Owned(level_img.resize_exact(w, h, FilterType::Triangle));
+            }
+
+            for ((_, _, rect), out_path) in self.tiles(w, h).into_iter().zip(&plan.tiles[level]) {
+                let Some(out_path) = out_path else {
+                    continue;
+                };
+                if let Some(parent) = out_path.parent() {
+                    std::fs::create_dir_all(parent).with_context(|| {
+                        format!("Unable to create directory '{}'", parent.display())
+                    })?;
+                }
+                encoding
+                    .save(&crop(&level_img, rect, None), out_path, &metadata)
+                    .with_context(|| format!("Unable to save tile to '{}'", out_path.display()))?;
+                written += 1;
+            }
+        }
+
+        if let Some(dzi_path) = &plan.descriptor {
+            let descriptor = self.dzi_descriptor(&plan.ext, width, height);
+            write_atomic(dzi_path, |writer| {
+                writer
+                    .write_all(descriptor.as_bytes())
+                    .with_context(|| format!("Unable to write '{}'", dzi_path.display()))
+            })?;
+        }
+
+        Ok(written)
+    }
+
+    /// XML descriptor for a Deep Zoom image.
+    fn dzi_descriptor(&self, ext: &str, width: u32, height: u32) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<Image xmlns="http://schemas.microsoft.com/deepzoom/2008" Format="{ext}" Overlap="{}" TileSize="{}">
+  <Size Width="{width}" Height="{height}"/>
+</Image>
+"#,
+            self.overlap, self.tile_size
+        )
+    }
+}
+
+/// Claimed output paths of one input's pyramid.
+#[derive(Debug)]
+pub(crate) struct Plan {
+    /// Extension, and so format, of the tiles
+    ext: String,
+    /// Path of each tile by level, in the order of [`Pyramid::tiles`], or
+    /// `None` where the tile already exists and is skipped
+    tiles: Vec<Vec<Option<PathBuf>>>,
+    /// Path of the Deep Zoom descriptor, unless it is skipped
+    descriptor: Option<PathBuf>,
+}
+
+impl Plan {
+    /// Number of tiles that will be written.
+    pub(crate) fn tiles(&self) -> usize {
+        self.tiles.iter().flatten().flatten().count()
+    }
+
+    /// Number of tiles skipped because they already exist.
+    pub(crate) fn skipped(&self) -> usize {
+        self.tiles.iter().flatten().filter(|t| t.is_none()).count()
+    }
+}
+
+/// Smallest `n` such that `2^n >= v`.
+fn ceil_log2(v: u32) -> u32 {
+    if v <= 1 {
+        0
+    } else {
+        32 - (v - 1).leading_zeros()
+    }
+}
+
+/// Build pyramid settings, applying per-layout defaults.
+pub(crate) fn settings(
+    layout: Layout,
+    tile_size: Option<u32>,
+    overlap: Option<u32>,
+) -> Result<Pyramid> {
+    let tile_size = tile_size.unwrap_or_else(|| layout.default_tile_size());
+    let overlap = overlap.unwrap_or_else(|| layout.default_overlap());
+    if tile_size == 0 {
+        return Err(anyhow!("Pyramid tile size must be positive"));
+    }
+    if layout == Layout::Xyz && overlap != 0 {
+        return Err(anyhow!("The xyz pyramid layout does not support overlap"));
+    }
+    Ok(Pyramid {
+        layout,
+        tile_size,
+        overlap,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Conflict;
+    use crate::test_util::TempDir;
+
+    fn dzi(tile_size: u32, overlap: u32) -> Pyramid {
+        settings(Layout::Dzi, Some(tile_size), Some(overlap)).unwrap()
+    }
+
+    #[test]
+    fn test_layout_from_str() {
+        assert_eq!("dzi".parse::<Layout>().unwrap(), Layout::Dzi);
+        assert_eq!("Deep-Zoom".parse::<Layout>().unwrap(), Layout::Dzi);
+        assert_eq!("XYZ".parse::<Layout>().unwrap(), Layout::Xyz);
+        assert!("tms".parse::<Layout>().is_err());
+    }
+
+    #[test]
+    fn test_ceil_log2() {
+        assert_eq!(ceil_log2(0), 0);
+        assert_eq!(ceil_log2(1), 0);
+        assert_eq!(ceil_log2(2), 1);
+        assert_eq!(ceil_log2(3), 2);
+        assert_eq!(ceil_log2(1024), 10);
+        assert_eq!(ceil_log2(1025), 11);
+    }
+
+    #[test]
+    fn test_settings_defaults() {
+        let p = settings(Layout::Dzi, None, None).unwrap();
+        assert_eq!((p.tile_size, p.overlap), (254, 1));
+        let p = settings(Layout::Xyz, None, None).unwrap();
+        assert_eq!((p.tile_size, p.overlap), (256, 0));
+        assert!(settings(Layout::Xyz, None, Some(2)).is_err());
+        assert!(settings(Layout::Dzi, Some(0), None).is_err());
+    }
+
+    #[test]
+    fn test_dzi_levels() {
+        let levels = dzi(254, 1).levels(1000, 600);
+        assert_eq!(levels.len(), 11);
+        assert_eq!(levels[0], (1, 1));
+        assert_eq!(levels[9], (500, 300));
+        assert_eq!(levels[10], (1000, 600));
+    }
+
+    #[test]
+    fn test_xyz_levels() {
+        let p = settings(Layout::Xyz, None, None).unwrap();
+        let levels = p.levels(1000, 600);
+        assert_eq!(levels, vec![(250, 150), (500, 300), (1000, 600)]);
+        assert_eq!(p.levels(200, 100), vec![(200, 100)]);
+    }
+
+    #[test]
+    fn test_dzi_tiles_overlap() {
+        let tiles = dzi(10, 1).tiles(25, 10);
+        let spans: Vec<(u32, i64, u32)> =
+            tiles.iter().map(|&(c, _, r)| (c, r.x, r.width)).collect();
+        assert_eq!(spans, vec![(0, 0, 11), (1, 9, 12), (2, 19, 6)]);
+        assert!(tiles.iter().all(|&(_, row, r)| row == 0 && r.height == 10));
+    }
+
+    #[test]
+    fn test_xyz_tiles_full_size() {
+        let p = settings(Layout::Xyz, Some(16), None).unwrap();
+        let tiles = p.tiles(20, 10);
+        assert_eq!(tiles.len(), 2);
+        assert_eq!(
+            tiles[1].2,
+            Rect {
+                x: 16,
+                y: 0,
+                width: 16,
+                height: 16
+            }
+        );
+    }
+
+    #[test]
+    fn test_tile_count() {
+        // Levels: 1x1, 2x1, 3x2, 5x3, 10x5, 20x10 with 8px tiles
+        assert_eq!(dzi(8, 0).tile_count(20, 10), 1 + 1 + 1 + 1 + 2 + 6);
+    }
+
+    #[test]
+    fn test_tiles_past_u32() {
+        let tiles = dzi(1 << 31, 1).tiles(u32::MAX, 1);
+        let spans: Vec<(i64, u32)> = tiles.iter().map(|&(_, _, r)| (r.x, r.width)).collect();
+        assert_eq!(spans, vec![(0, (1 << 31) + 1), ((1 << 31) - 1, 1 << 31)]);
+        let tiles = settings(Layout::Xyz, Some(1 << 31), None)
+            .unwrap()
+            .tiles(u32::MAX, 1);
+        assert_eq!(tiles[1].2.x, 1 << 31);
+
+        // 70000x70000 alone has 4.9 billion single-pixel tiles
+        let count = dzi(1, 0).tile_count(70000, 70000);
+        assert!(count > 4_900_000_000);
+    }
+
+    #[test]
+    fn test_export_dzi() {
+        let dir = TempDir::new("pyramid");
+        let input = dir.join("scan.png");
+        let img = DynamicImage::ImageRgb8(image::RgbImage::new(20, 10));
+
+        let pyramid = dzi(8, 1);
+        let encoding = Encoding::default();
+        let outputs = OutputRegistry::new(Conflict::Error);
+        let plan = pyramid
+            .plan(&input, &dir, &encoding, &outputs, 20, 10)
+            .unwrap();
+        assert_eq!(plan.tiles(), pyramid.tile_count(20, 10));
+        let written = pyramid.export(&plan, &encoding, &img, false).unwrap();
+        assert_eq!(written, pyramid.tile_count(20, 10));
+
+        let descriptor = std::fs::read_to_string(dir.join("scan.dzi")).unwrap();
+        assert!(descriptor.contains(r#"Format="png" Overlap="1" TileSize="8""#));
+        assert!(descriptor.contains(r#"<Size Width="20" Height="10"/>"#));
+
+        let tile = image::open(dir.join("scan_files/5/2_1.png")).unwrap();
+        assert_eq!(tile.dimensions(), (5, 3));
+        assert!(dir.join("scan_files/0/0_0.png").is_file());
+
+        // Existing tiles go through the overwrite policy like other outputs
+        let err = pyramid
+            .plan(
+                &input,
+                &dir,
+                &encoding,
+                &OutputRegistry::new(Conflict::Error),
+                20,
+                10,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{err}");
+        std::fs::remove_file(dir.join("scan_files/5/2_1.png")).unwrap();
+        let plan = pyramid
+            .plan(
+                &input,
+                &dir,
+                &encoding,
+                &OutputRegistry::new(Conflict::Skip),
+                20,
+                10,
+            )
+            .unwrap();
+        assert_eq!((plan.tiles(), plan.skipped()), (1, written - 1));
+        assert!(plan.descriptor.is_none());
+        let err = pyramid
+            .plan(
+                &input,
+                &dir,
+                &encoding,
+                &OutputRegistry::new(Conflict::Suffix),
+                20,
+                10,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("cannot rename"), "{err}");
+    }
+}
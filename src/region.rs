@@ -0,0 +1,628 @@
+//! Decoding only the parts of an image that captures cover.
+//!
+//! Striped and tiled TIFFs, non-interlaced PNGs and baseline JPEGs are read
+//! a strip, tile, row or MCU row at a time, and only those overlapping a
+//! capture are decoded and kept, so that small captures of a huge image
+//! never hold all of its pixels in memory. Other inputs are decoded whole.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use image::metadata::Orientation;
+use image::{ColorType, DynamicImage, ImageBuffer, ImageDecoder, ImageFormat, ImageReader};
+use tiff::decoder::DecodingResult;
+
+use crate::frames::{self, Decoded, PageSize, Pages, TiffPage};
+use crate::metadata::{self, Metadata};
+use crate::{jpeg, Rect};
+
+/// An opened input: either decoded whole, or with just its header read so
+/// that only the parts captures cover are decoded later.
+pub(crate) enum Input {
+    Decoded(Decoded),
+    Partial(Partial),
+}
+
+impl Input {
+    /// Open an image, reading only its header if it can be decoded in part.
+    ///
+    /// A TIFF is read in part when a single page is captured: its only
+    /// page, or the one `selected` picks. Other multi-page and animated
+    /// inputs, and inputs that `auto_orient` would rotate or flip, are
+    /// decoded whole, as by [`metadata::open`], except for TIFF pages that
+    /// `selected` leaves out.
+    pub(crate) fn open(
+        path: &Path,
+        auto_orient: bool,
+        selected: Option<&Pages>,
+    ) -> Result<(Self, Metadata)> {
+        if let Some(partial) = Partial::open(path, selected) {
+            let (mut metadata, orientation) = metadata::read(path)?;
+            if !auto_orient || orientation == Orientation::NoTransforms {
+                metadata.cmyk = partial.cmyk;
+                return Ok((Input::Partial(partial), metadata));
+            }
+        }
+        let (decoded, metadata) = metadata::open(path, auto_orient, selected)?;
+        Ok((Input::Decoded(decoded), metadata))
+    }
+
+    /// Number and dimensions of every page.
+    pub(crate) fn pages(&self) -> Vec<PageSize> {
+        match self {
+            Input::Decoded(decoded) => decoded.pages.clone(),
+            Input::Partial(partial) => partial.pages.clone(),
+        }
+    }
+}
+
+/// How a partially decoded input is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    /// The page of a TIFF that is decoded
+    Tiff(TiffPage),
+    Png,
+    Jpeg,
+}
+
+/// A page of an image of which only parts are decoded.
+pub(crate) struct Partial {
+    path: PathBuf,
+    layout: Layout,
+    /// Every page of the image, of which only one is decoded
+    pages: Vec<PageSize>,
+    width: u32,
+    height: u32,
+    /// Pixel layout of the decoded parts
+    color: ColorType,
+    /// The samples are CMYK, held as RGBA
+    cmyk: bool,
+}
+
+impl Partial {
+    /// Read the header of an image that can be decoded in part, given the
+    /// pages its captures read. Returns `None` for images that must be
+    /// decoded whole, including any whose header cannot be read here,
+    /// leaving errors to the full decoder.
+    fn open(path: &Path, selected: Option<&Pages>) -> Option<Self> {
+        let format = ImageReader::open(path).ok()?.format()?;
+        let single = |width, height| {
+            vec![PageSize {
+                page: 1,
+                width,
+                height,
+            }]
+        };
+        let (layout, pages, color, cmyk) = match format {
+            ImageFormat::Tiff => {
+                let (pages, page, color, cmyk) = tiff_header(path, selected)?;
+                (Layout::Tiff(page), pages, color, cmyk)
+            }
+            ImageFormat::Png => {
+                let (width, height, color) = png_header(path)?;
+                (Layout::Png, single(width, height), color, false)
+            }
+            ImageFormat::Jpeg => {
+                if !jpeg::is_baseline(path) {
+                    return None;
+                }
+                let decoder = ImageReader::open(path).ok()?.into_decoder().ok()?;
+                let (width, height) = decoder.dimensions();
+                let color = decoder.color_type();
+                (Layout::Jpeg, single(width, height), color, false)
+            }
+            _ => return None,
+        };
+        let page = match layout {
+            Layout::Tiff(page) => page.size,
+            _ => pages[0],
+        };
+        Some(Partial {
+            path: path.to_path_buf(),
+            layout,
+            width: page.width,
+            height: page.height,
+            pages,
+            color,
+            cmyk,
+        })
+    }
+
+    pub(crate) fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Decode the parts of the image inside `rects`, which lie within it,
+    /// returning one image per rectangle.
+    pub(crate) fn decode(&self, rects: &[Rect]) -> Result<Vec<DynamicImage>> {
+        let is_empty = |rect: &Rect| rect.width == 0 || rect.height == 0;
+        let wanted: Vec<Rect> = rects.iter().copied().filter(|r| !is_empty(r)).collect();
+
+        let decoded = match self.layout {
+            Layout::Jpeg => jpeg::decode_regions(&self.path, &wanted)?,
+            Layout::Tiff(_) | Layout::Png => {
+                let mut targets: Vec<Target> = wanted
+                    .iter()
+                    .map(|&rect| Target::new(rect, self.color))
+                    .collect();
+                match self.layout {
+                    Layout::Tiff(page) => self.read_tiff(&page, &mut targets),
+                    _ => self.read_png(&mut targets),
+                }
+                .with_context(|| format!("Unable to decode '{}'", self.path.display()))?;
+                targets
+                    .into_iter()
+                    .map(|target| target.into_image(self.color))
+                    .collect::<Result<_>>()?
+            }
+        };
+
+        let mut decoded = decoded.into_iter();
+        Ok(rects
+            .iter()
+            .map(|rect| {
+                if is_empty(rect) {
+                    DynamicImage::new(rect.width, rect.height, self.color)
+                } else {
+                    decoded.next().expect("one image per rectangle")
+                }
+            })
+            .collect())
+    }
+
+    /// Decode each strip or tile that overlaps a target, once.
+    fn read_tiff(&self, page: &TiffPage, targets: &mut [Target]) -> Result<()> {
+        let mut decoder = tiff::decoder::Decoder::new(page.reader(&self.path)?)?;
+        let (chunk_w, chunk_h) = decoder.chunk_dimensions();
+        let across = self.width.div_ceil(chunk_w);
+
+        let chunks: BTreeSet<u32> = targets
+            .iter()
+            .flat_map(|target| {
+                let (x0, x1) = (target.x / chunk_w, (target.x + target.width - 1) / chunk_w);
+                let (y0, y1) = (target.y / chunk_h, (target.y + target.height - 1) / chunk_h);
+                (y0..=y1).flat_map(move |y| (x0..=x1).map(move |x| y * across + x))
+            })
+            .collect();
+        for chunk in chunks {
+            let (width, height) = decoder.chunk_data_dimensions(chunk);
+            let samples = match decoder.read_chunk(chunk)? {
+                DecodingResult::U8(samples) => samples,
+                DecodingResult::U16(samples) => {
+                    samples.iter().flat_map(|s| s.to_ne_bytes()).collect()
+                }
+                DecodingResult::F32(samples) => {
+                    samples.iter().flat_map(|s| s.to_ne_bytes()).collect()
+                }
+                _ => return Err(anyhow!("Unsupported TIFF sample format")),
+            };
+            let (x, y) = ((chunk % across) * chunk_w, (chunk / across) * chunk_h);
+            for target in targets.iter_mut() {
+                target.fill(x, y, width, height, &samples);
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode rows down to the last one a target needs.
+    fn read_png(&self, targets: &mut [Target]) -> Result<()> {
+        let mut decoder = png::Decoder::new(BufReader::new(File::open(&self.path)?));
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info()?;
+        let sixteen = reader.output_color_type().1 == png::BitDepth::Sixteen;
+
+        let bottom = targets
+            .iter()
+            .map(|target| target.y + target.height)
+            .max()
+            .unwrap_or(0);
+        for y in 0..bottom {
+            let row = reader
+                .next_row()?
+                .ok_or_else(|| anyhow!("PNG data ends at row {y}"))?;
+            if !targets.iter().any(|target| target.covers_row(y)) {
+                continue;
+            }
+            if sixteen {
+                // PNG stores samples big-endian
+                let samples: Vec<u8> = row
+                    .data()
+                    .chunks_exact(2)
+                    .flat_map(|sample| u16::from_be_bytes([sample[0], sample[1]]).to_ne_bytes())
+                    .collect();
+                for target in targets.iter_mut() {
+                    target.fill(0, y, self.width, 1, &samples);
+                }
+            } else {
+                for target in targets.iter_mut() {
+                    target.fill(0, y, self.width, 1, row.data());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The pages of a TIFF, and the one page captured with its pixel layout and
+/// whether it is CMYK, if its strips or tiles can be read one at a time.
+/// That is the only page, or the single page of several that `selected`
+/// picks.
+fn tiff_header(
+    path: &Path,
+    selected: Option<&Pages>,
+) -> Option<(Vec<PageSize>, TiffPage, ColorType, bool)> {
+    use tiff::tags::{PlanarConfiguration, SampleFormat, Tag};
+
+    let mut decoder = tiff::decoder::Decoder::new(BufReader::new(File::open(path).ok()?)).ok()?;
+    let pages = frames::tiff_pages(&mut decoder).ok()?;
+    let mut captured = pages
+        .iter()
+        .filter(|page| pages.len() == 1 || selected.is_some_and(|s| s.contains(page.size.page)));
+    let page = match (captured.next(), captured.next()) {
+        (Some(&page), None) => page,
+        _ => return None,
+    };
+    let mut decoder = tiff::decoder::Decoder::new(page.reader(path).ok()?).ok()?;
+    let planar = decoder
+        .find_tag_unsigned::<u16>(Tag::PlanarConfiguration)
+        .ok()?;
+    if planar.is_some_and(|planar| planar != PlanarConfiguration::Chunky.to_u16()) {
+        return None;
+    }
+    let formats = decoder
+        .find_tag_unsigned_vec::<u16>(Tag::SampleFormat)
+        .ok()?
+        .unwrap_or_default();
+    let all = |format: SampleFormat| formats.iter().all(|&f| f == format.to_u16());
+    let float = !formats.is_empty() && all(SampleFormat::IEEEFP);
+    if !float && !all(SampleFormat::Uint) {
+        return None;
+    }
+
+    let (color, cmyk) = match (decoder.colortype().ok()?, float) {
+        (tiff::ColorType::Gray(8), false) => (ColorType::L8, false),
+        (tiff::ColorType::Gray(16), false) => (ColorType::L16, false),
+        (
+            tiff::ColorType::Multiband {
+                bit_depth: 8,
+                num_samples: 2,
+            },
+            false,
+        ) => (ColorType::La8, false),
+        (
+            tiff::ColorType::Multiband {
+                bit_depth: 16,
+                num_samples: 2,
+            },
+            false,
+        ) => (ColorType::La16, false),
+        (tiff::ColorType::RGB(8), false) => (ColorType::Rgb8, false),
+        (tiff::ColorType::RGB(16), false) => (ColorType::Rgb16, false),
+        (tiff::ColorType::RGB(32), true) => (ColorType::Rgb32F, false),
+        (tiff::ColorType::RGBA(8), false) => (ColorType::Rgba8, false),
+        (tiff::ColorType::RGBA(16), false) => (ColorType::Rgba16, false),
+        (tiff::ColorType::RGBA(32), true) => (ColorType::Rgba32F, false),
+        (tiff::ColorType::CMYK(8), false) => (ColorType::Rgba8, true),
+        (tiff::ColorType::CMYK(16), false) => (ColorType::Rgba16, true),
+        _ => return None,
+    };
+    let pages = pages.into_iter().map(|page| page.size).collect();
+    Some((pages, page, color, cmyk))
+}
+
+/// The dimensions and decoded pixel layout of a non-interlaced PNG.
+fn png_header(path: &Path) -> Option<(u32, u32, ColorType)> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path).ok()?));
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let reader = decoder.read_info().ok()?;
+    let info = reader.info();
+    if info.interlaced {
+        return None;
+    }
+    let color = match reader.output_color_type() {
+        (png::ColorType::Grayscale, png::BitDepth::Eight) => ColorType::L8,
+        (png::ColorType::GrayscaleAlpha, png::BitDepth::Eight) => ColorType::La8,
+        (png::ColorType::Rgb, png::BitDepth::Eight) => ColorType::Rgb8,
+        (png::ColorType::Rgba, png::BitDepth::Eight) => ColorType::Rgba8,
+        (png::ColorType::Grayscale, png::BitDepth::Sixteen) => ColorType::L16,
+        (png::ColorType::GrayscaleAlpha, png::BitDepth::Sixteen) => ColorType::La16,
+        (png::ColorType::Rgb, png::BitDepth::Sixteen) => ColorType::Rgb16,
+        (png::ColorType::Rgba, png::BitDepth::Sixteen) => ColorType::Rgba16,
+        _ => return None,
+    };
+    Some((info.width, info.height, color))
+}
+
+/// The pixels of one rectangle, filled in as the strips, tiles or rows
+/// overlapping it are decoded.
+struct Target {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    bytes_per_pixel: usize,
+    /// Samples in native byte order, row by row
+    data: Vec<u8>,
+}
+
+impl Target {
+    fn new(rect: Rect, color: ColorType) -> Self {
+        let bytes_per_pixel = usize::from(color.bytes_per_pixel());
+        Target {
+            x: rect.x as u32,
+            y: rect.y as u32,
+            width: rect.width,
+            height: rect.height,
+            bytes_per_pixel,
+            data: vec![0; rect.width as usize * rect.height as usize * bytes_per_pixel],
+        }
+    }
+
+    fn covers_row(&self, y: u32) -> bool {
+        (self.y..self.y + self.height).contains(&y)
+    }
+
+    /// Copy the part of a decoded block of pixels at (`x`, `y`) that falls
+    /// inside the target.
+    fn fill(&mut self, x: u32, y: u32, width: u32, height: u32, samples: &[u8]) {
+        let left = x.max(self.x);
+        let right = (x + width).min(self.x + self.width);
+        let top = y.max(self.y);
+        let bottom = (y + height).min(self.y + self.height);
+        if left >= right || top >= bottom {
+            return;
+        }
+
+        let bpp = self.bytes_per_pixel;
+        let len = (right - left) as usize * bpp;
+        for row in top..bottom {
+            let from = ((row - y) as usize * width as usize + (left - x) as usize) * bpp;
+            let to =
+                ((row - self.y) as usize * self.width as usize + (left - self.x) as usize) * bpp;
+            self.data[to..to + len].copy_from_slice(&samples[from..from + len]);
+        }
+    }
+
+    fn into_image(self, color: ColorType) -> Result<DynamicImage> {
+        let (width, height) = (self.width, self.height);
+        let words = |data: Vec<u8>| -> Vec<u16> {
+            data.chunks_exact(2)
+                .map(|s| u16::from_ne_bytes([s[0], s[1]]))
+                .collect()
+        };
+        let floats = |data: Vec<u8>| -> Vec<f32> {
+            data.chunks_exact(4)
+                .map(|s| f32::from_ne_bytes([s[0], s[1], s[2], s[3]]))
+                .collect()
+        };
+        let data = self.data;
+        match color {
+            ColorType::L8 => {
+                ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma8)
+            }
+            ColorType::La8 => {
+                ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA8)
+            }
+            ColorType::Rgb8 => {
+                ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+            }
+            ColorType::Rgba8 => {
+                ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+            }
+            ColorType::L16 => {
+                ImageBuffer::from_raw(width, height, words(data)).map(DynamicImage::ImageLuma16)
+            }
+            ColorType::La16 => {
+                ImageBuffer::from_raw(width, height, words(data)).map(DynamicImage::ImageLumaA16)
+            }
+            ColorType::Rgb16 => {
+                ImageBuffer::from_raw(width, height, words(data)).map(DynamicImage::ImageRgb16)
+            }
+            ColorType::Rgba16 => {
+                ImageBuffer::from_raw(width, height, words(data)).map(DynamicImage::ImageRgba16)
+            }
+            ColorType::Rgb32F => {
+                ImageBuffer::from_raw(width, height, floats(data)).map(DynamicImage::ImageRgb32F)
+            }
+            ColorType::Rgba32F => {
+                ImageBuffer::from_raw(width, height, floats(data)).map(DynamicImage::ImageRgba32F)
+            }
+            _ => None,
+        }
+        .ok_or_else(|| anyhow!("Unsupported pixel layout {color:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frames::Frame;
+    use crate::output::Encoding;
+    use crate::test_util::TempDir;
+    use image::GenericImageView;
+
+    /// Check that decoding parts of an image gives the same pixels as
+    /// decoding it whole.
+    fn assert_partial_matches_full(path: &Path, selected: Option<&Pages>) {
+        let partial = Partial::open(path, selected).expect("decodable in part");
+        let (decoded, _) = metadata::open(path, false, selected).unwrap();
+        let full = &decoded.frames[0].image;
+        let (width, height) = full.dimensions();
+        assert_eq!(partial.dimensions(), (width, height));
+
+        let rects: Vec<Rect> = [
+            (0, 0, width, height),
+            (3, 5, 17, 9),
+            (width / 2, 1, width / 2, height - 1),
+            (width - 1, height - 1, 1, 1),
+            (7, 7, 0, 3),
+        ]
+        .iter()
+        .map(|&(x, y, width, height)| Rect {
+            x: i64::from(x),
+            y: i64::from(y),
+            width,
+            height,
+        })
+        .collect();
+        for (rect, part) in rects.iter().zip(partial.decode(&rects).unwrap()) {
+            let expected = full.crop_imm(rect.x as u32, rect.y as u32, rect.width, rect.height);
+            assert_eq!(part.color(), full.color(), "{}", path.display());
+            assert_eq!(part.dimensions(), expected.dimensions());
+            assert_eq!(
+                part.as_bytes(),
+                expected.as_bytes(),
+                "{rect:?} of {}",
+                path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn test_partial_decode() {
+        let dir = TempDir::new("partial");
+
+        let images = [
+            DynamicImage::ImageRgba8(image::ImageBuffer::from_fn(90, 70, |x, y| {
+                image::Rgba([x as u8, y as u8, (x * y) as u8, 200])
+            })),
+            DynamicImage::ImageLuma16(image::ImageBuffer::from_fn(90, 70, |x, y| {
+                image::Luma([(x * 700 + y) as u16])
+            })),
+            DynamicImage::ImageLumaA16(image::ImageBuffer::from_fn(90, 70, |x, y| {
+                image::LumaA([(x * 700) as u16, (y * 900) as u16])
+            })),
+            DynamicImage::ImageRgb32F(image::ImageBuffer::from_fn(90, 70, |x, y| {
+                image::Rgb([x as f32 / 7.0, y as f32, -1.5])
+            })),
+        ];
+        for (i, img) in images.iter().enumerate() {
+            for ext in ["tif", "png"] {
+                let path = dir.join(format!("{i}.{ext}"));
+                if Encoding::default()
+                    .save(img, &path, &Metadata::default())
+                    .is_ok()
+                {
+                    assert_partial_matches_full(&path, None);
+                }
+            }
+        }
+
+        let cmyk = Metadata {
+            cmyk: true,
+            ..Metadata::default()
+        };
+        let path = dir.join("cmyk.tif");
+        Encoding::default().save(&images[0], &path, &cmyk).unwrap();
+        assert_partial_matches_full(&path, None);
+        let (_, metadata) = Input::open(&path, true, None).unwrap();
+        assert!(metadata.cmyk);
+    }
+
+    #[test]
+    fn test_partial_decode_tiled_tiff() {
+        let dir = TempDir::new("tiled");
+        let path = dir.join("tiled.tif");
+
+        // A 40x40 gray image in 16x16 tiles, 3 across and 3 down
+        let (size, tile) = (40u32, 16u32);
+        let entries: [(u16, u16, u32, u32); 10] = [
+            (256, 3, 1, size), // ImageWidth
+            (257, 3, 1, size), // ImageLength
+            (258, 3, 1, 8),    // BitsPerSample
+            (259, 3, 1, 1),    // Compression: none
+            (262, 3, 1, 1),    // PhotometricInterpretation: BlackIsZero
+            (277, 3, 1, 1),    // SamplesPerPixel
+            (322, 3, 1, tile), // TileWidth
+            (323, 3, 1, tile), // TileLength
+            (324, 4, 9, 134),  // TileOffsets
+            (325, 4, 9, 170),  // TileByteCounts
+        ];
+        let mut data = b"II*\0\x08\0\0\0".to_vec();
+        data.extend(10u16.to_le_bytes());
+        for (tag, kind, count, value) in entries {
+            data.extend(tag.to_le_bytes());
+            data.extend(kind.to_le_bytes());
+            data.extend(count.to_le_bytes());
+            data.extend(value.to_le_bytes());
+        }
+        data.extend(0u32.to_le_bytes());
+        for i in 0..9 {
+            data.extend((206 + i * tile * tile).to_le_bytes());
+        }
+        for _ in 0..9 {
+            data.extend((tile * tile).to_le_bytes());
+        }
+        for ty in 0..3 {
+            for tx in 0..3 {
+                for py in 0..tile {
+                    for px in 0..tile {
+                        let (x, y) = (tx * tile + px, ty * tile + py);
+                        let inside = x < size && y < size;
+                        data.push(if inside { (x * 3 + y * 5) as u8 } else { 0 });
+                    }
+                }
+            }
+        }
+        std::fs::write(&path, data).unwrap();
+
+        assert_partial_matches_full(&path, None);
+    }
+
+    #[test]
+    fn test_open_decodes_whole_when_needed() {
+        let dir = TempDir::new("whole");
+        let img = DynamicImage::ImageRgb8(image::RgbImage::new(6, 4));
+
+        let path = dir.join("still.png");
+        Encoding::default()
+            .save(&img, &path, &Metadata::default())
+            .unwrap();
+        assert!(matches!(
+            Input::open(&path, true, None).unwrap().0,
+            Input::Partial(_)
+        ));
+
+        // Multi-page TIFFs are decoded whole unless a single page is captured
+        let path = dir.join("pages.tif");
+        let frames: Vec<Frame> = (1..=3)
+            .map(|page| Frame {
+                page,
+                image: DynamicImage::ImageRgb8(image::ImageBuffer::from_fn(
+                    30 + page,
+                    20,
+                    |x, y| image::Rgb([x as u8, y as u8, page as u8]),
+                )),
+                delay: None,
+            })
+            .collect();
+        Encoding::default()
+            .save_frames(&frames, &path, &Metadata::default())
+            .unwrap();
+        let (input, _) = Input::open(&path, true, None).unwrap();
+        assert!(matches!(input, Input::Decoded(_)));
+        assert_eq!(input.pages().len(), 3);
+        let both: Pages = "1,3".parse().unwrap();
+        let (input, _) = Input::open(&path, true, Some(&both)).unwrap();
+        assert!(matches!(input, Input::Decoded(_)));
+
+        let second: Pages = "2".parse().unwrap();
+        let (input, _) = Input::open(&path, true, Some(&second)).unwrap();
+        assert!(matches!(input, Input::Partial(_)));
+        assert_eq!(input.pages().len(), 3);
+        assert_eq!(input.pages()[2].width, 33);
+        assert_partial_matches_full(&path, Some(&second));
+
+        let path = dir.join("still.bmp");
+        Encoding::default()
+            .save(&img, &path, &Metadata::default())
+            .unwrap();
+        assert!(matches!(
+            Input::open(&path, true, None).unwrap().0,
+            Input::Decoded(_)
+        ));
+    }
+}
@@ -0,0 +1,179 @@
+//! Machine-readable records of a run, printed as JSON lines with --json.
+//!
+//! Each input produces one [`ImageRecord`] once it is validated or
+//! processed, and a run ends with a summary record. Every record has a
+//! `type` field saying which it is.
+
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::Rect;
+
+/// What happened to one input image.
+#[derive(Debug, Default, Serialize)]
+#[serde(tag = "type", rename = "image")]
+pub(crate) struct ImageRecord {
+    pub(crate) input: String,
+    pub(crate) status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    /// Dimensions of the first page, once the image has been opened
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) pages: Option<usize>,
+    pub(crate) captures: Vec<CaptureRecord>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) pyramid: Option<PyramidRecord>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) timings: Option<Timings>,
+}
+
+impl ImageRecord {
+    pub(crate) fn new(input: &Path) -> Self {
+        ImageRecord {
+            input: input.display().to_string(),
+            ..ImageRecord::default()
+        }
+    }
+
+    /// Mark the image as failed with the full chain of the error.
+    pub(crate) fn fail(&mut self, error: &anyhow::Error) {
+        self.status = Status::Failed;
+        self.error = Some(format!("{error:#}"));
+    }
+}
+
+/// Outcome of an image or a capture.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Status {
+    /// The image was validated or processed without failures
+    #[default]
+    Ok,
+    /// The image, or the capture, failed
+    Failed,
+    /// A dry run would write the output
+    Planned,
+    /// The output was written
+    Written,
+    /// The output was skipped because its file already exists
+    Exists,
+    /// The capture was skipped by its out-of-bounds policy
+    Outside,
+}
+
+/// One output file of a capture, or a capture that produced none.
+#[derive(Debug, Serialize)]
+pub(crate) struct CaptureRecord {
+    pub(crate) name: String,
+    /// Position of the capture among the image's captures, from 0
+    pub(crate) index: usize,
+    pub(crate) status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) output: Option<String>,
+    /// The DCT coefficients of a JPEG input are copied instead of re-encoded
+    pub(crate) lossless: bool,
+    /// Resolved rectangle on each page written to the output
+    pub(crate) rects: Vec<PageRect>,
+}
+
+/// An absolute rectangle in top-left coordinates on one page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct PageRect {
+    pub(crate) page: u32,
+    pub(crate) x: i64,
+    pub(crate) y: i64,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+impl PageRect {
+    pub(crate) fn new(page: u32, rect: Rect) -> Self {
+        PageRect {
+            page,
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+/// The tile pyramid of an input.
+#[derive(Debug, Serialize)]
+pub(crate) struct PyramidRecord {
+    pub(crate) layout: String,
+    pub(crate) levels: usize,
+    /// Tiles a dry run would write, or written once processed
+    pub(crate) tiles: usize,
+    pub(crate) output: String,
+}
+
+/// Time spent on an input, in milliseconds.
+#[derive(Debug, Serialize)]
+pub(crate) struct Timings {
+    /// Opening the image and reading its header or pixels
+    pub(crate) decode_ms: u128,
+    /// Cropping, encoding and writing outputs, including any region decoding
+    pub(crate) save_ms: u128,
+}
+
+/// Print a record to stdout as one line of JSON.
+///
+/// Records of images processed in parallel are written whole, one at a time.
+pub(crate) fn emit(record: &impl Serialize) -> Result<()> {
+    let line = serde_json::to_string(record).context("Unable to serialize JSON record")?;
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{line}")
+        .and_then(|()| stdout.flush())
+        .context("Unable to write JSON record")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_record_json() {
+        let mut record = ImageRecord::new(Path::new("scans/a.png"));
+        record.width = Some(100);
+        record.height = Some(80);
+        record.pages = Some(1);
+        record.captures.push(CaptureRecord {
+            name: "left".to_string(),
+            index: 0,
+            status: Status::Written,
+            error: None,
+            output: Some("out/a_left.png".to_string()),
+            lossless: false,
+            rects: vec![PageRect::new(
+                1,
+                Rect {
+                    x: -2,
+                    y: 0,
+                    width: 10,
+                    height: 20,
+                },
+            )],
+        });
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"type":"image","input":"scans/a.png","status":"ok","width":100,"height":80,"pages":1,"captures":[{"name":"left","index":0,"status":"written","output":"out/a_left.png","lossless":false,"rects":[{"page":1,"x":-2,"y":0,"width":10,"height":20}]}]}"#
+        );
+
+        let mut record = ImageRecord::new(Path::new("b.png"));
+        record.fail(&anyhow::anyhow!("Invalid PNG signature").context("Unable to open 'b.png'"));
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"type":"image","input":"b.png","status":"failed","error":"Unable to open 'b.png': Invalid PNG signature","captures":[]}"#
+        );
+    }
+}
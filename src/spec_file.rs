@@ -0,0 +1,594 @@
+//! Loading capture specifications from TOML, JSON or YAML files.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::frames::Pages;
+use crate::{build_capture_spec, Anchor, CaptureSpec, OutOfBounds};
+
+/// Structured file format of a spec file, chosen by file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    /// Determine the format from a path's extension.
+    fn from_path(path: &Path) -> Result<Self> {
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "toml" => Ok(Format::Toml),
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            _ => Err(anyhow!(
+                "Unsupported spec file '{}'. Expected a .toml, .json, .yaml or .yml extension",
+                path.display()
+            )),
+        }
+    }
+
+    /// Deserialize a document in this format.
+    fn parse<T: DeserializeOwned>(self, text: &str) -> Result<T> {
+        let value = match self {
+            Format::Toml => toml::from_str(text)?,
+            Format::Json => serde_json::from_str(text)?,
+            Format::Yaml => serde_yaml::from_str(text)?,
+        };
+        Ok(value)
+    }
+}
+
+/// Top-level contents of a spec file.
+///
+/// Top-level captures are always used. Named layouts add their own captures
+/// on top and are selected with --layout.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpecFile {
+    #[serde(default)]
+    captures: Vec<CaptureEntry>,
+    #[serde(default)]
+    layouts: BTreeMap<String, Layout>,
+}
+
+/// A named set of captures, e.g. one revision of a form.
+///
+/// Example (TOML):
+///
+/// ```toml
+/// [[layouts.invoice-v2.captures]]
+/// name = "total"
+/// rect = "1200x2400:600x150"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Layout {
+    #[serde(default)]
+    captures: Vec<CaptureEntry>,
+}
+
+/// Contents of a per-image sidecar file, e.g. `scan001.cutout.json`.
+///
+/// Sidecar captures extend the global captures, replacing any global capture
+/// with the same name. With `replace = true` the global captures are ignored
+/// for that image.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SidecarFile {
+    #[serde(default)]
+    captures: Vec<CaptureEntry>,
+    #[serde(default)]
+    replace: bool,
+}
+
+/// Extensions checked when looking for an input's sidecar file.
+const SIDECAR_EXTENSIONS: [&str; 4] = ["json", "toml", "yaml", "yml"];
+
+/// A single capture as written in a spec file.
+///
+/// Example (TOML):
+///
+/// ```toml
+/// [[captures]]
+/// name = "left"
+/// rect = "200x300:1200x1850"
+/// origin = "bl"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CaptureEntry {
+    /// Name of the capture region, used in output filename
+    name: String,
+    /// Position and size: <x>x<y>:<width>x<height>
+    rect: String,
+    /// Per-capture origin or anchor point; overrides --origin
+    #[serde(default, alias = "anchor")]
+    origin: Option<String>,
+    /// Pages or animation frames to capture from; overrides --pages
+    #[serde(default, alias = "pages")]
+    page: Option<PageSelection>,
+    /// Handling of the capture past the image edge; overrides --out-of-bounds
+    #[serde(default)]
+    out_of_bounds: Option<String>,
+}
+
+/// A `page` field: a single page number or a list such as `"1,3-5"`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PageSelection {
+    Number(u32),
+    List(String),
+}
+
+impl CaptureEntry {
+    /// Convert the entry into a capture spec using the command-line grammar.
+    fn into_spec(self) -> Result<CaptureSpec> {
+        let anchor = self
+            .origin
+            .as_deref()
+            .map(|o| o.parse::<Anchor>())
+            .transpose()
+            .map_err(|e| anyhow!(e))
+            .with_context(|| format!("Invalid origin for capture '{}'", self.name))?;
+
+        let (position, size) = self
+            .rect
+            .split_once(':')
+            .filter(|(_, size)| !size.contains(':'))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid rect '{}' for capture '{}'. Expected format: <x>x<y>:<width>x<height>",
+                    self.rect,
+                    self.name
+                )
+            })?;
+
+        let pages = self
+            .page
+            .map(|page| match page {
+                PageSelection::Number(n) => n.to_string().parse::<Pages>(),
+                PageSelection::List(list) => list.parse::<Pages>(),
+            })
+            .transpose()
+            .map_err(|e| anyhow!(e))
+            .with_context(|| format!("Invalid page for capture '{}'", self.name))?;
+
+        let out_of_bounds = self
+            .out_of_bounds
+            .as_deref()
+            .map(|policy| policy.parse::<OutOfBounds>())
+            .transpose()
+            .map_err(|e| anyhow!(e))
+            .with_context(|| format!("Invalid out_of_bounds for capture '{}'", self.name))?;
+
+        let mut spec = build_capture_spec(self.name.clone(), anchor, position, size, &self.rect)?;
+        spec.pages = pages;
+        spec.out_of_bounds = out_of_bounds;
+        Ok(spec)
+    }
+}
+
+/// Load capture specs from a TOML, JSON or YAML file.
+///
+/// If the file defines layouts, `layout` must name one of them.
+pub(crate) fn load(path: &Path, layout: Option<&str>) -> Result<Vec<CaptureSpec>> {
+    let format = Format::from_path(path)?;
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read spec file '{}'", path.display()))?;
+    parse(format, &text, layout).with_context(|| format!("Invalid spec file '{}'", path.display()))
+}
+
+/// Parse the text of a spec file into capture specs, selecting a layout.
+fn parse(format: Format, text: &str, layout: Option<&str>) -> Result<Vec<CaptureSpec>> {
+    let mut file: SpecFile = format.parse(text)?;
+    let available = file.layouts.keys().cloned().collect::<Vec<_>>().join(", ");
+
+    let layout_captures = match layout {
+        Some(name) => match file.layouts.remove(name) {
+            Some(layout) => layout.captures,
+            None if file.layouts.is_empty() => {
+                return Err(anyhow!("Layout '{name}' not found; no layouts are defined"))
+            }
+            None => {
+                return Err(anyhow!(
+                    "Layout '{name}' not found. Available layouts: {available}"
+                ))
+            }
+        },
+        None if !file.layouts.is_empty() => {
+            return Err(anyhow!(
+                "Spec file defines layouts ({available}); choose one with --layout"
+            ))
+        }
+        None => Vec::new(),
+    };
+
+    file.captures
+        .into_iter()
+        .chain(layout_captures)
+        .map(CaptureEntry::into_spec)
+        .collect()
+}
+
+/// Find the sidecar file for an input image.
+///
+/// For `scan001.png` this looks for `scan001.png.cutout.json`, `.toml`,
+/// `.yaml` and `.yml` in the same directory, and then for
+/// `scan001.cutout.json` and so on, which `scan001.jpg` would share. Finding
+/// more than one with the same name is an error.
+fn find_sidecar(input: &Path) -> Result<Option<PathBuf>> {
+    let name = input
+        .file_name()
+        .ok_or_else(|| anyhow!("Input path '{}' has no file name", input.display()))?
+        .to_string_lossy();
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+
+    let candidates = |base: &str| -> Vec<PathBuf> {
+        SIDECAR_EXTENSIONS
+            .iter()
+            .map(|ext| input.with_file_name(format!("{base}.cutout.{ext}")))
+            .filter(|p| p.is_file())
+            .collect()
+    };
+    let mut found = candidates(&name);
+    if found.is_empty() {
+        found = candidates(&stem);
+    }
+
+    match found.len() {
+        0 => Ok(None),
+        1 => Ok(found.into_iter().next()),
+        _ => Err(anyhow!(
+            "Multiple sidecar files found for '{}': {}",
+            input.display(),
+            found
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// Apply an input's sidecar file, if it has one, to the global capture specs.
+pub(crate) fn apply_sidecar<'a>(
+    input: &Path,
+    global: &'a [CaptureSpec],
+) -> Result<Cow<'a, [CaptureSpec]>> {
+    let Some(path) = find_sidecar(input)? else {
+        return Ok(Cow::Borrowed(global));
+    };
+
+    let format = Format::from_path(&path)?;
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("Unable to read sidecar file '{}'", path.display()))?;
+    let (captures, replace) = parse_sidecar(format, &text)
+        .with_context(|| format!("Invalid sidecar file '{}'", path.display()))?;
+
+    if replace {
+        return Ok(Cow::Owned(captures));
+    }
+    Ok(Cow::Owned(merge_specs(global, captures)))
+}
+
+/// Parse the text of a sidecar file into capture specs and its replace flag.
+fn parse_sidecar(format: Format, text: &str) -> Result<(Vec<CaptureSpec>, bool)> {
+    let file: SidecarFile = format.parse(text)?;
+    let captures = file
+        .captures
+        .into_iter()
+        .map(CaptureEntry::into_spec)
+        .collect::<Result<_>>()?;
+    Ok((captures, file.replace))
+}
+
+/// Extend `global` with `overrides`, replacing global specs of the same name
+/// in place and appending the rest.
+fn merge_specs(global: &[CaptureSpec], overrides: Vec<CaptureSpec>) -> Vec<CaptureSpec> {
+    let mut merged = global.to_vec();
+    for spec in overrides {
+        match merged.iter_mut().find(|s| s.name == spec.name) {
+            Some(existing) => *existing = spec,
+            None => merged.push(spec),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::Length;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(
+            Format::from_path(&PathBuf::from("specs.toml")).unwrap(),
+            Format::Toml
+        );
+        assert_eq!(
+            Format::from_path(&PathBuf::from("specs.JSON")).unwrap(),
+            Format::Json
+        );
+        assert_eq!(
+            Format::from_path(&PathBuf::from("specs.yml")).unwrap(),
+            Format::Yaml
+        );
+        assert_eq!(
+            Format::from_path(&PathBuf::from("specs.yaml")).unwrap(),
+            Format::Yaml
+        );
+    }
+
+    #[test]
+    fn test_format_from_path_unsupported() {
+        let result = Format::from_path(&PathBuf::from("specs.txt"));
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Unsupported spec file"));
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let text = r#"
+            [[captures]]
+            name = "left"
+            rect = "200x300:1200x1850"
+
+            [[captures]]
+            name = "footer"
+            rect = "0x0:100%x10%"
+            origin = "bl"
+        "#;
+        let specs = parse(Format::Toml, text, None).unwrap();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].name, "left");
+        assert_eq!(specs[0].anchor, None);
+        assert_eq!(specs[0].x, Length::Pixels(200));
+        assert_eq!(specs[0].height, Length::Pixels(1850));
+        assert_eq!(specs[1].name, "footer");
+        assert_eq!(specs[1].anchor, Some(Anchor::SouthWest));
+        assert_eq!(specs[1].width, Length::Fraction(1.0));
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let text = r#"{
+            "captures": [
+                {"name": "logo", "rect": "0x0:400x400", "anchor": "center"}
+            ]
+        }"#;
+        let specs = parse(Format::Json, text, None).unwrap();
+        assert_eq!(specs.len(), 1);
+        assert_eq!(specs[0].name, "logo");
+        assert_eq!(specs[0].anchor, Some(Anchor::Center));
+    }
+
+    #[test]
+    fn test_parse_yaml() {
+        let text = "
+captures:
+  - name: header
+    rect: 0x0:100%x200
+  - name: badge
+    rect: 20x20:64x64
+    origin: se
+";
+        let specs = parse(Format::Yaml, text, None).unwrap();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].name, "header");
+        assert_eq!(specs[1].anchor, Some(Anchor::SouthEast));
+    }
+
+    #[test]
+    fn test_parse_invalid_rect() {
+        let text = r#"
+            [[captures]]
+            name = "left"
+            rect = "200x300"
+        "#;
+        let result = parse(Format::Toml, text, None);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("Invalid rect"));
+    }
+
+    #[test]
+    fn test_parse_invalid_origin() {
+        let text = r#"{"captures": [{"name": "a", "rect": "0x0:1x1", "origin": "up"}]}"#;
+        let result = parse(Format::Json, text, None);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("Invalid origin"));
+    }
+
+    #[test]
+    fn test_parse_page() {
+        let text = r#"
+            [[captures]]
+            name = "cover"
+            rect = "0x0:10x10"
+            page = 1
+
+            [[captures]]
+            name = "body"
+            rect = "0x0:10x10"
+            page = "2-4,6"
+        "#;
+        let specs = parse(Format::Toml, text, None).unwrap();
+        assert_eq!(specs[0].pages, Some("1".parse().unwrap()));
+        assert_eq!(specs[1].pages, Some("2-4,6".parse().unwrap()));
+
+        let text = r#"{"captures": [{"name": "a", "rect": "0x0:1x1", "page": 0}]}"#;
+        let result = parse(Format::Json, text, None);
+        assert!(result.unwrap_err().to_string().contains("Invalid page"));
+    }
+
+    #[test]
+    fn test_parse_out_of_bounds() {
+        let text = r#"
+            captures:
+              - name: margin
+                rect: 0x0:100%x110%
+                out_of_bounds: "pad:white"
+              - name: body
+                rect: 0x0:10x10
+        "#;
+        let specs = parse(Format::Yaml, text, None).unwrap();
+        assert_eq!(
+            specs[0].out_of_bounds,
+            Some(OutOfBounds::Pad(Some(image::Rgba([255, 255, 255, 255]))))
+        );
+        assert_eq!(specs[1].out_of_bounds, None);
+
+        let text = r#"{"captures": [{"name": "a", "rect": "0x0:1x1", "out_of_bounds": "wrap"}]}"#;
+        let result = parse(Format::Json, text, None);
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Invalid out_of_bounds"));
+    }
+
+    #[test]
+    fn test_parse_unknown_field() {
+        let text = r#"{"captures": [{"name": "a", "rect": "0x0:1x1", "colour": "red"}]}"#;
+        assert!(parse(Format::Json, text, None).is_err());
+    }
+
+    const LAYOUTS_TOML: &str = r#"
+        [[captures]]
+        name = "header"
+        rect = "0x0:100%x200"
+
+        [[layouts.invoice-v1.captures]]
+        name = "total"
+        rect = "1000x2000:500x100"
+
+        [[layouts.invoice-v2.captures]]
+        name = "total"
+        rect = "1200x2400:600x150"
+
+        [[layouts.invoice-v2.captures]]
+        name = "tax"
+        rect = "1200x2300:600x100"
+    "#;
+
+    #[test]
+    fn test_parse_layout_selected() {
+        let specs = parse(Format::Toml, LAYOUTS_TOML, Some("invoice-v2")).unwrap();
+        let names: Vec<&str> = specs.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["header", "total", "tax"]);
+        assert_eq!(specs[1].x, Length::Pixels(1200));
+    }
+
+    #[test]
+    fn test_parse_layout_missing() {
+        let result = parse(Format::Toml, LAYOUTS_TOML, Some("invoice-v3"));
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Available layouts: invoice-v1, invoice-v2"));
+    }
+
+    #[test]
+    fn test_parse_layout_required() {
+        let result = parse(Format::Toml, LAYOUTS_TOML, None);
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("choose one with --layout"));
+    }
+
+    #[test]
+    fn test_parse_layout_without_layouts() {
+        let text = r#"{"captures": [{"name": "a", "rect": "0x0:1x1"}]}"#;
+        let result = parse(Format::Json, text, Some("invoice-v1"));
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("no layouts are defined"));
+    }
+
+    #[test]
+    fn test_parse_layout_yaml() {
+        let text = "
+layouts:
+  invoice-v1:
+    captures:
+      - name: total
+        rect: 1000x2000:500x100
+";
+        let specs = parse(Format::Yaml, text, Some("invoice-v1")).unwrap();
+        assert_eq!(specs.len(), 1);
+        assert_eq!(specs[0].name, "total");
+    }
+
+    #[test]
+    fn test_parse_sidecar() {
+        let text = r#"{"captures": [{"name": "a", "rect": "0x0:1x1"}], "replace": true}"#;
+        let (captures, replace) = parse_sidecar(Format::Json, text).unwrap();
+        assert_eq!(captures.len(), 1);
+        assert!(replace);
+
+        let text = r#"{"captures": [{"name": "a", "rect": "0x0:1x1"}]}"#;
+        let (_, replace) = parse_sidecar(Format::Json, text).unwrap();
+        assert!(!replace);
+    }
+
+    #[test]
+    fn test_merge_specs_overrides_and_extends() {
+        let global = vec![
+            crate::parse_capture_spec("header:0x0:100x10").unwrap(),
+            crate::parse_capture_spec("footer:0x-10:100x10").unwrap(),
+        ];
+        let overrides = vec![
+            crate::parse_capture_spec("footer:0x-20:100x20").unwrap(),
+            crate::parse_capture_spec("stamp:5x5:50x50").unwrap(),
+        ];
+        let merged = merge_specs(&global, overrides);
+        let names: Vec<&str> = merged.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["header", "footer", "stamp"]);
+        assert_eq!(merged[1].height, Length::Pixels(20));
+    }
+
+    #[test]
+    fn test_find_sidecar() {
+        let dir = TempDir::new("sidecar");
+        let input = dir.join("scan001.png");
+
+        assert_eq!(find_sidecar(&input).unwrap(), None);
+
+        let json = dir.join("scan001.cutout.json");
+        std::fs::write(&json, "{}").unwrap();
+        assert_eq!(find_sidecar(&input).unwrap(), Some(json.clone()));
+
+        // A sidecar named after the whole file name wins, so that inputs
+        // differing only in extension can have their own
+        let own = dir.join("scan001.png.cutout.yaml");
+        std::fs::write(&own, "").unwrap();
+        assert_eq!(find_sidecar(&input).unwrap(), Some(own.clone()));
+        assert_eq!(
+            find_sidecar(&dir.join("scan001.jpg")).unwrap(),
+            Some(json.clone())
+        );
+        std::fs::remove_file(&own).unwrap();
+
+        std::fs::write(dir.join("scan001.cutout.toml"), "").unwrap();
+        assert!(find_sidecar(&input).is_err());
+    }
+}
@@ -0,0 +1,39 @@
+//! Helpers shared by the tests of several modules.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A scratch directory for a test, removed with its contents when dropped,
+/// so that a failing assertion does not leave it behind.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create an empty directory in the system temporary directory, named
+    /// after `name` and the process so that concurrent runs do not collide.
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("cutout-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}